@@ -0,0 +1,516 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Something the user can trigger from the keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Quit,
+    ToggleView,
+    ToggleToc,
+    ScrollUp,
+    ScrollDown,
+    PageUp,
+    PageDown,
+    Top,
+    Bottom,
+    NextFile,
+    PreviousFile,
+    Help,
+    Stats,
+    SetMark,
+    JumpToMark,
+    Fold,
+    Footnote,
+    ToggleSplit,
+    ShrinkSplit,
+    GrowSplit,
+    CycleWidth,
+    Select,
+    Copy,
+    Export,
+    Search,
+    NextMatch,
+    PreviousMatch,
+    ToggleTodos,
+    Percent,
+    Pipe,
+    Listing,
+    ToggleMarkers,
+    NextHeading,
+    PreviousHeading,
+    Palette,
+    ToggleWrapMarkers,
+    FollowLink,
+    Back,
+    Reload,
+    ToggleWhitespace,
+    FindHeading,
+    HalfPageUp,
+    HalfPageDown,
+    ViewUp,
+    ViewDown,
+    ViewRendered,
+    ViewSource,
+    ViewSideBySide,
+    AutoScroll,
+}
+
+impl Action {
+    pub const ALL: [Action; 49] = [
+        Action::Quit,
+        Action::ToggleView,
+        Action::ToggleToc,
+        Action::ScrollUp,
+        Action::ScrollDown,
+        Action::PageUp,
+        Action::PageDown,
+        Action::Top,
+        Action::Bottom,
+        Action::NextFile,
+        Action::PreviousFile,
+        Action::Help,
+        Action::Stats,
+        Action::SetMark,
+        Action::JumpToMark,
+        Action::Fold,
+        Action::Footnote,
+        Action::ToggleSplit,
+        Action::ShrinkSplit,
+        Action::GrowSplit,
+        Action::CycleWidth,
+        Action::Select,
+        Action::Copy,
+        Action::Export,
+        Action::Search,
+        Action::NextMatch,
+        Action::PreviousMatch,
+        Action::ToggleTodos,
+        Action::Percent,
+        Action::Pipe,
+        Action::Listing,
+        Action::ToggleMarkers,
+        Action::NextHeading,
+        Action::PreviousHeading,
+        Action::Palette,
+        Action::ToggleWrapMarkers,
+        Action::FollowLink,
+        Action::Back,
+        Action::Reload,
+        Action::ToggleWhitespace,
+        Action::FindHeading,
+        Action::HalfPageUp,
+        Action::HalfPageDown,
+        Action::ViewUp,
+        Action::ViewDown,
+        Action::ViewRendered,
+        Action::ViewSource,
+        Action::ViewSideBySide,
+        Action::AutoScroll,
+    ];
+
+    /// Name used for the action in the config file
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Quit => "quit",
+            Action::ToggleView => "toggle_view",
+            Action::ToggleToc => "toggle_toc",
+            Action::ScrollUp => "scroll_up",
+            Action::ScrollDown => "scroll_down",
+            Action::PageUp => "page_up",
+            Action::PageDown => "page_down",
+            Action::Top => "top",
+            Action::Bottom => "bottom",
+            Action::NextFile => "next_file",
+            Action::PreviousFile => "previous_file",
+            Action::Help => "help",
+            Action::Stats => "stats",
+            Action::SetMark => "set_mark",
+            Action::JumpToMark => "jump_to_mark",
+            Action::Fold => "fold",
+            Action::Footnote => "footnote",
+            Action::ToggleSplit => "toggle_split",
+            Action::ShrinkSplit => "shrink_split",
+            Action::GrowSplit => "grow_split",
+            Action::CycleWidth => "cycle_width",
+            Action::Select => "select",
+            Action::Copy => "copy",
+            Action::Export => "export",
+            Action::Search => "search",
+            Action::NextMatch => "next_match",
+            Action::PreviousMatch => "previous_match",
+            Action::ToggleTodos => "toggle_todos",
+            Action::Percent => "percent",
+            Action::Pipe => "pipe",
+            Action::Listing => "listing",
+            Action::ToggleMarkers => "toggle_markers",
+            Action::NextHeading => "next_heading",
+            Action::PreviousHeading => "previous_heading",
+            Action::Palette => "palette",
+            Action::ToggleWrapMarkers => "toggle_wrap_markers",
+            Action::FollowLink => "follow_link",
+            Action::Back => "back",
+            Action::Reload => "reload",
+            Action::ToggleWhitespace => "toggle_whitespace",
+            Action::FindHeading => "find_heading",
+            Action::HalfPageUp => "half_page_up",
+            Action::HalfPageDown => "half_page_down",
+            Action::ViewUp => "view_up",
+            Action::ViewDown => "view_down",
+            Action::ViewRendered => "view_rendered",
+            Action::ViewSource => "view_source",
+            Action::ViewSideBySide => "view_side_by_side",
+            Action::AutoScroll => "auto_scroll",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Action> {
+        Action::ALL.into_iter().find(|action| action.name() == name)
+    }
+
+    fn default_keys(self) -> &'static [&'static str] {
+        match self {
+            Action::Quit => &["q", "Esc"],
+            Action::ToggleView => &["Tab"],
+            Action::ToggleToc => &["t"],
+            Action::ScrollUp => &["Up", "k"],
+            Action::ScrollDown => &["Down", "j"],
+            Action::PageUp => &["PageUp", "b"],
+            Action::PageDown => &["PageDown", "Space"],
+            Action::Top => &["Home", "g", "0"],
+            Action::Bottom => &["End", "G"],
+            Action::NextFile => &["ctrl+n"],
+            Action::PreviousFile => &["ctrl+p"],
+            Action::Help => &["ctrl+h"],
+            Action::Stats => &["ctrl+g"],
+            Action::SetMark => &["m"],
+            Action::JumpToMark => &["'"],
+            Action::Fold => &["z"],
+            Action::Footnote => &["f"],
+            Action::ToggleSplit => &["s"],
+            Action::ShrinkSplit => &["<"],
+            Action::GrowSplit => &[">"],
+            Action::CycleWidth => &["w"],
+            Action::Select => &["v"],
+            Action::Copy => &["y"],
+            Action::Export => &["ctrl+s"],
+            Action::Search => &["/"],
+            Action::NextMatch => &["n"],
+            Action::PreviousMatch => &["N"],
+            Action::ToggleTodos => &["T"],
+            Action::Percent => &["%", "p"],
+            Action::Pipe => &["|"],
+            Action::Listing => &["-"],
+            Action::ToggleMarkers => &["M"],
+            Action::NextHeading => &["]"],
+            Action::PreviousHeading => &["["],
+            Action::Palette => &[":"],
+            Action::ToggleWrapMarkers => &["W"],
+            Action::FollowLink => &["Enter"],
+            Action::Back => &["Backspace"],
+            Action::Reload => &["r"],
+            Action::ToggleWhitespace => &["e"],
+            Action::FindHeading => &["ctrl+t"],
+            Action::HalfPageUp => &["u"],
+            Action::HalfPageDown => &["d"],
+            Action::ViewUp => &["ctrl+y"],
+            Action::ViewDown => &["ctrl+e"],
+            Action::ViewRendered => &["1", "alt+1"],
+            Action::ViewSource => &["2", "alt+2"],
+            Action::ViewSideBySide => &["3", "alt+3"],
+            Action::AutoScroll => &["a"],
+        }
+    }
+
+    /// What the action does, as listed in the command palette
+    pub fn description(self) -> &'static str {
+        match self {
+            Action::Quit => "Quit",
+            Action::ToggleView => "Toggle view mode",
+            Action::ToggleToc => "Toggle table of contents",
+            Action::ScrollUp => "Move the cursor up",
+            Action::ScrollDown => "Move the cursor down",
+            Action::PageUp => "Page up",
+            Action::PageDown => "Page down",
+            Action::Top => "Go to beginning of file",
+            Action::Bottom => "Go to end of file",
+            Action::NextFile => "Next file",
+            Action::PreviousFile => "Previous file",
+            Action::Help => "Show help",
+            Action::Stats => "Show document statistics",
+            Action::SetMark => "Set a mark",
+            Action::JumpToMark => "Jump to a mark",
+            Action::Fold => "Fold command",
+            Action::Footnote => "Follow footnote",
+            Action::ToggleSplit => "Stack or columns in side-by-side view",
+            Action::ShrinkSplit => "Shrink the rendered pane",
+            Action::GrowSplit => "Grow the rendered pane",
+            Action::CycleWidth => "Cycle the reading width",
+            Action::Select => "Start/stop selecting lines",
+            Action::Copy => "Copy to clipboard",
+            Action::Export => "Export to a file",
+            Action::Search => "Search",
+            Action::NextMatch => "Next match",
+            Action::PreviousMatch => "Previous match",
+            Action::ToggleTodos => "Toggle TODO highlighting",
+            Action::Percent => "Go to a percentage of the file",
+            Action::Pipe => "Pipe to a shell command",
+            Action::Listing => "Show the directory listing",
+            Action::ToggleMarkers => "Show/hide markdown markers",
+            Action::NextHeading => "Next heading",
+            Action::PreviousHeading => "Previous heading",
+            Action::Palette => "Command palette",
+            Action::ToggleWrapMarkers => "Mark/unmark wrapped lines",
+            Action::FollowLink => "Follow the link on the cursor line",
+            Action::Back => "Go back to the previous document",
+            Action::Reload => "Read the file again",
+            Action::ToggleWhitespace => "Show/hide trailing whitespace",
+            Action::FindHeading => "Find a heading by typing part of it",
+            Action::HalfPageUp => "Half page up",
+            Action::HalfPageDown => "Half page down",
+            Action::ViewUp => "Scroll the view up a line, leaving the cursor",
+            Action::ViewDown => "Scroll the view down a line, leaving the cursor",
+            Action::ViewRendered => "Switch to rendered view",
+            Action::ViewSource => "Switch to source view",
+            Action::ViewSideBySide => "Switch to side-by-side view",
+            Action::AutoScroll => "Start/pause scrolling a line at a time",
+        }
+    }
+}
+
+/// A key together with the modifiers that matter for matching
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyBinding {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl KeyBinding {
+    /// Parse a key specification like `j`, `PageDown`, or `ctrl+n`
+    fn parse(spec: &str) -> Option<KeyBinding> {
+        let mut parts: Vec<&str> = spec.split('+').collect();
+        // A lone `+` (or a trailing one, as in `ctrl++`) is the plus key itself
+        if spec.ends_with('+') {
+            parts.pop();
+            parts.pop();
+            parts.push("+");
+        }
+        let key = parts.pop()?;
+
+        let mut modifiers = KeyModifiers::NONE;
+        for modifier in parts {
+            modifiers |= match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return None,
+            };
+        }
+
+        let mut chars = key.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match key.to_ascii_lowercase().as_str() {
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "tab" => KeyCode::Tab,
+                "enter" => KeyCode::Enter,
+                "esc" | "escape" => KeyCode::Esc,
+                "space" => KeyCode::Char(' '),
+                "backspace" => KeyCode::Backspace,
+                "delete" => KeyCode::Delete,
+                name => KeyCode::F(name.strip_prefix('f')?.parse().ok()?),
+            },
+        };
+
+        Some(KeyBinding::new(code, modifiers))
+    }
+
+    /// How the key is written in the config file, like `j` or `ctrl+n`
+    fn label(&self) -> String {
+        let mut label = String::new();
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "ctrl+"),
+            (KeyModifiers::ALT, "alt+"),
+            (KeyModifiers::SHIFT, "shift+"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                label.push_str(name);
+            }
+        }
+        match self.code {
+            KeyCode::Char(' ') => label.push_str("Space"),
+            KeyCode::Char(c) => label.push(c),
+            KeyCode::F(n) => label.push_str(&format!("F{}", n)),
+            code => label.push_str(&format!("{:?}", code)),
+        }
+        label
+    }
+
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> KeyBinding {
+        // Shift is already reflected in the character itself for printable keys
+        let modifiers = match code {
+            KeyCode::Char(_) => modifiers - KeyModifiers::SHIFT,
+            _ => modifiers,
+        };
+        KeyBinding {
+            code,
+            modifiers: modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT),
+        }
+    }
+}
+
+/// Resolved mapping from keys to actions
+#[derive(Debug, Clone)]
+pub struct KeyBindings {
+    bindings: HashMap<KeyBinding, Action>,
+}
+
+impl KeyBindings {
+    pub fn action_for(&self, key: &KeyEvent) -> Option<Action> {
+        self.bindings
+            .get(&KeyBinding::new(key.code, key.modifiers))
+            .copied()
+    }
+
+    /// Labels of the keys bound to `action`, in a stable order
+    pub fn keys_for(&self, action: Action) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .bindings
+            .iter()
+            .filter(|(_, bound)| **bound == action)
+            .map(|(binding, _)| binding.label())
+            .collect();
+        keys.sort();
+        keys
+    }
+}
+
+/// A key list in the config file, written either as `"q"` or `["q", "Esc"]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum KeySpecs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl KeySpecs {
+    fn specs(&self) -> Vec<&str> {
+        match self {
+            KeySpecs::One(spec) => vec![spec.as_str()],
+            KeySpecs::Many(specs) => specs.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+/// Words highlighted by `--highlight-todos` unless the config file lists its own
+const DEFAULT_TODO_KEYWORDS: [&str; 4] = ["TODO", "FIXME", "HACK", "NOTE"];
+
+/// Settings read from `~/.config/mess/config.toml`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    keys: HashMap<String, KeySpecs>,
+    todo_keywords: Option<Vec<String>>,
+    scroll_margin: Option<usize>,
+}
+
+impl Config {
+    /// Load the user's config file, warning about and ignoring anything invalid
+    pub fn load() -> Config {
+        let Some(path) = config_path() else {
+            return Config::default();
+        };
+        let Ok(text) = fs::read_to_string(&path) else {
+            return Config::default();
+        };
+
+        match toml::from_str(&text) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Warning: ignoring invalid config file '{}': {}", path.display(), e);
+                Config::default()
+            }
+        }
+    }
+
+    /// Words to highlight when TODO highlighting is on
+    pub fn todo_keywords(&self) -> Vec<String> {
+        match &self.todo_keywords {
+            Some(keywords) => keywords.iter().filter(|keyword| !keyword.is_empty()).cloned().collect(),
+            None => DEFAULT_TODO_KEYWORDS.iter().map(|keyword| keyword.to_string()).collect(),
+        }
+    }
+
+    /// Lines of context kept around the cursor, if set
+    pub fn scroll_margin(&self) -> Option<usize> {
+        self.scroll_margin
+    }
+
+    /// Combine the default bindings with any overrides from the config file.
+    /// An action listed in the config replaces all of its default keys.
+    pub fn key_bindings(&self) -> KeyBindings {
+        let mut keys: HashMap<Action, Vec<KeyBinding>> = Action::ALL
+            .into_iter()
+            .map(|action| {
+                let defaults = action
+                    .default_keys()
+                    .iter()
+                    .filter_map(|spec| KeyBinding::parse(spec))
+                    .collect();
+                (action, defaults)
+            })
+            .collect();
+
+        let mut configured = Vec::new();
+        for (name, specs) in &self.keys {
+            let Some(action) = Action::from_name(name) else {
+                eprintln!("Warning: unknown action '{}' in config, ignoring", name);
+                continue;
+            };
+
+            let mut parsed = Vec::new();
+            for spec in specs.specs() {
+                match KeyBinding::parse(spec) {
+                    Some(binding) => parsed.push(binding),
+                    None => eprintln!("Warning: invalid key '{}' for action '{}', ignoring", spec, name),
+                }
+            }
+
+            if parsed.is_empty() {
+                eprintln!("Warning: no valid keys for action '{}', keeping defaults", name);
+            } else {
+                keys.insert(action, parsed);
+                configured.push(action);
+            }
+        }
+
+        // A key the config file gives to one action is taken from any default
+        // action it was bound to
+        let (configured, defaults): (Vec<_>, Vec<_>) = keys.into_iter().partition(|(action, _)| configured.contains(action));
+        let bindings = defaults
+            .into_iter()
+            .chain(configured)
+            .flat_map(|(action, bindings)| bindings.into_iter().map(move |binding| (binding, action)))
+            .collect();
+
+        KeyBindings { bindings }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/mess/config.toml`, falling back to `~/.config/mess/config.toml`
+fn config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("mess").join("config.toml"))
+}
@@ -0,0 +1,247 @@
+/// Edits past which two files are considered too different to line up, and
+/// the lines between their common start and end are shown as one change. The
+/// search keeps a row of positions per edit, so this bounds its memory.
+const MAX_EDITS: usize = 2000;
+
+/// One row of two files laid side by side, by line index into each file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffRow {
+    Same(usize, usize),
+    /// A line of the old file replaced by a line of the new one
+    Changed(usize, usize),
+    Removed(usize),
+    Added(usize),
+}
+
+impl DiffRow {
+    pub fn old_line(&self) -> Option<usize> {
+        match *self {
+            DiffRow::Same(old, _) | DiffRow::Changed(old, _) | DiffRow::Removed(old) => Some(old),
+            DiffRow::Added(_) => None,
+        }
+    }
+
+    pub fn new_line(&self) -> Option<usize> {
+        match *self {
+            DiffRow::Same(_, new) | DiffRow::Changed(_, new) | DiffRow::Added(new) => Some(new),
+            DiffRow::Removed(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Edit {
+    Keep,
+    Delete,
+    Insert,
+}
+
+/// Line up `old` and `new` by the fewest lines removed and added. Lines removed
+/// and added at the same place share rows, so a changed line stays across from
+/// what replaced it and the rest of a change gets a gap on the other side.
+pub fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffRow> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_middle, new_middle) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+    let edits = shortest_edit(old_middle, new_middle).unwrap_or_else(|| {
+        let deletes = std::iter::repeat_n(Edit::Delete, old_middle.len());
+        deletes.chain(std::iter::repeat_n(Edit::Insert, new_middle.len())).collect()
+    });
+
+    let mut rows: Vec<DiffRow> = (0..prefix).map(|index| DiffRow::Same(index, index)).collect();
+    let (mut old_line, mut new_line) = (prefix, prefix);
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    for edit in edits.into_iter().chain(std::iter::repeat_n(Edit::Keep, suffix)) {
+        match edit {
+            Edit::Delete => {
+                removed.push(old_line);
+                old_line += 1;
+            }
+            Edit::Insert => {
+                added.push(new_line);
+                new_line += 1;
+            }
+            Edit::Keep => {
+                pair_changes(&mut rows, &mut removed, &mut added);
+                rows.push(DiffRow::Same(old_line, new_line));
+                old_line += 1;
+                new_line += 1;
+            }
+        }
+    }
+    pair_changes(&mut rows, &mut removed, &mut added);
+    rows
+}
+
+/// Add rows for a run of removed and added lines, pairing them up in order
+fn pair_changes(rows: &mut Vec<DiffRow>, removed: &mut Vec<usize>, added: &mut Vec<usize>) {
+    for index in 0..removed.len().max(added.len()) {
+        rows.push(match (removed.get(index), added.get(index)) {
+            (Some(&old), Some(&new)) => DiffRow::Changed(old, new),
+            (Some(&old), None) => DiffRow::Removed(old),
+            (None, Some(&new)) => DiffRow::Added(new),
+            (None, None) => unreachable!(),
+        });
+    }
+    removed.clear();
+    added.clear();
+}
+
+/// Myers' algorithm: the shortest run of edits turning `old` into `new`, or
+/// `None` if it takes more than `MAX_EDITS`
+fn shortest_edit(old: &[&str], new: &[&str]) -> Option<Vec<Edit>> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max = (old.len() + new.len()).min(MAX_EDITS) as isize;
+    // Furthest `x` reached on each diagonal `k = x - y`, stored at `k + max + 1`
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let at = |k: isize| (k + max + 1) as usize;
+    // Diagonals `-d - 1..=d + 1` of `v` as each edit count `d` started
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    'search: {
+        for d in 0..=max {
+            trace.push(v[at(-d - 1)..=at(d + 1)].to_vec());
+            for k in (-d..=d).step_by(2) {
+                let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                    v[at(k + 1)]
+                } else {
+                    v[at(k - 1)] + 1
+                };
+                let mut y = x - k;
+                while x < n && y < m && old[x as usize] == new[y as usize] {
+                    x += 1;
+                    y += 1;
+                }
+                v[at(k)] = x;
+                if x >= n && y >= m {
+                    break 'search;
+                }
+            }
+        }
+        return None;
+    }
+
+    // Walk back from the end through the diagonals each step came from
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let previous_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let previous_x = at(previous_k);
+        let previous_y = previous_x - previous_k;
+        while x > previous_x && y > previous_y {
+            edits.push(Edit::Keep);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == previous_x {
+                edits.push(Edit::Insert);
+                y -= 1;
+            } else {
+                edits.push(Edit::Delete);
+                x -= 1;
+            }
+        }
+    }
+    edits.reverse();
+    Some(edits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn common_start_and_end_stay_side_by_side() {
+        let old = ["a", "b", "c", "d"];
+        let new = ["a", "b", "x", "c", "d"];
+        assert_eq!(
+            diff_lines(&old, &new),
+            vec![
+                DiffRow::Same(0, 0),
+                DiffRow::Same(1, 1),
+                DiffRow::Added(2),
+                DiffRow::Same(2, 3),
+                DiffRow::Same(3, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_files_are_all_same() {
+        let lines = ["one", "two"];
+        assert_eq!(diff_lines(&lines, &lines), vec![DiffRow::Same(0, 0), DiffRow::Same(1, 1)]);
+    }
+
+    #[test]
+    fn removed_and_added_lines_pair_up_as_changes() {
+        let old = ["keep", "old 1", "old 2", "old 3", "end"];
+        let new = ["keep", "new 1", "end", "extra"];
+        assert_eq!(
+            diff_lines(&old, &new),
+            vec![
+                DiffRow::Same(0, 0),
+                DiffRow::Changed(1, 1),
+                DiffRow::Removed(2),
+                DiffRow::Removed(3),
+                DiffRow::Same(4, 2),
+                DiffRow::Added(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn more_added_than_removed_lines_leave_a_gap_on_the_old_side() {
+        let old = ["a", "b"];
+        let new = ["x", "y", "z"];
+        assert_eq!(
+            diff_lines(&old, &new),
+            vec![DiffRow::Changed(0, 0), DiffRow::Changed(1, 1), DiffRow::Added(2)]
+        );
+    }
+
+    #[test]
+    fn empty_old_file_is_all_added() {
+        assert_eq!(diff_lines(&[], &["a", "b"]), vec![DiffRow::Added(0), DiffRow::Added(1)]);
+    }
+
+    #[test]
+    fn empty_new_file_is_all_removed() {
+        assert_eq!(diff_lines(&["a", "b"], &[]), vec![DiffRow::Removed(0), DiffRow::Removed(1)]);
+    }
+
+    #[test]
+    fn both_empty_has_no_rows() {
+        assert!(diff_lines(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn rows_know_their_lines_on_each_side() {
+        assert_eq!((DiffRow::Changed(3, 4).old_line(), DiffRow::Changed(3, 4).new_line()), (Some(3), Some(4)));
+        assert_eq!((DiffRow::Removed(2).old_line(), DiffRow::Removed(2).new_line()), (Some(2), None));
+        assert_eq!((DiffRow::Added(5).old_line(), DiffRow::Added(5).new_line()), (None, Some(5)));
+    }
+
+    #[test]
+    fn too_many_edits_fall_back_to_one_change() {
+        // Interleaving shared lines with unique ones keeps the middle from being
+        // trimmed while needing far more than `MAX_EDITS` edits to line up
+        let old: Vec<String> = (0..MAX_EDITS * 2).map(|i| if i % 2 == 0 { "same".into() } else { format!("old {}", i) }).collect();
+        let new: Vec<String> = (0..MAX_EDITS * 2).map(|i| if i % 2 == 1 { "same".into() } else { format!("new {}", i) }).collect();
+        let old: Vec<&str> = old.iter().map(String::as_str).collect();
+        let new: Vec<&str> = new.iter().map(String::as_str).collect();
+        assert!(shortest_edit(&old, &new).is_none());
+
+        let rows = diff_lines(&old, &new);
+        assert_eq!(rows.len(), old.len());
+        assert!(rows.iter().enumerate().all(|(index, row)| *row == DiffRow::Changed(index, index)));
+    }
+}
@@ -0,0 +1,93 @@
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use std::path::Path;
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{FontStyle, Theme as SyntaxTheme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+
+/// Syntax definitions and themes are slow to load, so only do it once and only when needed
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme_set() -> &'static ThemeSet {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    THEME_SET.get_or_init(ThemeSet::load_defaults)
+}
+
+/// Syntax highlighting for a source file, computed on demand as lines come into view.
+/// Parsing is stateful, so lines are highlighted in order and kept once done.
+pub struct SourceHighlighter {
+    highlighter: HighlightLines<'static>,
+    lines: Vec<Line<'static>>,
+}
+
+impl SourceHighlighter {
+    /// A highlighter for the language implied by the file's extension, if it has one
+    /// that syntect knows and the syntax theme exists
+    pub fn for_file(name: &str, syntax_theme: &str) -> Option<SourceHighlighter> {
+        let extension = Path::new(name).extension()?.to_str()?;
+        let syntax = syntax_set().find_syntax_by_extension(extension)?;
+        if syntax.name == "Plain Text" {
+            return None;
+        }
+        let theme: &'static SyntaxTheme = theme_set().themes.get(syntax_theme)?;
+        Some(SourceHighlighter {
+            highlighter: HighlightLines::new(syntax, theme),
+            lines: Vec::new(),
+        })
+    }
+
+    /// Styled source lines `start..end`, highlighting any lines not seen yet.
+    /// `source_line` provides the text of a line by index; `end` must not exceed the line count.
+    pub fn lines(&mut self, start: usize, end: usize, source_line: impl Fn(usize) -> String) -> &[Line<'static>] {
+        while self.lines.len() < end {
+            let line = source_line(self.lines.len());
+            let styled = self.highlight(&line);
+            self.lines.push(styled);
+        }
+        &self.lines[start.min(end)..end]
+    }
+
+    fn highlight(&mut self, line: &str) -> Line<'static> {
+        let with_newline = format!("{}\n", line);
+        match self.highlighter.highlight_line(&with_newline, syntax_set()) {
+            Ok(regions) => Line::from(
+                regions
+                    .into_iter()
+                    .map(|(style, text)| {
+                        Span::styled(text.trim_end_matches('\n').to_string(), convert_style(style))
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            // A parse failure only costs this line its colors
+            Err(_) => Line::from(line.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Debug for SourceHighlighter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SourceHighlighter")
+            .field("highlighted_lines", &self.lines.len())
+            .finish()
+    }
+}
+
+/// Foreground and font style only, so the terminal's own background shows through
+fn convert_style(style: syntect::highlighting::Style) -> Style {
+    let color = style.foreground;
+    let mut converted = Style::default().fg(Color::Rgb(color.r, color.g, color.b));
+    if style.font_style.contains(FontStyle::BOLD) {
+        converted = converted.add_modifier(Modifier::BOLD);
+    }
+    if style.font_style.contains(FontStyle::ITALIC) {
+        converted = converted.add_modifier(Modifier::ITALIC);
+    }
+    if style.font_style.contains(FontStyle::UNDERLINE) {
+        converted = converted.add_modifier(Modifier::UNDERLINED);
+    }
+    converted
+}
@@ -0,0 +1,98 @@
+use image::DynamicImage;
+use ratatui::layout::Rect;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use viuer::KittySupport;
+
+/// Whether the terminal can show images with the kitty, iTerm or sixel protocol.
+/// The first call asks the terminal, so make it before entering raw mode.
+pub fn graphics_supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        viuer::get_kitty_support() != KittySupport::None
+            || viuer::is_iterm_supported()
+            || viuer::is_sixel_supported()
+    })
+}
+
+/// Draw an image scaled to fit inside `area`, leaving the cursor where it was
+pub fn print(image: &DynamicImage, area: Rect) -> viuer::ViuResult {
+    let config = viuer::Config {
+        absolute_offset: true,
+        x: area.x,
+        y: area.y as i16,
+        width: Some(area.width as u32),
+        height: Some(area.height as u32),
+        restore_cursor: true,
+        ..Default::default()
+    };
+    viuer::print(image, &config).map(|_| ())
+}
+
+/// Remove every image drawn so far. Kitty keeps images apart from the text,
+/// while the other protocols draw into cells that go away once redrawn.
+pub fn clear() -> io::Result<()> {
+    if viuer::get_kitty_support() == KittySupport::None {
+        return Ok(());
+    }
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b_Ga=d\x1b\\")?;
+    stdout.flush()
+}
+
+enum ImageState {
+    Loading,
+    Ready(DynamicImage),
+    Failed,
+}
+
+/// Decodes images on background threads so large files don't hold up scrolling
+pub struct ImageLoader {
+    images: HashMap<PathBuf, ImageState>,
+    sender: Sender<(PathBuf, Option<DynamicImage>)>,
+    receiver: Receiver<(PathBuf, Option<DynamicImage>)>,
+}
+
+impl ImageLoader {
+    pub fn new() -> ImageLoader {
+        let (sender, receiver) = mpsc::channel();
+        ImageLoader { images: HashMap::new(), sender, receiver }
+    }
+
+    /// The image at `path` once it has been decoded, starting to load it if this
+    /// is the first time it is asked for. Images that fail to load stay `None`.
+    pub fn get(&mut self, path: &Path) -> Option<&DynamicImage> {
+        while let Ok((loaded, image)) = self.receiver.try_recv() {
+            let state = image.map_or(ImageState::Failed, ImageState::Ready);
+            self.images.insert(loaded, state);
+        }
+
+        if !self.images.contains_key(path) {
+            self.images.insert(path.to_path_buf(), ImageState::Loading);
+            let sender = self.sender.clone();
+            let path = path.to_path_buf();
+            thread::spawn(move || {
+                let image = image::open(&path).ok();
+                // The viewer may have quit in the meantime
+                let _ = sender.send((path, image));
+            });
+        }
+
+        match self.images.get(path) {
+            Some(ImageState::Ready(image)) => Some(image),
+            Some(ImageState::Loading | ImageState::Failed) | None => None,
+        }
+    }
+}
+
+impl std::fmt::Debug for ImageLoader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImageLoader")
+            .field("images", &self.images.len())
+            .finish()
+    }
+}
@@ -0,0 +1,90 @@
+//! The markdown renderer behind the `mess` pager, for drawing markdown in
+//! other ratatui apps.
+//!
+//! ```
+//! use mess::theme::Theme;
+//!
+//! let text = mess::markdown_to_lines("# Notes\n\nSome **bold** text.", &Theme::dark());
+//! let lines: Vec<String> = text.lines.iter().map(|line| line.to_string()).collect();
+//! assert_eq!(lines, ["Notes", "", "Some bold text."]);
+//! ```
+
+pub mod markdown;
+pub mod rst;
+pub mod theme;
+
+use markdown::{HtmlMode, RenderOptions, Symbols};
+use ratatui::text::Text;
+use theme::Theme;
+
+/// Width in columns that `markdown_to_lines` draws rules and code block boxes at
+pub const DEFAULT_WIDTH: usize = 80;
+
+/// Render markdown as styled lines, the way the pager's rendered view shows
+/// it: headings, emphasis, code, links, quotes, lists, tables and footnotes.
+/// Raw HTML is stripped down to its text and images are shown as their alt
+/// text. Lines aren't wrapped, so wrap them with the paragraph drawing them.
+///
+/// ```
+/// use mess::theme::Theme;
+/// use ratatui::style::Modifier;
+///
+/// let text = mess::markdown_to_lines("Run `make` **now**", &Theme::light());
+/// let bold = text.lines[0].spans.iter().find(|span| span.content == "now").unwrap();
+/// assert!(bold.style.add_modifier.contains(Modifier::BOLD));
+/// ```
+pub fn markdown_to_lines(src: &str, theme: &Theme) -> Text<'static> {
+    let options = RenderOptions { image_rows: 0, html: HtmlMode::Strip, tab_width: 4, ascii: false, image_dir: None };
+    let rendered = markdown::render_markdown(src, &options);
+    let symbols = Symbols::get(false);
+    rendered
+        .text
+        .lines()
+        .map(|line| markdown::into_owned_line(markdown::style_rendered_line(line, theme, DEFAULT_WIDTH, false, symbols)))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::style::Modifier;
+
+    fn plain_lines(text: &Text) -> Vec<String> {
+        text.lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn empty_markdown_has_no_lines() {
+        assert!(markdown_to_lines("", &Theme::dark()).lines.is_empty());
+        assert!(markdown_to_lines("\n\n  \n", &Theme::dark()).lines.is_empty());
+    }
+
+    #[test]
+    fn markers_are_drawn_not_shown() {
+        let text = markdown_to_lines(
+            "## Title\n\n> quoted\n\n[link](https://example.com) and <b>html</b>\n\n---\n",
+            &Theme::dark(),
+        );
+        let lines = plain_lines(&text);
+        assert_eq!(lines[..5], ["Title", "", "│ quoted", "", "link and html"]);
+        assert_eq!(lines[6], "─".repeat(DEFAULT_WIDTH));
+        // None of the renderer's private markers leak into the text
+        assert!(lines.iter().all(|line| !line.chars().any(|c| ('\u{E000}'..='\u{F8FF}').contains(&c))));
+
+        let heading = &text.lines[0].spans[0];
+        assert!(heading.style.add_modifier.contains(Modifier::BOLD));
+        let link = text.lines[4].spans.iter().find(|span| span.content == "link").unwrap();
+        assert!(link.style.add_modifier.contains(Modifier::UNDERLINED));
+    }
+
+    #[test]
+    fn code_blocks_are_boxed_at_the_default_width() {
+        let text = markdown_to_lines("```rust\nfn main() {}\n```", &Theme::dark());
+        let lines = plain_lines(&text);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("┌─ rust ") && lines[0].ends_with('┐'));
+        assert_eq!(lines[1].trim_end_matches('│').trim_end(), "│ fn main() {}");
+        assert!(lines.iter().all(|line| line.chars().count() == DEFAULT_WIDTH));
+    }
+}
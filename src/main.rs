@@ -1,490 +1,4870 @@
+mod config;
+mod diff;
+mod highlight;
+mod images;
+mod state;
+
 use color_eyre::Result;
 use crossterm::{
-    event::{self, Event, KeyCode, KeyModifiers},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers, MouseButton, MouseEvent, MouseEventKind},
     execute,
     terminal::{EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span, Text},
-    widgets::{Block, Borders, List, ListItem, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState},
     Frame, Terminal,
 };
 use pulldown_cmark::Parser;
+use std::borrow::Cow;
+use std::cell::{OnceCell, RefCell};
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
-use std::io;
-use clap::Parser as ClapParser;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use clap::{Parser as ClapParser, ValueEnum};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use flate2::read::GzDecoder;
+use encoding_rs::Encoding;
+use config::{Action, Config, KeyBindings};
+use diff::DiffRow;
+use highlight::SourceHighlighter;
+use images::ImageLoader;
+use state::{Preferences, SavedPosition, SavedState};
+use mess::markdown::*;
+use mess::rst;
+use mess::theme::Theme;
 
-#[derive(ClapParser)]
+#[derive(ClapParser, Debug, Clone)]
 #[command(name = "mess")]
 #[command(about = "A less-like viewer with markdown support")]
 struct Args {
-    /// File to view
-    file: String,
+    /// Files to view, `-` for standard input. Like less, `+G` starts at the
+    /// end, `+<line>` at a line and `+/<pattern>` at the first match of a search.
+    #[arg(required = true)]
+    files: Vec<String>,
+
+    /// Compare two files side by side, an old one and a new one, with removed
+    /// lines in red and added lines in green
+    #[arg(long)]
+    diff: bool,
+
+    /// Open scrolled to the end, like `+G`
+    #[arg(long)]
+    start_at_end: bool,
+
+    /// Open at the first heading with this slug, its text lowercased with
+    /// spaces as hyphens and punctuation dropped, like `installation`
+    #[arg(long, value_name = "SLUG")]
+    goto_heading: Option<String>,
+
+    /// Don't use colors, showing highlights as reversed text instead. Also on
+    /// when the NO_COLOR environment variable is set.
+    #[arg(long)]
+    no_color: bool,
+
+    /// Draw bullets, rules, bars and the scrollbar with plain ASCII characters.
+    /// On by default when the locale isn't UTF-8 or TERM names a basic terminal.
+    #[arg(long)]
+    ascii: bool,
+
+    /// Treat files as markdown regardless of their extension
+    #[arg(long, conflicts_with = "no_markdown")]
+    markdown: bool,
+
+    /// Treat files as plain text regardless of their extension
+    #[arg(long)]
+    no_markdown: bool,
+
+    /// Number of columns a tab advances to in source view [default: 4]
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    tab_width: Option<u16>,
+
+    /// Character encoding of the files (e.g. latin1, windows-1252, utf-16le)
+    #[arg(long)]
+    encoding: Option<String>,
+
+    /// Show only lines START to END of each file, counting from 1, like `1000:1200`.
+    /// Markdown is rendered from just those lines of its source.
+    #[arg(long, value_name = "START:END")]
+    lines: Option<String>,
+
+    /// Remove ANSI escape sequences, like the colors in captured terminal output
+    #[arg(long)]
+    strip_ansi: bool,
+
+    /// View mode to start markdown files in [default: rendered, or the mode last used]
+    #[arg(long, value_enum)]
+    mode: Option<ViewMode>,
+
+    /// Color theme: `dark`, `light`, or the path to a TOML theme file
+    /// [default: dark, or the theme last used]
+    #[arg(long)]
+    theme: Option<String>,
+
+    /// Don't restore or save the last-read position of files, or the view
+    /// mode, wrapping and theme last used
+    #[arg(long)]
+    no_resume: bool,
+
+    /// Print the content to stdout instead of opening the pager (implied when stdout isn't a terminal)
+    #[arg(long)]
+    plain: bool,
+
+    /// Print the content and exit, like `--plain`, when it all fits on one screen
+    #[arg(long)]
+    quit_if_one_screen: bool,
+
+    /// Ask before quitting on `q` or Esc, so a stray key doesn't lose your
+    /// place; `y` quits. Ctrl+c always quits straight away.
+    #[arg(long)]
+    confirm_quit: bool,
+
+    /// Animate page and Home/End jumps instead of moving there at once
+    #[arg(long)]
+    smooth: bool,
+
+    /// Lines of context kept around the cursor line and above search matches
+    /// [default: 3, or `scroll_margin` from the config file]
+    #[arg(long, value_name = "LINES")]
+    scroll_margin: Option<usize>,
+
+    /// Lines kept on screen from the previous page by Page Up/Page Down
+    #[arg(long, value_name = "LINES", default_value_t = 2)]
+    scroll_overlap: usize,
+
+    /// Mark rows continuing a wrapped line with `↳` in a gutter on the left
+    #[arg(long)]
+    wrap_markers: bool,
+
+    /// How rendered markdown wraps long lines: `word` lines the rest of the line
+    /// up under its list bullet or quote bars, `plain` starts it at the left
+    /// edge like source view does [default: word, or the wrapping last used]
+    #[arg(long, value_enum)]
+    wrap: Option<WrapMode>,
+
+    /// Show spaces and tabs at the ends of lines in source view as `·` on a
+    /// colored background
+    #[arg(long)]
+    trailing_whitespace: bool,
+
+    /// Milliseconds between lines while `a` scrolls the document on its own
+    /// [default: 1000]
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = DEFAULT_AUTO_SCROLL_INTERVAL,
+        hide_default_value = true,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    interval: u64,
+
+    /// Markdown larger than this opens in source view, and is only rendered
+    /// when Tab asks for it, on a background thread [default: 16 MB]
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_RENDER_SIZE, hide_default_value = true)]
+    max_render_size: u64,
+
+    /// Number lines in a gutter on the left in source view
+    #[arg(long)]
+    number: bool,
+
+    /// Number only the lines that aren't blank in source view, like `cat -b`
+    #[arg(long, conflicts_with = "number")]
+    number_nonblank: bool,
+
+    /// Keep markdown markers like `**`, `` ` `` and `#` in rendered view, dimmed
+    #[arg(long)]
+    show_markers: bool,
+
+    /// Highlight TODO, FIXME, HACK and NOTE, or the `todo_keywords` from the config file
+    #[arg(long)]
+    highlight_todos: bool,
+
+    /// Mark this column in source view, highlighting text that runs past it
+    #[arg(long, value_name = "COLUMN")]
+    ruler: Option<usize>,
+
+    /// How HTML in markdown is shown: `strip` keeps just its text, `placeholder`
+    /// shows a dimmed `[html]`, `raw` shows it as written. Comments stay hidden
+    /// unless raw.
+    #[arg(long, value_enum, default_value_t = HtmlMode::Strip)]
+    html: HtmlMode,
+
+    /// How to split side-by-side view: `vertical` for columns, `horizontal` to stack
+    /// the panes. Defaults to columns unless the terminal is narrow.
+    #[arg(long, value_enum)]
+    split: Option<SplitOrientation>,
+
+    /// When to show the scrollbar: `always`, `never`, or `auto` to show it only
+    /// when the document doesn't fit on screen. A hidden scrollbar's column
+    /// goes to the text.
+    #[arg(long, value_enum, default_value_t = ScrollbarMode::Always)]
+    scrollbar: ScrollbarMode,
+
+    /// How the scrollbar is drawn: `arrows` at both ends of the track, or
+    /// `plain` for just the track
+    #[arg(long, value_enum, default_value_t = ScrollbarStyle::Arrows)]
+    scrollbar_style: ScrollbarStyle,
+
+    /// Open links on the cursor line with Enter: local files in a buffer of
+    /// their own, which Backspace leaves again, and web links in the browser
+    #[arg(long)]
+    follow_links: bool,
+
+    /// Cap rendered markdown at this many columns, centered in the terminal
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    width: Option<u16>,
+
+    /// Shell command to run when a watched file changes, like `make docs`,
+    /// reading the first file named again once it has finished
+    #[arg(long, value_name = "COMMAND")]
+    watch_command: Option<String>,
+
+    /// A file, or a directory with everything in it, whose changes run
+    /// `--watch-command`. Can be given more than once [default: the file in
+    /// view and its directory]
+    #[arg(long, value_name = "PATH", requires = "watch_command")]
+    watch: Vec<String>,
+
+    /// Write the first file's current view to this path and exit; `.html` paths get HTML
+    #[arg(long)]
+    export: Option<String>,
+
+    /// Settings saved by the last session, used where no flag is given
+    #[arg(skip)]
+    preferences: Preferences,
+}
+
+impl Args {
+    /// Fill in options not given as flags from `MESS_THEME`, `MESS_MODE` and
+    /// `MESS_TAB_WIDTH`, for when `mess` is started as a pager by programs that
+    /// don't pass flags. Invalid values are reported and ignored.
+    fn apply_environment(&mut self) {
+        let var = |name| std::env::var(name).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+        if self.theme.is_none()
+            && let Some(theme) = var("MESS_THEME")
+        {
+            match Theme::load(&theme) {
+                Ok(_) => self.theme = Some(theme),
+                Err(e) => eprintln!("Warning: ignoring MESS_THEME: {}", e),
+            }
+        }
+        if self.mode.is_none()
+            && let Some(mode) = var("MESS_MODE")
+        {
+            match ViewMode::from_str(&mode, true) {
+                Ok(mode) => self.mode = Some(mode),
+                Err(_) => eprintln!(
+                    "Warning: ignoring MESS_MODE '{}': expected rendered, source or side-by-side",
+                    mode
+                ),
+            }
+        }
+        if self.tab_width.is_none()
+            && let Some(width) = var("MESS_TAB_WIDTH")
+        {
+            match width.parse::<u16>() {
+                Ok(width) if width > 0 => self.tab_width = Some(width),
+                _ => eprintln!("Warning: ignoring MESS_TAB_WIDTH '{}': expected a number of columns from 1", width),
+            }
+        }
+    }
+
+    fn tab_width(&self) -> usize {
+        self.tab_width.map_or(DEFAULT_TAB_WIDTH, usize::from)
+    }
+
+    /// The theme named by `--theme`, else the one last used
+    fn theme(&self) -> Option<&str> {
+        self.theme.as_deref().or(self.preferences.theme.as_deref())
+    }
+
+    fn force_markdown(&self) -> Option<bool> {
+        if self.markdown {
+            Some(true)
+        } else if self.no_markdown {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Whether to leave out colors, asked for or through `NO_COLOR`
+    fn no_color(&self) -> bool {
+        self.no_color || std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+    }
+
+    /// Whether to draw with ASCII only, asked for or because the terminal
+    /// likely can't show the Unicode symbols
+    fn ascii(&self) -> bool {
+        if self.ascii {
+            return true;
+        }
+        let limited_term = std::env::var("TERM")
+            .is_ok_and(|term| matches!(term.as_str(), "dumb" | "ansi" | "vt52" | "vt100" | "vt102" | "vt220"));
+        // Only a locale that is set and isn't UTF-8 counts; many setups leave it unset
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()));
+        let non_utf8_locale = locale.is_some_and(|locale| {
+            let locale = locale.to_ascii_lowercase();
+            !locale.contains("utf-8") && !locale.contains("utf8")
+        });
+        limited_term || non_utf8_locale
+    }
+
+    /// Files to open, leaving out `+` startup arguments
+    fn file_args(&self) -> impl Iterator<Item = &String> {
+        self.files.iter().filter(|file| !file.starts_with('+'))
+    }
+
+    /// The first and last line to show from `--lines`, counting from 1
+    fn line_range(&self) -> Result<Option<(usize, usize)>> {
+        let Some(range) = &self.lines else {
+            return Ok(None);
+        };
+        let invalid = || color_eyre::eyre::eyre!("Invalid line range '{}': use <start>:<end>, like 10:20", range);
+        let (start, end) = range.split_once(':').ok_or_else(invalid)?;
+        let (start, end): (usize, usize) = (start.parse().map_err(|_| invalid())?, end.parse().map_err(|_| invalid())?);
+        if start == 0 || start > end {
+            return Err(color_eyre::eyre::eyre!(
+                "Invalid line range '{}': lines count from 1 and the start can't be past the end",
+                range
+            ));
+        }
+        Ok(Some((start, end)))
+    }
+
+    /// Where to start reading, from `--goto-heading`, `--start-at-end` or the
+    /// last `+` argument
+    fn start_position(&self) -> Result<Option<StartPosition>> {
+        if let Some(slug) = &self.goto_heading {
+            return Ok(Some(StartPosition::Heading(slug.clone())));
+        }
+        let Some(command) = self.files.iter().rev().find_map(|file| file.strip_prefix('+')) else {
+            return Ok(self.start_at_end.then_some(StartPosition::End));
+        };
+        if command == "G" {
+            Ok(Some(StartPosition::End))
+        } else if let Some(pattern) = command.strip_prefix('/') {
+            Ok(Some(StartPosition::Pattern(pattern.to_string())))
+        } else if let Ok(line) = command.parse() {
+            Ok(Some(StartPosition::Line(line)))
+        } else {
+            Err(color_eyre::eyre::eyre!(
+                "Invalid start position '+{}': use +G, +<line> or +/<pattern>",
+                command
+            ))
+        }
+    }
 }
 
+/// Where to put the view when a file opens, overriding a saved position
 #[derive(Debug, Clone, PartialEq)]
+enum StartPosition {
+    End,
+    Line(usize),
+    Pattern(String),
+    Heading(String),
+}
+
+/// How often watched files are checked for changes. A change runs the watch
+/// command once a check finds nothing more changed since the one before.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Build output and dependency directories left out of watched directories,
+/// along with hidden ones like `.git`
+const WATCH_SKIPPED_DIRS: [&str; 5] = ["target", "node_modules", "build", "dist", "__pycache__"];
+
+/// Bytes read from each file in a directory to tell text from binary
+const LISTING_SNIFF_BYTES: u64 = 1024;
+
+/// Number of lines moved per mouse wheel notch
+const MOUSE_SCROLL_LINES: usize = 3;
+
+/// Rows of rendered view reserved for each inline image
+const IMAGE_ROWS: usize = 12;
+
+/// How long to wait for a remote document before giving up
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Leading bytes identifying a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Markdown larger than this many bytes opens in source view unless `--max-render-size` says otherwise
+const DEFAULT_MAX_RENDER_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Files larger than this many bytes aren't read into memory; their lines are
+/// read from disk as they are shown, and they are only shown as source
+const STREAM_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Bytes a streamed file is scanned and read in at a time
+const STREAM_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Milliseconds between lines of auto-scroll unless `--interval` says otherwise
+const DEFAULT_AUTO_SCROLL_INTERVAL: u64 = 1000;
+
+/// Number of leading bytes inspected when deciding whether a file is binary
+const BINARY_SNIFF_BYTES: usize = 8192;
+
+/// Percentage of control characters above which a file is treated as binary
+const BINARY_CONTROL_PERCENT: usize = 10;
+
+/// Reading speed used to estimate reading time
+const READING_WORDS_PER_MINUTE: usize = 200;
+
+/// Width of the document statistics popup
+const STATS_POPUP_WIDTH: u16 = 40;
+
+/// Saved positions are ignored once a file's size changes by more than this percentage
+const RESUME_MAX_SIZE_CHANGE_PERCENT: u64 = 10;
+
+/// Width of the table of contents sidebar as a percentage of the screen
+const TOC_WIDTH_PERCENT: u16 = 30;
+
+/// Number of leading lines inspected when guessing whether an extensionless file is markdown
+const MARKDOWN_SNIFF_LINES: usize = 50;
+
+/// Delay between frames of a smooth scroll
+const SMOOTH_SCROLL_FRAME: Duration = Duration::from_millis(15);
+
+/// Each smooth scroll frame covers this fraction of the remaining distance (1/n)
+const SMOOTH_SCROLL_DIVISOR: usize = 4;
+
+/// How long to wait for input before redrawing when nothing is animating
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a footer message stays up when no key is pressed
+const STATUS_MESSAGE_DURATION: Duration = Duration::from_secs(2);
+
+/// Below this many columns side-by-side view stacks its panes unless `--split` says otherwise
+const NARROW_SPLIT_WIDTH: u16 = 100;
+
+/// Share of side-by-side view given to the rendered pane, in percent, and how far `<`/`>` move it
+const DEFAULT_SPLIT_RATIO: u16 = 50;
+const MIN_SPLIT_RATIO: u16 = 20;
+const MAX_SPLIT_RATIO: u16 = 80;
+const SPLIT_RATIO_STEP: u16 = 5;
+
+/// Reading widths cycled through by `w`; `None` uses the full terminal width
+const READING_WIDTH_PRESETS: [Option<u16>; 3] = [Some(80), Some(100), None];
+
+/// Documents longer than this are only searched once typing pauses, instead of on every key
+const LIVE_SEARCH_MAX_LINES: usize = 50_000;
+
+/// Lines of context kept around the cursor unless configured otherwise
+const DEFAULT_SCROLL_MARGIN: usize = 3;
+
+/// Columns a tab advances to when neither `--tab-width` nor `MESS_TAB_WIDTH` say
+const DEFAULT_TAB_WIDTH: usize = 4;
+
+/// How long typing must pause before a large document is searched
+const SEARCH_DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// How long a digit bound to an action waits for more digits making it a count
+const DIGIT_ACTION_DELAY: Duration = Duration::from_millis(500);
+
+const HELP_TEXT: &[&str] = &[
+    "mess - A less-like viewer with markdown support",
+    "Version: 0.1.0",
+    "",
+    "Keyboard Shortcuts:",
+    "  TAB          - Toggle view mode (rendered/source/side-by-side)",
+    "  1/2/3        - Switch straight to rendered/source/side-by-side view",
+    "                 (on their own; Alt+1/2/3 switch without waiting for a count)",
+    "  ↑/↓, j/k     - Move the cursor line up/down, scrolling at the edges",
+    "  Page Up/Down - Scroll up/down a page (also b/Space)",
+    "  u/d          - Scroll up/down half a page",
+    "  Ctrl+y/e     - Scroll the view a line, leaving the cursor where it is",
+    "  a            - Start/pause scrolling a line every --interval ms",
+    "  Home/End     - Go to beginning/end of file",
+    "  g/G          - Go to beginning/end of file",
+    "  <n> prefix   - Repeat a motion n times, or go to line n with g/G",
+    "  <n>% or <n>p - Go to n percent of the way through the file",
+    "  Mouse wheel  - Scroll up/down, click the scrollbar to jump",
+    "  Ctrl+n/p     - Switch to next/previous file",
+    "  t            - Toggle table of contents (↑/↓ select, Enter jump)",
+    "  q/Esc        - Quit (asks first with --confirm-quit)",
+    "  Ctrl+c       - Quit, even with --confirm-quit",
+    "  Ctrl+h       - Show this help",
+    "  :            - Command palette: type to filter actions, Enter to run",
+    "  Ctrl+t       - Find a heading: type part of it, Enter to jump",
+    "  Ctrl+g       - Show document statistics",
+    "  r            - Read the file again",
+    "  m<letter>    - Set a mark at the current position",
+    "  '<letter>    - Jump to a mark",
+    "  za           - Collapse/expand the section at the cursor",
+    "  ]] / [[      - Jump to the next/previous heading",
+    "  f            - Jump between a footnote reference and its definition",
+    "  s            - Stack side-by-side panes or put them in columns",
+    "  < / >        - Shrink/grow the rendered pane in side-by-side view",
+    "  w            - Cycle the reading width (80, 100, full)",
+    "  v            - Start/stop selecting lines from the cursor line",
+    "  y            - Copy the selection, or the visible lines, to the clipboard",
+    "  Ctrl+s       - Export the current view to a file",
+    "  |            - Pipe the selection, or the document, to a shell command",
+    "  -            - Go back to the directory listing a file was opened from",
+    "  Enter        - Open the link on the cursor line (with --follow-links)",
+    "  Backspace    - Go back to the file a link was followed from",
+    "  M            - Show/hide markdown markers like ** and # in rendered view",
+    "  W            - Mark/unmark rows continuing a wrapped line",
+    "  e            - Show/hide trailing whitespace in source view",
+    "  /            - Search as you type (Enter to keep, Esc to cancel)",
+    "  n / N        - Next/previous search match",
+    "  T            - Toggle TODO/FIXME highlighting",
+    "",
+    "View Modes (for markdown files):",
+    "  Rendered     - Shows rendered markdown",
+    "  Source       - Shows raw markdown source",
+    "  Side-by-side - Shows both rendered and source",
+    "",
+    "Files:",
+    "  A file named - is read from standard input, as in `cmd | mess -`. Keys",
+    "  then come from the terminal; without one the text is printed instead.",
+    "",
+    "Environment:",
+    "  MESS_THEME, MESS_MODE and MESS_TAB_WIDTH stand in for --theme, --mode",
+    "  and --tab-width when those aren't given.",
+    "",
+    "Saved Settings:",
+    "  The view mode, wrapping, wrap markers and theme in use on quitting are",
+    "  restored next time. Flags like --mode and --theme win over them, and",
+    "  they win over the defaults; --no-resume ignores them.",
+    "",
+    "Use ↑/↓ to scroll, q/Esc/Ctrl+h to close",
+];
+
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
 enum ViewMode {
     Rendered,
     Source,
     SideBySide,
 }
 
+/// How rendered lines too wide for the pane are wrapped
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+enum WrapMode {
+    /// Break between words, indenting the continuation rows
+    Word,
+    /// Break between words, starting continuation rows at the left edge
+    Plain,
+}
+
+/// When the scrollbar is shown
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum ScrollbarMode {
+    /// Only when the document doesn't fit on screen
+    Auto,
+    Always,
+    Never,
+}
+
+/// How the scrollbar is drawn
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum ScrollbarStyle {
+    /// Arrows at both ends of the track
+    Arrows,
+    /// Just the track
+    Plain,
+}
+
+/// Rendering of a document over `--max-render-size`, which waits to be asked for
 #[derive(Debug)]
-struct AppState {
-    content: String,
-    rendered_content: String,
-    view_mode: ViewMode,
-    scroll_offset: usize,
-    file_path: String,
-    is_markdown: bool,
+enum DeferredRender {
+    /// Not started yet; Tab starts it
+    Waiting(RenderOptions),
+    /// Running on a background thread since `started`, which sends the result
+    /// when done; the view then switches to `mode`
+    Running { started: Instant, receiver: mpsc::Receiver<Result<RenderedMarkdown>>, mode: ViewMode },
 }
 
-impl AppState {
-    fn new(file_path: String) -> Result<Self> {
-        // Check if file exists first
-        if !std::path::Path::new(&file_path).exists() {
-            return Err(color_eyre::eyre::eyre!("File '{}' does not exist", file_path));
-        }
-        
-        let content = fs::read_to_string(&file_path)
-            .map_err(|e| color_eyre::eyre::eyre!("Failed to read file '{}': {}", file_path, e))?;
-        let is_markdown = file_path.ends_with(".md") || file_path.ends_with(".markdown");
-        
-        let rendered_content = if is_markdown {
-            Self::render_markdown(&content)
-        } else {
-            content.clone()
-        };
+/// Which lines source view numbers in its gutter
+#[derive(Debug, Clone, PartialEq)]
+enum Numbering {
+    Off,
+    /// Every line, from `--number`
+    All,
+    /// Only lines that aren't blank, from `--number-nonblank`, with the number of each
+    NonBlank(Vec<Option<usize>>),
+}
 
-        Ok(AppState {
-            content,
-            rendered_content,
-            view_mode: if is_markdown { ViewMode::Rendered } else { ViewMode::Source },
-            scroll_offset: 0,
-            file_path,
-            is_markdown,
-        })
+/// How the panes of side-by-side view are divided
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum SplitOrientation {
+    /// Rendered pane above the source pane
+    Horizontal,
+    /// Rendered pane left of the source pane
+    Vertical,
+}
+
+/// A command waiting for its second key, like the letter naming a mark
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PendingCommand {
+    SetMark,
+    JumpToMark,
+    Fold,
+    NextHeading,
+    PreviousHeading,
+    /// `--confirm-quit` asking whether to quit
+    Quit,
+}
+
+/// The `:` command palette: every action, filtered by what has been typed
+#[derive(Debug, Clone, Default)]
+struct CommandPalette {
+    query: String,
+    selected: usize,
+}
+
+impl CommandPalette {
+    /// Actions whose description or config name contains the query, ignoring case
+    fn matches(&self) -> Vec<Action> {
+        let query = self.query.trim().to_lowercase();
+        Action::ALL
+            .into_iter()
+            .filter(|&action| action != Action::Palette)
+            .filter(|action| action.description().to_lowercase().contains(&query) || action.name().contains(&query))
+            .collect()
+    }
+
+    /// The line to go to when the query is a number
+    fn line(&self) -> Option<usize> {
+        self.query.trim().parse().ok()
+    }
+}
+
+/// The `Ctrl+t` heading finder: headings fuzzy matched against what has been typed
+#[derive(Debug, Clone, Default)]
+struct HeadingFinder {
+    query: String,
+    selected: usize,
+    /// Table of contents indexes of the matching headings, best first, with
+    /// the positions of their characters that matched
+    matches: Vec<(usize, Vec<usize>)>,
+}
+
+impl HeadingFinder {
+    fn new(toc: &[TocEntry]) -> Self {
+        let mut finder = HeadingFinder::default();
+        finder.update(toc);
+        finder
+    }
+
+    /// Match the headings again after the query changes
+    fn update(&mut self, toc: &[TocEntry]) {
+        let mut scored: Vec<(i64, usize, Vec<usize>)> = toc
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| {
+                fuzzy_match(&entry.text, &self.query).map(|(score, positions)| (score, index, positions))
+            })
+            .collect();
+        // Equal scores stay in document order
+        scored.sort_by_key(|&(score, index, _)| (std::cmp::Reverse(score), index));
+        self.matches = scored.into_iter().map(|(_, index, positions)| (index, positions)).collect();
+        self.selected = 0;
     }
+}
 
+/// Score `text` against `query` if the query's characters all appear in it in
+/// order, ignoring case, along with the positions of the characters matched.
+/// Characters starting a word or following the last match score higher, and
+/// characters skipped between matches lower.
+fn fuzzy_match(text: &str, query: &str) -> Option<(i64, Vec<usize>)> {
+    let text: Vec<char> = text.chars().collect();
+    let query: Vec<char> = query.chars().filter(|c| !c.is_whitespace()).collect();
+    let same = |a: char, b: char| a.to_lowercase().eq(b.to_lowercase());
+    let Some((&first, rest)) = query.split_first() else {
+        return Some((0, Vec::new()));
+    };
 
-    fn render_markdown(content: &str) -> String {
-        let parser = Parser::new(content);
-        let mut result = String::new();
-        
-        for event in parser {
-            match event {
-                pulldown_cmark::Event::Start(pulldown_cmark::Tag::Heading { level, .. }) => {
-                    result.push('\n');
-                    for _ in 0..level as usize {
-                        result.push('#');
+    // Try each place the query could start, taking the next match after it for the rest
+    (0..text.len())
+        .filter(|&start| same(text[start], first))
+        .filter_map(|start| {
+            let mut positions = vec![start];
+            for &wanted in rest {
+                let from = positions[positions.len() - 1] + 1;
+                positions.push((from..text.len()).find(|&index| same(text[index], wanted))?);
+            }
+            let score = positions
+                .iter()
+                .enumerate()
+                .map(|(n, &index)| {
+                    let word_start = index == 0 || !text[index - 1].is_alphanumeric();
+                    let skipped = if n == 0 { 0 } else { index - positions[n - 1] - 1 };
+                    let mut score = 1 - skipped.min(3) as i64;
+                    if word_start {
+                        score += 8;
                     }
-                    result.push(' ');
-                }
-                pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Heading(_)) => {
-                    result.push('\n');
-                }
-                pulldown_cmark::Event::Start(pulldown_cmark::Tag::Paragraph) => {
-                    if !result.ends_with('\n') {
-                        result.push('\n');
+                    if n > 0 && skipped == 0 {
+                        score += 5;
                     }
-                }
-                pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Paragraph) => {
-                    result.push('\n');
-                }
-                pulldown_cmark::Event::Start(pulldown_cmark::Tag::CodeBlock(_)) => {
-                    result.push_str("\n```\n");
-                }
-                pulldown_cmark::Event::End(pulldown_cmark::TagEnd::CodeBlock) => {
-                    result.push_str("\n```\n");
-                }
-                pulldown_cmark::Event::Start(pulldown_cmark::Tag::List(_)) => {
-                    result.push('\n');
-                }
-                pulldown_cmark::Event::End(pulldown_cmark::TagEnd::List(_)) => {
-                    result.push('\n');
-                }
-                pulldown_cmark::Event::Start(pulldown_cmark::Tag::Item) => {
-                    result.push_str("• ");
-                }
-                pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Item) => {
-                    result.push('\n');
-                }
-                pulldown_cmark::Event::Start(pulldown_cmark::Tag::BlockQuote) => {
-                    result.push_str("\n> ");
-                }
-                pulldown_cmark::Event::End(pulldown_cmark::TagEnd::BlockQuote) => {
-                    result.push('\n');
-                }
-                pulldown_cmark::Event::Start(pulldown_cmark::Tag::Strong) => {
-                    result.push_str("**");
-                }
-                pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Strong) => {
-                    result.push_str("**");
-                }
-                pulldown_cmark::Event::Start(pulldown_cmark::Tag::Emphasis) => {
-                    result.push('*');
-                }
-                pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Emphasis) => {
-                    result.push('*');
-                }
-                pulldown_cmark::Event::Code(text) => {
-                    result.push('`');
-                    result.push_str(&text);
-                    result.push('`');
-                }
-                pulldown_cmark::Event::Rule => {
-                    result.push_str("\n---\n");
-                }
-                pulldown_cmark::Event::Text(text) => {
-                    result.push_str(&text);
-                }
-                pulldown_cmark::Event::SoftBreak => {
-                    result.push('\n');
-                }
-                pulldown_cmark::Event::HardBreak => {
-                    result.push('\n');
-                }
-                _ => {
-                    // Handle other events as needed
-                }
-            }
-        }
-        
-        // Clean up multiple newlines
-        while result.contains("\n\n\n") {
-            result = result.replace("\n\n\n", "\n\n");
+                    score
+                })
+                .sum();
+            Some((score, positions))
+        })
+        .max_by_key(|(score, positions)| (*score, std::cmp::Reverse(positions[0])))
+}
+
+/// A search query being typed, searched live as it changes
+#[derive(Debug, Clone)]
+struct SearchInput {
+    query: String,
+    /// Where the view and cursor were when the search started, restored on cancel
+    origin: usize,
+    origin_cursor: usize,
+    /// The query changed but a large document hasn't been searched for it yet
+    stale: bool,
+}
+
+/// The text files of a directory opened from the command line
+#[derive(Debug, Clone)]
+struct Listing {
+    directory: String,
+    /// File names, sorted
+    files: Vec<String>,
+    selected: usize,
+}
+
+/// The old file of a `--diff`, lined up row by row with the buffer's content
+#[derive(Debug)]
+struct DiffView {
+    old_path: String,
+    old_content: String,
+    old_index: LineIndex,
+    rows: Vec<DiffRow>,
+}
+
+/// Size and modification time of a local file, shown in the header
+#[derive(Debug, Clone, Copy)]
+struct FileMetadata {
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Where an inline image is drawn on screen
+#[derive(Debug, Clone, PartialEq)]
+struct ImageSlot {
+    path: PathBuf,
+    area: Rect,
+}
+
+/// Byte offsets where each line of a text starts, so single lines can be
+/// sliced out without splitting the whole text. Lines follow `str::lines`.
+#[derive(Debug, Default)]
+struct LineIndex {
+    starts: Vec<usize>,
+    text_len: usize,
+}
+
+impl LineIndex {
+    fn new(text: &str) -> LineIndex {
+        let mut starts = Vec::new();
+        if !text.is_empty() {
+            starts.push(0);
         }
-        
-        result.trim().to_string()
+        starts.extend(
+            text.match_indices('\n')
+                .map(|(index, _)| index + 1)
+                .filter(|&start| start < text.len()),
+        );
+        LineIndex { starts, text_len: text.len() }
     }
 
-    fn toggle_view_mode(&mut self) {
-        if !self.is_markdown {
-            return; // Only toggle for markdown files
+    /// The index of text read from `reader` a block at a time, without keeping it
+    fn from_reader(mut reader: impl Read) -> io::Result<LineIndex> {
+        let mut starts = vec![0];
+        let mut buffer = vec![0; STREAM_BLOCK_SIZE];
+        let mut text_len = 0;
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let newlines = buffer[..read].iter().enumerate().filter(|&(_, &byte)| byte == b'\n');
+            starts.extend(newlines.map(|(index, _)| text_len + index + 1));
+            text_len += read;
         }
-        
-        self.view_mode = match self.view_mode {
-            ViewMode::Rendered => ViewMode::Source,
-            ViewMode::Source => ViewMode::SideBySide,
-            ViewMode::SideBySide => ViewMode::Rendered,
-        };
-        self.scroll_offset = 0; // Reset scroll when changing view
+        // As in `new`, a final newline doesn't start another line
+        if starts.last() == Some(&text_len) {
+            starts.pop();
+        }
+        Ok(LineIndex { starts, text_len })
     }
 
-    fn scroll_up(&mut self, lines: usize) {
-        if self.scroll_offset > lines {
-            self.scroll_offset -= lines;
-        } else {
-            self.scroll_offset = 0;
+    fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    /// Byte range of line `index`, with its newline
+    fn range(&self, index: usize) -> (usize, usize) {
+        (self.starts[index], self.starts.get(index + 1).copied().unwrap_or(self.text_len))
+    }
+
+    /// Line `index` of `text`, the text this index was built from
+    fn line<'a>(&self, text: &'a str, index: usize) -> &'a str {
+        let (start, end) = self.range(index);
+        text[start..end].trim_end_matches('\n')
+    }
+}
+
+/// A file too large to read into memory, read from disk as its lines are
+/// shown. Line endings are left as they are on disk; `\r\n` lines lose their
+/// `\r` when read, and copies get the endings `normalize_line_endings` gives.
+#[derive(Debug)]
+struct StreamedFile {
+    path: PathBuf,
+    file: fs::File,
+    /// The block read last and the offset it starts at, since the lines read
+    /// next are usually close by
+    block: RefCell<(usize, Vec<u8>)>,
+    /// Words and characters, counted the first time they are asked for
+    counts: OnceCell<(usize, usize)>,
+}
+
+impl StreamedFile {
+    /// Line `index` of the file `lines` indexes. A line that can't be read,
+    /// as when the file has shrunk since, comes back empty.
+    fn line(&self, lines: &LineIndex, index: usize) -> String {
+        let (start, end) = lines.range(index);
+        let mut block = self.block.borrow_mut();
+        let (offset, bytes) = &mut *block;
+        if start < *offset || end > *offset + bytes.len() {
+            // Half a block before the line too, so scrolling up reads as seldom
+            // as scrolling down. Lines longer than a block are read whole.
+            *offset = start.saturating_sub(STREAM_BLOCK_SIZE / 2);
+            let length = (end - *offset).max(STREAM_BLOCK_SIZE);
+            bytes.clear();
+            let mut file = &self.file;
+            let read = file.seek(SeekFrom::Start(*offset as u64)).and_then(|_| file.take(length as u64).read_to_end(bytes));
+            if read.is_err() {
+                bytes.clear();
+            }
         }
+        let line = &bytes[(start - *offset).min(bytes.len())..(end - *offset).min(bytes.len())];
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line)).into_owned()
     }
 
-    fn scroll_down(&mut self, lines: usize, max_lines: usize) {
-        if self.scroll_offset + lines < max_lines {
-            self.scroll_offset += lines;
-        } else {
-            self.scroll_offset = max_lines.saturating_sub(1);
+    /// The lines of the file from the start, read through a separate handle
+    fn lines(&self) -> io::Result<impl Iterator<Item = String>> {
+        let reader = BufReader::with_capacity(STREAM_BLOCK_SIZE, fs::File::open(&self.path)?);
+        Ok(reader.split(b'\n').map_while(Result::ok).map(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            String::from_utf8_lossy(line).into_owned()
+        }))
+    }
+
+    /// Words and characters in the file, as `DocumentStats` counts them
+    fn counts(&self) -> (usize, usize) {
+        *self.counts.get_or_init(|| {
+            let Ok(file) = fs::File::open(&self.path) else {
+                return (0, 0);
+            };
+            let mut reader = BufReader::with_capacity(STREAM_BLOCK_SIZE, file);
+            let (mut words, mut characters, mut line) = (0, 0, Vec::new());
+            while reader.read_until(b'\n', &mut line).is_ok_and(|read| read > 0) {
+                let text = String::from_utf8_lossy(&line);
+                words += text.split_whitespace().count();
+                // A `\r\n` ending counts once, as it would once normalized
+                characters += text.chars().count() - usize::from(text.ends_with("\r\n"));
+                line.clear();
+            }
+            (words, characters)
+        })
+    }
+
+    /// Copy the file to `writer` as text, or as a `<pre>` block of HTML. Like a
+    /// file read into memory, the text has `\n` line endings and ends with one.
+    fn copy_to(&self, writer: &mut impl Write, html: bool) -> io::Result<()> {
+        let mut writer = io::BufWriter::with_capacity(STREAM_BLOCK_SIZE, writer);
+        if html {
+            writer.write_all(b"<pre>")?;
+        }
+        for line in self.lines()? {
+            if html {
+                writeln!(writer, "{}", escape_html(&line))?;
+            } else {
+                // A `\r` left inside a line ended one on its own
+                writeln!(writer, "{}", line.replace('\r', "\n"))?;
+            }
+        }
+        if html {
+            writer.write_all(b"</pre>\n")?;
         }
+        writer.flush()
     }
+}
 
-    fn get_content_lines(&self) -> Vec<String> {
-        match self.view_mode {
-            ViewMode::Rendered => self.rendered_content.lines().map(|s| s.to_string()).collect(),
-            ViewMode::Source => self.content.lines().map(|s| s.to_string()).collect(),
-            ViewMode::SideBySide => {
-                // For side-by-side, we render separately in render_side_by_side function
-                // but still need to return something for scrollbar calculation
-                let rendered_lines: Vec<String> = self.rendered_content.lines().map(|s| s.to_string()).collect();
-                let source_lines: Vec<String> = self.content.lines().map(|s| s.to_string()).collect();
-                // Return the longer of the two for scrollbar calculation
-                if rendered_lines.len() > source_lines.len() {
-                    rendered_lines
-                } else {
-                    source_lines
-                }
+/// The screen row each line of a view starts on once wrapped to `width`,
+/// followed by the total number of rows. Side-by-side view counts the taller
+/// of the two panes, with the source pane `source_width` wide.
+#[derive(Debug)]
+struct DisplayRows {
+    width: usize,
+    source_width: usize,
+    view_mode: ViewMode,
+    starts: Vec<usize>,
+}
+
+/// Rendered lines styled for a pane of a given width
+#[derive(Debug)]
+struct StyledCache {
+    width: usize,
+    lines: Vec<Line<'static>>,
+}
+
+/// Turns a document format into the rendered view, picked by file extension
+trait Renderer: std::fmt::Debug + Send + Sync {
+    /// The rendered view of `content`, or why it has none
+    fn render(&self, content: &str, options: &RenderOptions) -> Result<RenderedMarkdown>;
+
+    /// `content` as HTML, for exports
+    fn to_html(&self, content: &str) -> String;
+
+    /// Whether source view is syntax highlighted, for formats that aren't prose
+    fn highlights_source(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+struct MarkdownRenderer;
+
+impl Renderer for MarkdownRenderer {
+    fn render(&self, content: &str, options: &RenderOptions) -> Result<RenderedMarkdown> {
+        Ok(render_markdown(content, options))
+    }
+
+    fn to_html(&self, content: &str) -> String {
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, Parser::new_ext(content, markdown_options()));
+        html
+    }
+}
+
+/// Basic reStructuredText, rewritten as markdown line for line
+#[derive(Debug)]
+struct RstRenderer;
+
+impl Renderer for RstRenderer {
+    fn render(&self, content: &str, options: &RenderOptions) -> Result<RenderedMarkdown> {
+        MarkdownRenderer.render(&rst::to_markdown(content), options)
+    }
+
+    fn to_html(&self, content: &str) -> String {
+        MarkdownRenderer.to_html(&rst::to_markdown(content))
+    }
+}
+
+/// JSON, reindented, as a code block
+#[derive(Debug)]
+struct JsonRenderer;
+
+impl JsonRenderer {
+    fn pretty(content: &str) -> Result<String> {
+        let value: serde_json::Value =
+            serde_json::from_str(content).map_err(|e| color_eyre::eyre::eyre!("Invalid JSON: {}", e))?;
+        Ok(serde_json::to_string_pretty(&value)?)
+    }
+}
+
+impl Renderer for JsonRenderer {
+    fn render(&self, content: &str, options: &RenderOptions) -> Result<RenderedMarkdown> {
+        MarkdownRenderer.render(&fenced("json", &Self::pretty(content)?), options)
+    }
+
+    fn to_html(&self, content: &str) -> String {
+        format!("<pre>{}</pre>\n", escape_html(&Self::pretty(content).unwrap_or_else(|_| content.to_string())))
+    }
+
+    fn highlights_source(&self) -> bool {
+        true
+    }
+}
+
+/// TOML, written out again in its usual layout, as a code block. Comments
+/// only survive in source view.
+#[derive(Debug)]
+struct TomlRenderer;
+
+impl TomlRenderer {
+    fn pretty(content: &str) -> Result<String> {
+        let table: toml::Table = toml::from_str(content).map_err(|e| {
+            color_eyre::eyre::eyre!("Invalid TOML: {}", e.message())
+        })?;
+        Ok(toml::to_string_pretty(&table)?)
+    }
+}
+
+impl Renderer for TomlRenderer {
+    fn render(&self, content: &str, options: &RenderOptions) -> Result<RenderedMarkdown> {
+        MarkdownRenderer.render(&fenced("toml", &Self::pretty(content)?), options)
+    }
+
+    fn to_html(&self, content: &str) -> String {
+        format!("<pre>{}</pre>\n", escape_html(&Self::pretty(content).unwrap_or_else(|_| content.to_string())))
+    }
+
+    fn highlights_source(&self) -> bool {
+        true
+    }
+}
+
+/// `code` as a markdown code block in `language`, fenced with more backticks
+/// than any run of them inside it
+fn fenced(language: &str, code: &str) -> String {
+    let longest_run = code.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_run.max(2) + 1);
+    format!("{}{}\n{}\n{}\n", fence, language, code.trim_end(), fence)
+}
+
+#[derive(Debug)]
+struct AppState {
+    content: String,
+    rendered_content: String,
+    source_index: LineIndex,
+    rendered_index: LineIndex,
+    view_mode: ViewMode,
+    scroll_offset: usize,
+    /// View position of the current line, which `j`/`k` move and the view follows
+    cursor_line: usize,
+    /// Lines that fit in the pane, as of the last frame
+    view_height: usize,
+    /// Columns the text wraps at, as of the last frame
+    view_width: usize,
+    file_path: String,
+    canonical_path: Option<PathBuf>,
+    file_size: u64,
+    /// `None` for documents that didn't come from a local file
+    metadata: Option<FileMetadata>,
+    /// Renderer for the document's format; documents without one only have source view
+    renderer: Option<Arc<dyn Renderer>>,
+    tab_width: usize,
+    pending_count: Option<usize>,
+    content_area: Rect,
+    show_help: bool,
+    help_scroll: usize,
+    show_stats: bool,
+    marks: HashMap<char, usize>,
+    /// Rendered lines of headings whose sections are collapsed
+    collapsed: BTreeSet<usize>,
+    /// Rendered line behind each line of rendered view while any section is collapsed
+    fold_map: Vec<usize>,
+    pending_command: Option<PendingCommand>,
+    /// Footer message and when it times out; it goes at the next key, or
+    /// once it has been up for `STATUS_MESSAGE_DURATION` unless it lasts
+    status_message: Option<(String, Option<Instant>)>,
+    /// Rendering put off because the document is over `--max-render-size`
+    deferred_render: Option<DeferredRender>,
+    toc: Vec<TocEntry>,
+    show_toc: bool,
+    toc_selected: usize,
+    /// Directory this buffer was opened from, and whether its listing is shown
+    listing: Option<Listing>,
+    /// The file this one is compared against, for `--diff`
+    diff: Option<DiffView>,
+    /// Where lines are read from for a file too large to hold in `content`,
+    /// which is left empty
+    streamed: Option<StreamedFile>,
+    show_listing: bool,
+    footnotes: Vec<FootnoteLink>,
+    /// Where a smooth scroll is heading, while it is still animating
+    scroll_target: Option<usize>,
+    /// Side-by-side orientation chosen by `--split` or the toggle, `None` to pick by width
+    split: Option<SplitOrientation>,
+    /// Percentage of side-by-side view taken by the rendered pane
+    split_ratio: u16,
+    scrollbar: ScrollbarMode,
+    scrollbar_style: ScrollbarStyle,
+    /// Whether the scrollbar took a column, as of the last frame
+    scrollbar_shown: bool,
+    /// Syntax highlighting for source files in a language we recognize
+    highlighter: Option<SourceHighlighter>,
+    styled_cache: Option<StyledCache>,
+    /// Wrapped heights of the lines in single view, measured when drawn
+    display_rows: Option<DisplayRows>,
+    /// Scroll to the end once the first frame knows how tall the view is
+    pending_bottom: bool,
+    /// Whether rendered view keeps the markdown markers, dimmed
+    show_markers: bool,
+    /// Whether rows continuing a wrapped line get a `↳` in the gutter
+    wrap_markers: bool,
+    /// How rendered lines wrap
+    wrap_mode: WrapMode,
+    /// Whether source view shows trailing whitespace
+    trailing_whitespace: bool,
+    numbering: Numbering,
+    /// Whether to draw with ASCII symbols only
+    ascii: bool,
+    search_input: Option<SearchInput>,
+    /// The active search and the view positions of lines matching it, in order
+    search_query: String,
+    search_matches: Vec<usize>,
+    /// Index into `search_matches` of the match last jumped to
+    current_match: Option<usize>,
+    /// Lines kept visible above a position the view jumps to, like vim's `scrolloff`
+    scroll_margin: usize,
+    /// Lines shared by consecutive pages, from `--scroll-overlap`
+    scroll_overlap: usize,
+    /// View position where a line selection started; it extends to the cursor line
+    selection_anchor: Option<usize>,
+    /// File name being typed for an export
+    export_input: Option<String>,
+    /// Shell command being typed after `|`
+    pipe_input: Option<String>,
+    images: Vec<InlineImage>,
+    /// Images placed by the last frame, to be drawn over their reserved rows
+    image_slots: Vec<ImageSlot>,
+}
+
+impl AppState {
+    fn new(file_path: String, args: &Args, theme: &Theme, interactive: bool) -> Result<Self> {
+        let is_remote = is_url(&file_path);
+        let is_stdin = file_path == STDIN_PATH;
+        if !is_remote && std::path::Path::new(&file_path).is_dir() {
+            return Self::for_directory(file_path, args, theme, interactive);
+        }
+        let bytes = if is_remote {
+            fetch_url(&file_path)?
+        } else if is_stdin {
+            let mut bytes = Vec::new();
+            io::stdin()
+                .read_to_end(&mut bytes)
+                .map_err(|e| color_eyre::eyre::eyre!("Failed to read standard input: {}", e))?;
+            bytes
+        } else {
+            // Check if file exists first
+            if !std::path::Path::new(&file_path).exists() {
+                return Err(color_eyre::eyre::eyre!("File '{}' does not exist", file_path));
+            }
+            if let Some(app_state) = Self::open_streamed(&file_path, args, theme, interactive)? {
+                return Ok(app_state);
+            }
+            fs::read(&file_path)
+                .map_err(|e| color_eyre::eyre::eyre!("Failed to read file '{}': {}", file_path, e))?
+        };
+        let file_size = bytes.len() as u64;
+        // Remote documents and standard input have no path to remember a reading position by
+        let canonical_path = if is_remote || is_stdin { None } else { fs::canonicalize(&file_path).ok() };
+        let metadata = if is_remote || is_stdin {
+            None
+        } else {
+            fs::metadata(&file_path).ok().map(|metadata| FileMetadata {
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            })
+        };
+        let is_gzip = file_path.ends_with(".gz") || bytes.starts_with(&GZIP_MAGIC);
+        let bytes = if is_gzip {
+            decompress_gzip(&bytes)
+                .map_err(|e| color_eyre::eyre::eyre!("Failed to decompress file '{}': {}", file_path, e))?
+        } else {
+            bytes
+        };
+        let content = match &args.encoding {
+            Some(label) => {
+                let encoding = Encoding::for_label(label.as_bytes())
+                    .ok_or_else(|| color_eyre::eyre::eyre!("Unknown encoding '{}'", label))?;
+                encoding.decode(&bytes).0.into_owned()
+            }
+            None => {
+                if looks_binary(&bytes) {
+                    return Err(color_eyre::eyre::eyre!(
+                        "File '{}' appears to be binary; pass --encoding if it is text in another encoding",
+                        file_path
+                    ));
+                }
+                // Invalid sequences become replacement characters instead of failing to open
+                match String::from_utf8(bytes) {
+                    Ok(content) => content,
+                    Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                }
+            }
+        };
+        let content = match args.line_range()? {
+            Some((start, end)) => select_lines(&normalize_line_endings(content), start, end).ok_or_else(|| {
+                color_eyre::eyre::eyre!("File '{}' has fewer than {} lines", file_path, start)
+            })?,
+            None => content,
+        };
+        let mut app_state = Self::from_content(file_path, content, args, theme, interactive);
+        app_state.canonical_path = canonical_path;
+        app_state.file_size = file_size;
+        app_state.metadata = metadata;
+        Ok(app_state)
+    }
+
+    /// A buffer for a local file over `STREAM_THRESHOLD`, shown as source and
+    /// read from disk as its lines are shown. `None` for smaller files, and
+    /// for ones that have to be decoded or cut down in memory first.
+    fn open_streamed(file_path: &str, args: &Args, theme: &Theme, interactive: bool) -> Result<Option<Self>> {
+        let read_error = |e: io::Error| color_eyre::eyre::eyre!("Failed to read file '{}': {}", file_path, e);
+        let metadata = fs::metadata(file_path).map_err(read_error)?;
+        if !metadata.is_file()
+            || metadata.len() <= STREAM_THRESHOLD
+            || file_path.ends_with(".gz")
+            || args.encoding.is_some()
+            || args.strip_ansi
+            || args.lines.is_some()
+        {
+            return Ok(None);
+        }
+        let mut file = fs::File::open(file_path).map_err(read_error)?;
+        let mut head = Vec::new();
+        (&mut file).take(BINARY_SNIFF_BYTES as u64).read_to_end(&mut head).map_err(read_error)?;
+        if head.starts_with(&GZIP_MAGIC) {
+            return Ok(None);
+        }
+        if looks_binary(&head) {
+            return Err(color_eyre::eyre::eyre!(
+                "File '{}' appears to be binary; pass --encoding if it is text in another encoding",
+                file_path
+            ));
+        }
+        file.rewind().map_err(read_error)?;
+        let source_index = LineIndex::from_reader(&file).map_err(read_error)?;
+        let streamed = StreamedFile { path: PathBuf::from(file_path), file, block: RefCell::default(), counts: OnceCell::new() };
+
+        let mut app_state = Self::from_content(file_path.to_string(), String::new(), args, theme, interactive);
+        app_state.source_index = source_index;
+        app_state.file_size = metadata.len();
+        app_state.canonical_path = fs::canonicalize(file_path).ok();
+        app_state.metadata = Some(FileMetadata { size: metadata.len(), modified: metadata.modified().ok() });
+        // Nothing is rendered or highlighted, since that takes the whole file
+        app_state.renderer = None;
+        app_state.highlighter = None;
+        app_state.view_mode = ViewMode::Source;
+        if args.number_nonblank && !args.number {
+            app_state.numbering = Numbering::NonBlank(nonblank_numbers(streamed.lines().map_err(read_error)?));
+        }
+        if interactive {
+            let message = format!("Over {}, so read from disk as needed and shown as source", format_size(STREAM_THRESHOLD));
+            app_state.status_message = Some((message, None));
+        }
+        app_state.streamed = Some(streamed);
+        Ok(Some(app_state))
+    }
+
+    /// A buffer listing the text files in `directory` to pick one from. The
+    /// content is the list of names, which is what plain output prints.
+    fn for_directory(directory: String, args: &Args, theme: &Theme, interactive: bool) -> Result<Self> {
+        let files = list_text_files(std::path::Path::new(&directory))
+            .map_err(|e| color_eyre::eyre::eyre!("Failed to read directory '{}': {}", directory, e))?;
+        let mut app_state = Self::from_content(directory.clone(), files.join("\n"), args, theme, interactive);
+        app_state.listing = Some(Listing { directory, files, selected: 0 });
+        app_state.show_listing = true;
+        Ok(app_state)
+    }
+
+    /// A buffer comparing `new_path` against `old_path`, both shown as source
+    /// side by side
+    fn for_diff(old_path: String, new_path: String, args: &Args, theme: &Theme, interactive: bool) -> Result<Self> {
+        let old = Self::new(old_path.clone(), args, theme, interactive)?;
+        let mut app_state = Self::new(new_path, args, theme, interactive)?;
+        if let Some(listing) = old.listing.as_ref().or(app_state.listing.as_ref()) {
+            return Err(color_eyre::eyre::eyre!("Can't compare directory '{}'", listing.directory));
+        }
+        if let Some(streamed) = old.streamed.as_ref().or(app_state.streamed.as_ref()) {
+            return Err(color_eyre::eyre::eyre!(
+                "Can't compare '{}', which is over {}",
+                streamed.path.display(),
+                format_size(STREAM_THRESHOLD)
+            ));
+        }
+        let old_lines: Vec<&str> = old.content.lines().collect();
+        let new_lines: Vec<&str> = app_state.content.lines().collect();
+        let rows = diff::diff_lines(&old_lines, &new_lines);
+        app_state.diff = Some(DiffView { old_path, old_content: old.content, old_index: old.source_index, rows });
+        // Both panes are source, so nothing rendered is kept
+        app_state.renderer = None;
+        app_state.deferred_render = None;
+        app_state.status_message = None;
+        app_state.rendered_content = String::new();
+        app_state.rendered_index = LineIndex::default();
+        app_state.toc = Vec::new();
+        app_state.footnotes = Vec::new();
+        app_state.images = Vec::new();
+        app_state.view_mode = ViewMode::SideBySide;
+        Ok(app_state)
+    }
+
+    /// A buffer for `content` that was read from `file_path`, or produced
+    /// under that name, without anything that depends on the file on disk
+    fn from_content(file_path: String, content: String, args: &Args, theme: &Theme, interactive: bool) -> Self {
+        let is_remote = is_url(&file_path);
+        let content = normalize_line_endings(content);
+        let content = if args.strip_ansi { strip_ansi(&content) } else { content };
+
+        // Judge the format by the name of the uncompressed file, or the path of a URL
+        let name = if is_remote { url_path(&file_path) } else { &file_path };
+        let name = name.strip_suffix(".gz").unwrap_or(name);
+        let renderer = renderer_for(name, &content, args);
+        let has_renderer = renderer.is_some();
+        // Rendering a huge document up front could hang the pager, so it opens
+        // as source and is only rendered when asked for
+        let too_large = has_renderer && content.len() as u64 > args.max_render_size;
+        let too_large_message = too_large.then(|| {
+            let size = format_size(args.max_render_size);
+            match interactive {
+                true => format!("Over {}, so shown as source; press Tab to render it anyway", size),
+                false => format!("Warning: '{}' is over {}, so printed as source; see --max-render-size", file_path, size),
+            }
+        });
+        if let Some(message) = &too_large_message
+            && !interactive
+        {
+            eprintln!("{}", message);
+        }
+
+        // Only ask the terminal about graphics when there may be images to draw on it
+        let image_rows = if interactive
+            && !is_remote
+            && has_renderer
+            && (content.contains("![") || content.contains("image::"))
+            && images::graphics_supported()
+        {
+            IMAGE_ROWS
+        } else {
+            0
+        };
+        let image_dir = (!is_remote).then(|| document_dir(&file_path).to_path_buf());
+        let options = RenderOptions { image_rows, html: args.html, tab_width: args.tab_width(), ascii: args.ascii(), image_dir };
+        let rendered = match &renderer {
+            Some(renderer) if !too_large => renderer.render(&content, &options),
+            // There is only source view until then, so no second copy is kept
+            _ => Ok(RenderedMarkdown::default()),
+        };
+        // A document that can't be rendered, like malformed JSON, is still shown as source
+        let (mut rendered, render_error) = match rendered {
+            Ok(rendered) => (rendered, None),
+            Err(e) => (RenderedMarkdown::default(), Some(format!("{}; showing source", e))),
+        };
+        if let Some(error) = &render_error
+            && !interactive
+        {
+            eprintln!("Warning: '{}': {}", file_path, error);
+        }
+        let renderer = renderer.filter(|_| render_error.is_none());
+        let has_renderer = renderer.is_some();
+        resolve_image_paths(&file_path, &mut rendered.images);
+        let highlighter = if renderer.as_ref().is_some_and(|renderer| !renderer.highlights_source()) || theme.monochrome {
+            None
+        } else {
+            SourceHighlighter::for_file(name, &theme.syntax_theme)
+        };
+
+        let numbering = if args.number {
+            Numbering::All
+        } else if args.number_nonblank {
+            Numbering::NonBlank(nonblank_numbers(content.lines()))
+        } else {
+            Numbering::Off
+        };
+
+        AppState {
+            file_size: content.len() as u64,
+            source_index: LineIndex::new(&content),
+            rendered_index: LineIndex::new(&rendered.text),
+            content,
+            rendered_content: rendered.text,
+            view_mode: match args.mode.clone().or(args.preferences.view_mode.clone()) {
+                _ if too_large => ViewMode::Source,
+                Some(mode) if has_renderer => mode,
+                None if has_renderer => ViewMode::Rendered,
+                _ => ViewMode::Source,
+            },
+            scroll_offset: 0,
+            cursor_line: 0,
+            view_height: 0,
+            view_width: 0,
+            file_path,
+            canonical_path: None,
+            metadata: None,
+            renderer,
+            tab_width: args.tab_width(),
+            pending_count: None,
+            content_area: Rect::default(),
+            show_help: false,
+            help_scroll: 0,
+            show_stats: false,
+            marks: HashMap::new(),
+            collapsed: BTreeSet::new(),
+            fold_map: Vec::new(),
+            pending_command: None,
+            status_message: too_large_message.or(render_error).filter(|_| interactive).map(|message| (message, None)),
+            deferred_render: too_large.then_some(DeferredRender::Waiting(options)),
+            toc: rendered.toc,
+            show_toc: false,
+            toc_selected: 0,
+            listing: None,
+            show_listing: false,
+            diff: None,
+            streamed: None,
+            footnotes: rendered.footnotes,
+            scroll_target: None,
+            split: args.split,
+            scrollbar: args.scrollbar,
+            scrollbar_style: args.scrollbar_style,
+            scrollbar_shown: args.scrollbar != ScrollbarMode::Never,
+            split_ratio: DEFAULT_SPLIT_RATIO,
+            highlighter,
+            styled_cache: None,
+            display_rows: None,
+            pending_bottom: false,
+            show_markers: args.show_markers,
+            ascii: args.ascii(),
+            wrap_markers: args.wrap_markers || args.preferences.wrap_markers.unwrap_or(false),
+            wrap_mode: args.wrap.or(args.preferences.wrap).unwrap_or(WrapMode::Word),
+            trailing_whitespace: args.trailing_whitespace,
+            numbering,
+            search_input: None,
+            search_query: String::new(),
+            search_matches: Vec::new(),
+            current_match: None,
+            scroll_margin: DEFAULT_SCROLL_MARGIN,
+            scroll_overlap: args.scroll_overlap,
+            selection_anchor: None,
+            export_input: None,
+            pipe_input: None,
+            images: rendered.images,
+            image_slots: Vec::new(),
+        }
+    }
+
+    /// Conservatively guess whether extensionless content is markdown.
+    /// Headings and fenced code are strong signals, while lists and links only
+    /// tip the balance, so plain text with a few dashes stays plain.
+    fn looks_like_markdown(content: &str) -> bool {
+        let mut score = 0;
+        let mut seen_list = false;
+        let mut seen_link = false;
+
+        for line in content.lines().take(MARKDOWN_SNIFF_LINES) {
+            let trimmed = line.trim_start();
+            let hashes = trimmed.chars().take_while(|&c| c == '#').count();
+            let is_heading = (1..=6).contains(&hashes) && trimmed[hashes..].starts_with(' ');
+            let is_fence = trimmed.starts_with("```") || trimmed.starts_with("~~~");
+
+            if is_heading || is_fence {
+                score += 2;
+            } else if !seen_list && Self::is_list_item(trimmed) {
+                seen_list = true;
+                score += 1;
+            }
+
+            if !seen_link && line.contains("](") && line.contains('[') {
+                seen_link = true;
+                score += 1;
+            }
+        }
+
+        score >= 3
+    }
+
+    fn is_list_item(line: &str) -> bool {
+        if line.starts_with("- ") || line.starts_with("* ") || line.starts_with("+ ") {
+            return true;
+        }
+        let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+        digits > 0 && line[digits..].starts_with(". ")
+    }
+
+    /// Resume from where this file was left off in a previous session, in the
+    /// view mode it was left in unless `--mode` asks for one
+    fn restore_position(&mut self, saved_state: &SavedState, args: &Args) {
+        let Some(position) = self
+            .canonical_path
+            .as_ref()
+            .filter(|_| self.diff.is_none())
+            .and_then(|path| saved_state.position(path))
+        else {
+            return;
+        };
+
+        if self.renderer.is_some() && self.deferred_render.is_none() && args.mode.is_none() {
+            self.view_mode = position.view_mode.clone();
+        }
+
+        // After a big edit the old offset likely points somewhere unrelated
+        let size_change = position.file_size.abs_diff(self.file_size);
+        if size_change * 100 > position.file_size * RESUME_MAX_SIZE_CHANGE_PERCENT {
+            return;
+        }
+
+        let max_offset = self.line_count().saturating_sub(1);
+        self.scroll_offset = position.scroll_offset.min(max_offset);
+        self.cursor_line = self.scroll_offset;
+    }
+
+    /// Move to `start`. A heading that isn't there is an error, listing those that are.
+    fn apply_start_position(&mut self, start: &StartPosition) -> Result<()> {
+        match start {
+            StartPosition::End => self.pending_bottom = true,
+            StartPosition::Line(line) => self.jump_to_line(*line),
+            StartPosition::Pattern(pattern) => {
+                self.search_query = pattern.clone();
+                self.refresh_search();
+                match self.search_matches.first() {
+                    Some(&position) => {
+                        self.current_match = Some(0);
+                        self.focus_line(position);
+                    }
+                    None => self.set_status("Pattern not found".to_string()),
+                }
+            }
+            StartPosition::Heading(slug) => {
+                if !self.go_to_heading(slug) {
+                    let slugs: Vec<&str> = self.toc.iter().map(|entry| entry.slug.as_str()).collect();
+                    return Err(match slugs.is_empty() {
+                        true => color_eyre::eyre::eyre!("No heading '{}': '{}' has no headings", slug, self.file_path),
+                        false => color_eyre::eyre::eyre!(
+                            "No heading '{}' in '{}'; its headings are: {}",
+                            slug,
+                            self.file_path,
+                            slugs.join(", ")
+                        ),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Go to the first heading whose slug matches `slug`, returning whether there is one
+    fn go_to_heading(&mut self, slug: &str) -> bool {
+        let slug = slugify(slug);
+        let Some((line, _)) = self.heading_positions().find(|(_, entry)| entry.slug == slug) else {
+            return false;
+        };
+        self.jump_to_line(line + 1);
+        true
+    }
+
+    /// Go to the end now that the view size is known, if that was asked for
+    fn apply_pending_bottom(&mut self) {
+        if std::mem::take(&mut self.pending_bottom) {
+            self.scroll_offset = self.bottom_offset();
+            self.cursor_line = self.line_count().saturating_sub(1);
+        }
+    }
+
+    fn save_position(&self, saved_state: &mut SavedState) {
+        if let Some(path) = &self.canonical_path {
+            let position = SavedPosition {
+                scroll_offset: self.scroll_offset,
+                view_mode: self.view_mode.clone(),
+                file_size: self.file_size,
+            };
+            saved_state.set_position(path.clone(), position);
+        }
+    }
+
+    fn toggle_view_mode(&mut self) {
+        if self.renderer.is_none() {
+            return; // Only toggle for files with a rendered view
+        }
+        if self.deferred_render.is_some() {
+            self.start_deferred_render(ViewMode::Rendered);
+            return;
+        }
+
+        let next_mode = match self.view_mode {
+            ViewMode::Rendered => ViewMode::Source,
+            ViewMode::Source => ViewMode::SideBySide,
+            ViewMode::SideBySide => ViewMode::Rendered,
+        };
+        self.set_view_mode(next_mode);
+    }
+
+    /// Switch straight to `mode`, which only files with a rendered view have a choice of
+    fn show_view_mode(&mut self, mode: ViewMode) {
+        if self.view_mode == mode {
+            return;
+        }
+        if self.renderer.is_none() {
+            self.set_status("Only source view is available for this file".to_string());
+        } else if self.deferred_render.is_some() {
+            self.start_deferred_render(mode);
+        } else {
+            self.set_view_mode(mode);
+        }
+    }
+
+    /// Render a document that was too large to render on load, on a background
+    /// thread so the pager keeps responding; `poll_deferred_render` picks up the
+    /// result and switches to `mode`, or to the last mode asked for while it runs
+    fn start_deferred_render(&mut self, mode: ViewMode) {
+        let options = match self.deferred_render.take() {
+            Some(DeferredRender::Waiting(options)) => options,
+            Some(DeferredRender::Running { started, receiver, .. }) => {
+                self.deferred_render = Some(DeferredRender::Running { started, receiver, mode });
+                return;
+            }
+            None => return,
+        };
+        let Some(renderer) = self.renderer.clone() else {
+            return;
+        };
+        let content = self.content.clone();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // The viewer may have quit in the meantime
+            let _ = sender.send(renderer.render(&content, &options));
+        });
+        self.deferred_render = Some(DeferredRender::Running { started: Instant::now(), receiver, mode });
+        self.poll_deferred_render();
+    }
+
+    /// Switch to the requested view once a deferred render is done, and until
+    /// then keep the footer saying how long it has been going
+    fn poll_deferred_render(&mut self) {
+        let Some(DeferredRender::Running { started, receiver, mode }) = &self.deferred_render else {
+            return;
+        };
+        let mode = mode.clone();
+        let elapsed = started.elapsed().as_secs_f64();
+        match receiver.try_recv() {
+            Ok(Ok(mut rendered)) => {
+                resolve_image_paths(&self.file_path, &mut rendered.images);
+                self.rendered_index = LineIndex::new(&rendered.text);
+                self.rendered_content = rendered.text;
+                self.toc = rendered.toc;
+                self.footnotes = rendered.footnotes;
+                self.images = rendered.images;
+                self.deferred_render = None;
+                self.set_view_mode(mode);
+                self.set_status(format!("Rendered in {:.1}s", elapsed));
+            }
+            Err(mpsc::TryRecvError::Empty) => {
+                let size = format_size(self.content.len() as u64);
+                self.set_lasting_status(format!("Rendering {}… {:.0}s", size, elapsed.floor()));
+            }
+            Ok(Err(e)) => {
+                self.deferred_render = None;
+                self.renderer = None;
+                self.set_lasting_status(format!("{}; showing source", e));
+            }
+            Err(mpsc::TryRecvError::Disconnected) => {
+                // The renderer panicked, so only source view is left
+                self.deferred_render = None;
+                self.renderer = None;
+                self.set_lasting_status("Rendering failed; showing source".to_string());
+            }
+        }
+    }
+
+    /// Switch view mode, keeping the scroll position at the same relative
+    /// place in the document since rendered and source lengths differ
+    fn set_view_mode(&mut self, mode: ViewMode) {
+        let old_max = self.line_count().saturating_sub(1);
+        let ratio = if old_max > 0 {
+            self.scroll_offset as f64 / old_max as f64
+        } else {
+            0.0
+        };
+
+        self.view_mode = mode;
+        self.invalidate_styled_cache();
+        self.refresh_search();
+
+        let new_max = self.line_count().saturating_sub(1);
+        self.scroll_offset = ((ratio * new_max as f64).round() as usize).min(new_max);
+        self.cursor_line = self.scroll_offset;
+    }
+
+    /// Scroll the view, taking the cursor along so it keeps its place on screen
+    fn scroll_up(&mut self, lines: usize) {
+        let before = self.scroll_offset;
+        if self.scroll_offset > lines {
+            self.scroll_offset -= lines;
+        } else {
+            self.scroll_offset = 0;
+        }
+        self.cursor_line = self.cursor_line.saturating_sub(before - self.scroll_offset);
+    }
+
+    /// Scroll down, stopping once the last line reaches the bottom of the view
+    fn scroll_down(&mut self, lines: usize) {
+        let before = self.scroll_offset;
+        self.scroll_offset = self.scroll_offset.saturating_add(lines).min(self.bottom_offset()).max(before);
+        let last = self.line_count().saturating_sub(1);
+        self.cursor_line = (self.cursor_line + (self.scroll_offset - before)).min(last);
+    }
+
+    /// Lines moved by Page Up/Page Down: the pane height less the overlap,
+    /// but always at least one
+    fn page_height(&self) -> usize {
+        self.view_height.saturating_sub(self.scroll_overlap).max(1)
+    }
+
+    /// Lines moved by `d`/`u`: half the pane height, but always at least one
+    fn half_page_height(&self) -> usize {
+        (self.view_height / 2).max(1)
+    }
+
+    /// Move the view without the cursor, which only moves to stay on screen.
+    /// Like `scroll_down`, it stops once the last line reaches the bottom.
+    fn shift_view(&mut self, lines: usize, down: bool) {
+        self.scroll_offset = match down {
+            true => self.scroll_offset.saturating_add(lines).min(self.bottom_offset()).max(self.scroll_offset),
+            false => self.scroll_offset.saturating_sub(lines),
+        };
+    }
+
+    /// Measure how many screen rows each line of the view wraps to at `width`,
+    /// or at `source_width` in the source pane of side-by-side view, unless
+    /// that is already known
+    fn measure_rows(&mut self, theme: &Theme, width: usize, source_width: usize) {
+        let total_lines = self.line_count();
+        let current = self.display_rows.as_ref().is_some_and(|rows| {
+            rows.width == width
+                && rows.source_width == source_width
+                && rows.view_mode == self.view_mode
+                && rows.starts.len() == total_lines + 1
+        });
+        if current {
+            return;
+        }
+
+        let heights: Vec<usize> = match self.view_mode {
+            _ if self.diff.is_some() => (0..total_lines)
+                .map(|index| {
+                    let (old, new) = self.diff_lines(index);
+                    let old_rows = old.map_or(1, |line| wrapped_rows(&Line::from(line), width));
+                    old_rows.max(new.map_or(1, |line| wrapped_rows(&Line::from(line), source_width)))
+                })
+                .collect(),
+            ViewMode::Rendered => self
+                .styled_window(theme, width, 0, total_lines)
+                .iter()
+                .map(|line| self.rendered_rows(line, width))
+                .collect(),
+            ViewMode::Source => (0..total_lines).map(|index| self.source_rows(index, width)).collect(),
+            ViewMode::SideBySide => {
+                let rendered = self.styled_rendered_lines(theme, width, 0, total_lines).to_vec();
+                (0..total_lines)
+                    .map(|index| {
+                        let rendered_rows = rendered.get(index).map_or(1, |line| self.rendered_rows(line, width));
+                        rendered_rows.max(self.source_rows(index, source_width))
+                    })
+                    .collect()
+            }
+        };
+        let mut starts = Vec::with_capacity(total_lines + 1);
+        let mut row = 0;
+        starts.push(row);
+        for height in heights {
+            row += height;
+            starts.push(row);
+        }
+        self.display_rows = Some(DisplayRows { width, source_width, view_mode: self.view_mode.clone(), starts });
+    }
+
+    /// Screen rows source line `index` wraps to at `width`; past the end of the
+    /// source, as side-by-side view can go, a line is a blank row
+    fn source_rows(&self, index: usize, width: usize) -> usize {
+        if index >= self.source_index.len() {
+            return 1;
+        }
+        wrapped_rows(&Line::from(self.source_line(index)), width)
+    }
+
+    /// Row starts measured for the view as it is now, if any
+    fn row_starts(&self) -> Option<&[usize]> {
+        self.display_rows
+            .as_ref()
+            .filter(|rows| rows.view_mode == self.view_mode && rows.starts.len() == self.line_count() + 1)
+            .map(|rows| &rows.starts[..])
+    }
+
+    /// Screen rows taken by view lines `start..end`, counting one per line until
+    /// the lines have been measured
+    fn rows_between(&self, start: usize, end: usize) -> usize {
+        match self.row_starts() {
+            Some(starts) => {
+                let end = end.min(starts.len() - 1);
+                starts[end] - starts[start.min(end)]
+            }
+            None => end.saturating_sub(start),
+        }
+    }
+
+    /// Offset that shows the lines before `end` with the last of them at the
+    /// bottom of the view
+    fn offset_ending_at(&self, end: usize) -> usize {
+        let height = self.view_height.max(1);
+        match self.row_starts() {
+            Some(starts) => {
+                let end = end.min(starts.len() - 1);
+                let top_row = starts[end].saturating_sub(height);
+                // A line taller than the view still starts at the top
+                starts[..end].partition_point(|&row| row < top_row).min(end.saturating_sub(1))
+            }
+            None => end.saturating_sub(height),
+        }
+    }
+
+    /// Offset that puts the last line at the bottom of the view
+    fn bottom_offset(&self) -> usize {
+        self.offset_ending_at(self.line_count())
+    }
+
+    /// Last line that fits in the view whole, or the first line if even that doesn't
+    fn last_visible_line(&self) -> usize {
+        let height = self.view_height.max(1);
+        match self.row_starts() {
+            Some(starts) => {
+                let bottom_row = starts[self.scroll_offset.min(starts.len() - 1)] + height;
+                let fitting = starts[1..].partition_point(|&row| row <= bottom_row);
+                fitting.saturating_sub(1).max(self.scroll_offset)
+            }
+            None => self.scroll_offset + height - 1,
+        }
+    }
+
+    /// Move the cursor, scrolling once it would pass the scroll margin
+    fn cursor_up(&mut self, lines: usize) {
+        self.cursor_line = self.cursor_line.saturating_sub(lines);
+        self.scroll_to_cursor();
+    }
+
+    fn cursor_down(&mut self, lines: usize) {
+        let last = self.line_count().saturating_sub(1);
+        self.cursor_line = self.cursor_line.saturating_add(lines).min(last);
+        self.scroll_to_cursor();
+    }
+
+    /// Scroll just far enough to keep the scroll margin around the cursor
+    fn scroll_to_cursor(&mut self) {
+        let height = self.view_height.max(1);
+        let margin = self.scroll_margin.min((height - 1) / 2);
+        let end = (self.cursor_line + margin + 1).min(self.line_count());
+        if self.cursor_line < self.scroll_offset + margin {
+            self.scroll_offset = self.cursor_line.saturating_sub(margin);
+        } else if self.rows_between(self.scroll_offset, end) > height {
+            self.scroll_offset = self.offset_ending_at(end);
+        }
+    }
+
+    fn set_status(&mut self, message: String) {
+        self.status_message = Some((message, Some(Instant::now() + STATUS_MESSAGE_DURATION)));
+    }
+
+    /// Show a message that stays in the footer until the next key, for errors
+    /// that turn up without a key being pressed
+    fn set_lasting_status(&mut self, message: String) {
+        self.status_message = Some((message, None));
+    }
+
+    /// Clear the footer message once it has been up long enough
+    fn expire_status(&mut self) {
+        if self.status_message.as_ref().is_some_and(|(_, expiry)| expiry.is_some_and(|expiry| Instant::now() >= expiry)) {
+            self.status_message = None;
+        }
+    }
+
+    /// Keep the offset in bounds and the cursor in view once the viewport
+    /// has been resized and `view_height` reflects the new size
+    fn fit_to_view(&mut self) {
+        self.scroll_offset = self.scroll_offset.min(self.bottom_offset());
+        self.scroll_to_cursor();
+        self.clamp_cursor();
+    }
+
+    /// Bring the cursor back into view after the view moved without it
+    fn clamp_cursor(&mut self) {
+        let last = self.line_count().saturating_sub(1);
+        self.cursor_line = self.cursor_line.clamp(self.scroll_offset, self.last_visible_line()).min(last);
+    }
+
+    /// Rewind to `from` and animate towards the current offset
+    fn start_scroll_animation(&mut self, from: usize) {
+        if self.scroll_offset != from {
+            self.scroll_target = Some(self.scroll_offset);
+            self.scroll_offset = from;
+        }
+    }
+
+    /// Move one frame closer to the scroll target, easing out as it approaches
+    fn step_scroll_animation(&mut self) {
+        let Some(target) = self.scroll_target else {
+            return;
+        };
+        let step = self.scroll_offset.abs_diff(target).div_ceil(SMOOTH_SCROLL_DIVISOR).max(1);
+        self.scroll_offset = if target > self.scroll_offset {
+            self.scroll_offset + step
+        } else {
+            self.scroll_offset - step
+        };
+        if self.scroll_offset == target {
+            self.scroll_target = None;
+        }
+    }
+
+    /// Skip the rest of a smooth scroll so new input acts on the final position
+    fn finish_scroll_animation(&mut self) {
+        if let Some(target) = self.scroll_target.take() {
+            self.scroll_offset = target;
+        }
+    }
+
+    fn jump_to_line(&mut self, line: usize) {
+        self.focus_line(line.saturating_sub(1).min(self.line_count().saturating_sub(1)));
+    }
+
+    /// Line (0-based) `percent` of the way through the current view
+    fn percent_line(&self, percent: usize) -> usize {
+        let last = self.line_count().saturating_sub(1);
+        last.saturating_mul(percent.min(100)) / 100
+    }
+
+    fn jump_to_percent(&mut self, percent: usize) {
+        self.jump_to_line(self.percent_line(percent) + 1);
+    }
+
+    fn push_count_digit(&mut self, digit: u32) {
+        let count = self.pending_count.unwrap_or(0);
+        self.pending_count = Some(count.saturating_mul(10).saturating_add(digit as usize));
+    }
+
+    fn handle_mouse(&mut self, mouse: MouseEvent) {
+        match mouse.kind {
+            MouseEventKind::ScrollUp => self.scroll_up(MOUSE_SCROLL_LINES),
+            MouseEventKind::ScrollDown => self.scroll_down(MOUSE_SCROLL_LINES),
+            MouseEventKind::Down(MouseButton::Left) | MouseEventKind::Drag(MouseButton::Left) => {
+                self.scroll_to_scrollbar_position(mouse.column, mouse.row);
+            }
+            _ => {}
+        }
+    }
+
+    fn scroll_to_scrollbar_position(&mut self, column: u16, row: u16) {
+        let (_, area) = self.scrollbar_columns(self.content_area);
+        if area.width == 0 || column != area.x {
+            return;
+        }
+
+        let track = self.scrollbar_track(area);
+        let (track_start, track_len) = (track.y, track.height);
+        if track_len == 0 || row < track_start || row >= track_start + track_len {
+            return;
+        }
+
+        // Clicking a heading or match mark goes straight to it
+        if let Some(line) = self.minimap_mark(row - track_start, track_len) {
+            self.focus_line(line);
+            return;
+        }
+
+        let max_offset = self.bottom_offset();
+        let position = (row - track_start) as usize;
+        self.scroll_offset = if track_len > 1 {
+            position * max_offset / (track_len as usize - 1)
+        } else {
+            0
+        };
+    }
+
+    fn handle_help_action(&mut self, action: Action) {
+        let max_scroll = HELP_TEXT.len().saturating_sub(1);
+        match action {
+            Action::Quit | Action::Help => self.show_help = false,
+            Action::ScrollUp => self.help_scroll = self.help_scroll.saturating_sub(1),
+            Action::ScrollDown => self.help_scroll = (self.help_scroll + 1).min(max_scroll),
+            Action::PageUp => self.help_scroll = self.help_scroll.saturating_sub(10),
+            Action::PageDown => self.help_scroll = (self.help_scroll + 10).min(max_scroll),
+            Action::Top => self.help_scroll = 0,
+            Action::Bottom => self.help_scroll = max_scroll,
+            _ => {}
+        }
+    }
+
+    fn complete_pending_command(&mut self, command: PendingCommand, key: char) {
+        match command {
+            PendingCommand::SetMark | PendingCommand::JumpToMark if !key.is_ascii_alphabetic() => {
+                self.set_status(format!("Invalid mark '{}', use a letter", key));
+            }
+            PendingCommand::SetMark => {
+                self.marks.insert(key, self.cursor_line);
+                self.set_status(format!("Mark '{}' set", key));
+            }
+            PendingCommand::JumpToMark => match self.marks.get(&key) {
+                Some(&line) => {
+                    let last = self.line_count().saturating_sub(1);
+                    self.focus_line(line.min(last));
+                    self.set_status(format!("Jumped to mark '{}'", key));
+                }
+                None => self.set_status(format!("Mark '{}' not set", key)),
+            },
+            PendingCommand::Fold => match key {
+                'a' => self.toggle_fold(),
+                _ => self.set_status(format!("Unknown fold command 'z{}'", key)),
+            },
+            PendingCommand::NextHeading if key == ']' => self.jump_to_heading(true),
+            PendingCommand::PreviousHeading if key == '[' => self.jump_to_heading(false),
+            PendingCommand::NextHeading | PendingCommand::PreviousHeading | PendingCommand::Quit => {}
+        }
+    }
+
+    /// Jump from the first footnote reference at or below the cursor to its
+    /// definition, or from a definition on the cursor line back to its reference
+    fn follow_footnote(&mut self) {
+        if self.view_mode == ViewMode::Source {
+            self.set_status("Footnotes can only be followed in rendered view".to_string());
+            return;
+        }
+
+        let cursor_line = match self.view_mode {
+            ViewMode::Rendered => self.rendered_line_at(self.cursor_line),
+            _ => self.cursor_line,
+        };
+        let target = match self.footnotes.iter().find(|link| link.definition_line == cursor_line) {
+            Some(link) => link.reference_line,
+            None => match self.footnotes.iter().find(|link| link.reference_line >= cursor_line) {
+                Some(link) => link.definition_line,
+                None => {
+                    self.set_status("No footnote reference below".to_string());
+                    return;
+                }
+            },
+        };
+
+        let position = match self.view_mode {
+            ViewMode::Rendered => self.visible_rendered_line(target),
+            _ => target,
+        };
+        self.focus_line(position);
+    }
+
+    /// Orientation of side-by-side view for a content area of the given width
+    fn split_orientation(&self, width: u16) -> SplitOrientation {
+        self.split.unwrap_or(if width < NARROW_SPLIT_WIDTH {
+            SplitOrientation::Horizontal
+        } else {
+            SplitOrientation::Vertical
+        })
+    }
+
+    fn toggle_markers(&mut self) {
+        self.show_markers = !self.show_markers;
+        self.invalidate_styled_cache();
+        self.set_status(if self.show_markers {
+            "Showing markdown markers".to_string()
+        } else {
+            "Hiding markdown markers".to_string()
+        });
+    }
+
+    fn toggle_wrap_markers(&mut self) {
+        self.wrap_markers = !self.wrap_markers;
+        self.set_status(if self.wrap_markers {
+            "Marking wrapped lines".to_string()
+        } else {
+            "Not marking wrapped lines".to_string()
+        });
+    }
+
+    fn toggle_trailing_whitespace(&mut self) {
+        self.trailing_whitespace = !self.trailing_whitespace;
+        self.set_status(if self.trailing_whitespace {
+            "Showing trailing whitespace".to_string()
+        } else {
+            "Hiding trailing whitespace".to_string()
+        });
+    }
+
+    /// Columns left of the text in single view, for line numbers and wrap markers
+    fn gutter_width(&self) -> u16 {
+        self.number_width() + if self.wrap_markers { 2 } else { 0 }
+    }
+
+    /// Columns taken by line numbers and the space after them, which only source view has
+    fn number_width(&self) -> u16 {
+        match self.numbering {
+            Numbering::Off => 0,
+            _ if self.view_mode != ViewMode::Source => 0,
+            _ => self.source_index.len().max(1).to_string().len() as u16 + 1,
+        }
+    }
+
+    /// The number shown beside source line `index`, if it gets one
+    fn line_number(&self, index: usize) -> Option<usize> {
+        match &self.numbering {
+            Numbering::Off => None,
+            Numbering::All => Some(index + 1),
+            Numbering::NonBlank(numbers) => numbers.get(index).copied().flatten(),
+        }
+    }
+
+    /// Screen rows a rendered line takes when wrapped to `width`
+    fn rendered_rows(&self, line: &Line<'static>, width: usize) -> usize {
+        match self.wrap_mode {
+            WrapMode::Word if line.width() > width => wrap_words(line, width, Symbols::get(self.ascii)).len(),
+            WrapMode::Word | WrapMode::Plain => wrapped_rows(line, width),
+        }
+    }
+
+    /// Rendered lines split into the rows they wrap to at `width`. Plain
+    /// wrapping is left to the paragraph drawing them.
+    fn wrap_rendered(&self, lines: Vec<Line<'static>>, width: usize) -> Vec<Line<'static>> {
+        match self.wrap_mode {
+            WrapMode::Word => lines
+                .iter()
+                .flat_map(|line| wrap_words(line, width, Symbols::get(self.ascii)))
+                .collect(),
+            WrapMode::Plain => lines,
+        }
+    }
+
+    fn toggle_split(&mut self) {
+        let split = match self.split_orientation(self.content_area.width) {
+            SplitOrientation::Horizontal => SplitOrientation::Vertical,
+            SplitOrientation::Vertical => SplitOrientation::Horizontal,
+        };
+        self.split = Some(split);
+        self.set_status(match split {
+            SplitOrientation::Horizontal => "Side-by-side panes stacked".to_string(),
+            SplitOrientation::Vertical => "Side-by-side panes in columns".to_string(),
+        });
+    }
+
+    /// Move the side-by-side divider, giving the rendered pane `delta` percent more room
+    fn resize_split(&mut self, delta: i16) {
+        self.split_ratio = self
+            .split_ratio
+            .saturating_add_signed(delta)
+            .clamp(MIN_SPLIT_RATIO, MAX_SPLIT_RATIO);
+        self.set_status(format!("Split {}/{}", self.split_ratio, 100 - self.split_ratio));
+    }
+
+    /// Collapse or expand the section containing the cursor line
+    fn toggle_fold(&mut self) {
+        if self.view_mode != ViewMode::Rendered {
+            self.set_status("Folding is only available in rendered view".to_string());
+            return;
+        }
+
+        let cursor_line = self.rendered_line_at(self.cursor_line);
+        let Some(heading) = self.toc.iter().rev().find(|entry| entry.rendered_line <= cursor_line) else {
+            self.set_status("No heading to fold".to_string());
+            return;
+        };
+
+        let heading_line = heading.rendered_line;
+        let message = if self.collapsed.remove(&heading_line) {
+            format!("Expanded '{}'", heading.text)
+        } else {
+            self.collapsed.insert(heading_line);
+            format!("Collapsed '{}'", heading.text)
+        };
+        self.set_status(message);
+        self.fold_map = self.build_fold_map();
+        self.display_rows = None;
+        self.refresh_search();
+        self.focus_line(self.visible_rendered_line(heading_line));
+    }
+
+    /// Rendered line where the section started by the heading on `heading_line`
+    /// ends: the next heading of the same or a higher level
+    fn section_end(&self, heading_line: usize, total_lines: usize) -> usize {
+        let Some(index) = self.toc.iter().position(|entry| entry.rendered_line == heading_line) else {
+            return heading_line + 1;
+        };
+        let level = self.toc[index].level;
+        self.toc[index + 1..]
+            .iter()
+            .find(|entry| entry.level <= level)
+            .map_or(total_lines, |entry| entry.rendered_line)
+    }
+
+    /// For each line shown in rendered view, the rendered line it comes from.
+    /// Collapsed sections contribute only their heading line. Empty when
+    /// nothing is collapsed, since every line then maps to itself.
+    fn build_fold_map(&self) -> Vec<usize> {
+        if self.collapsed.is_empty() {
+            return Vec::new();
+        }
+        let total_lines = self.rendered_index.len();
+        let mut fold_map = Vec::with_capacity(total_lines);
+        let mut line = 0;
+        while line < total_lines {
+            fold_map.push(line);
+            line = if self.collapsed.contains(&line) {
+                self.section_end(line, total_lines)
+            } else {
+                line + 1
+            };
+        }
+        fold_map
+    }
+
+    /// Number of lines in rendered view, counting a collapsed section as one
+    fn rendered_view_len(&self) -> usize {
+        if self.collapsed.is_empty() {
+            self.rendered_index.len()
+        } else {
+            self.fold_map.len()
+        }
+    }
+
+    /// Rendered line shown at a position in rendered view
+    fn rendered_line_at(&self, position: usize) -> usize {
+        if self.collapsed.is_empty() {
+            position
+        } else {
+            self.fold_map.get(position).copied().unwrap_or(0)
+        }
+    }
+
+    /// Position in rendered view of a rendered line, or of the fold hiding it
+    fn visible_rendered_line(&self, line: usize) -> usize {
+        if self.collapsed.is_empty() {
+            return line;
+        }
+        self.fold_map
+            .partition_point(|&shown| shown <= line)
+            .saturating_sub(1)
+    }
+
+    /// Styled rendered lines `start..end`, ignoring folds. Lines are styled once
+    /// per pane width and reused by every following frame.
+    fn styled_rendered_lines(&mut self, theme: &Theme, width: usize, start: usize, end: usize) -> &[Line<'static>] {
+        if self.styled_cache.as_ref().is_none_or(|cache| cache.width != width) {
+            let lines = (0..self.rendered_index.len())
+                .map(|index| {
+                    let line = self.rendered_index.line(&self.rendered_content, index);
+                    into_owned_line(style_rendered_line(line, theme, width, self.show_markers, Symbols::get(self.ascii)))
+                })
+                .collect();
+            self.styled_cache = Some(StyledCache { width, lines });
+        }
+        let lines = self.styled_cache.as_ref().map_or(&[][..], |cache| &cache.lines[..]);
+        let end = end.min(lines.len());
+        &lines[start.min(end)..end]
+    }
+
+    /// Styled lines `start..end` of rendered view, with collapsed sections
+    /// replaced by a placeholder
+    fn styled_window(&mut self, theme: &Theme, width: usize, start: usize, end: usize) -> Vec<Line<'static>> {
+        if self.collapsed.is_empty() {
+            return self.styled_rendered_lines(theme, width, start, end).to_vec();
+        }
+
+        let end = end.min(self.rendered_view_len());
+        (start.min(end)..end)
+            .map(|position| {
+                let line = self.rendered_line_at(position);
+                let entry = self.toc.iter().find(|entry| entry.rendered_line == line);
+                match entry {
+                    Some(entry) if self.collapsed.contains(&line) => {
+                        let next_line = self.fold_map.get(position + 1).copied().unwrap_or(self.rendered_index.len());
+                        let placeholder = format!(
+                            "{} ▸ {} ({} lines hidden)",
+                            "#".repeat(entry.level as usize),
+                            entry.text,
+                            next_line - line - 1
+                        );
+                        into_owned_line(style_rendered_line(&placeholder, theme, width, self.show_markers, Symbols::get(self.ascii)))
+                    }
+                    _ => self.styled_rendered_lines(theme, width, line, line + 1)[0].clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Text of the line at a position in the current view, as it is searched.
+    /// Past the end, as in an empty document, the line is empty.
+    fn view_line(&self, position: usize) -> Cow<'_, str> {
+        if position >= self.line_count() {
+            return Cow::Borrowed("");
+        }
+        if let Some(diff) = &self.diff {
+            let row = diff.rows[position];
+            return match (row.new_line(), row.old_line()) {
+                (Some(new), _) => self.raw_source_line(new),
+                (None, Some(old)) => Cow::Borrowed(diff.old_index.line(&diff.old_content, old)),
+                (None, None) => Cow::Borrowed(""),
+            };
+        }
+        match self.view_mode {
+            ViewMode::Rendered => {
+                Cow::Borrowed(self.rendered_index.line(&self.rendered_content, self.rendered_line_at(position)))
+            }
+            ViewMode::Source => self.raw_source_line(position),
+            ViewMode::SideBySide if position < self.rendered_index.len() => {
+                Cow::Borrowed(self.rendered_index.line(&self.rendered_content, position))
+            }
+            ViewMode::SideBySide => Cow::Borrowed(""),
+        }
+    }
+
+    /// Positions of the lines in the current view containing `query`.
+    /// Matching ignores case unless the query has an uppercase letter.
+    fn find_matches(&self, query: &str) -> Vec<usize> {
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let ignore_case = !query.chars().any(char::is_uppercase);
+        let needle = if ignore_case { query.to_lowercase() } else { query.to_string() };
+        (0..self.line_count())
+            .filter(|&position| {
+                let line = self.view_line(position);
+                if ignore_case {
+                    line.to_lowercase().contains(&needle)
+                } else {
+                    line.contains(&needle)
+                }
+            })
+            .collect()
+    }
+
+    /// Recompute matches for the active search after the view's lines change
+    fn refresh_search(&mut self) {
+        self.search_matches = self.find_matches(&self.search_query);
+        self.current_match = None;
+    }
+
+    fn start_search(&mut self) {
+        self.search_input = Some(SearchInput {
+            query: String::new(),
+            origin: self.scroll_offset,
+            origin_cursor: self.cursor_line,
+            stale: false,
+        });
+    }
+
+    /// Apply an edit to the search being typed. Small documents are searched
+    /// right away; large ones wait until typing pauses.
+    fn edit_search(&mut self, edit: impl FnOnce(&mut String)) {
+        let Some(input) = self.search_input.as_mut() else {
+            return;
+        };
+        edit(&mut input.query);
+        input.stale = true;
+        if self.line_count() <= LIVE_SEARCH_MAX_LINES {
+            self.run_live_search();
+        }
+    }
+
+    /// Search for the query being typed and show the first match at or after
+    /// where the search started, or go back there if nothing matches
+    fn run_live_search(&mut self) {
+        let Some(input) = self.search_input.as_mut() else {
+            return;
+        };
+        input.stale = false;
+        let (query, origin, origin_cursor) = (input.query.clone(), input.origin, input.origin_cursor);
+
+        self.search_matches = self.find_matches(&query);
+        self.search_query = query;
+        self.current_match = match self.search_matches.iter().position(|&position| position >= origin_cursor) {
+            Some(index) => Some(index),
+            None if self.search_matches.is_empty() => None,
+            None => Some(0),
+        };
+        match self.current_match {
+            Some(index) => self.focus_line(self.search_matches[index]),
+            None => {
+                self.scroll_offset = origin;
+                self.cursor_line = origin_cursor;
+            }
+        }
+    }
+
+    /// Put the cursor on `position` and scroll it into view with the scroll margin above it
+    fn focus_line(&mut self, position: usize) {
+        self.cursor_line = position;
+        self.scroll_offset = position.saturating_sub(self.scroll_margin);
+    }
+
+    fn confirm_search(&mut self) {
+        if self.search_input.as_ref().is_some_and(|input| input.stale) {
+            self.run_live_search();
+        }
+        self.search_input = None;
+        if !self.search_query.is_empty() && self.search_matches.is_empty() {
+            self.set_status("Pattern not found".to_string());
+        }
+    }
+
+    fn cancel_search(&mut self) {
+        if let Some(input) = self.search_input.take() {
+            self.scroll_offset = input.origin;
+            self.cursor_line = input.origin_cursor;
+        }
+        self.search_query.clear();
+        self.search_matches.clear();
+        self.current_match = None;
+    }
+
+    /// Move to the next match after the top of the view, or the previous one
+    /// before it, wrapping around the document
+    fn jump_to_match(&mut self, forward: bool) {
+        if self.search_matches.is_empty() {
+            if !self.search_query.is_empty() {
+                self.set_status("Pattern not found".to_string());
+            }
+            return;
+        }
+        let cursor = self.cursor_line;
+        let last = self.search_matches.len() - 1;
+        let next = if forward {
+            self.search_matches.iter().position(|&position| position > cursor)
+        } else {
+            self.search_matches.iter().rposition(|&position| position < cursor)
+        };
+        let index = next.unwrap_or_else(|| {
+            self.set_status(if forward {
+                "Search hit bottom, continuing at top".to_string()
+            } else {
+                "Search hit top, continuing at bottom".to_string()
+            });
+            if forward { 0 } else { last }
+        });
+        self.current_match = Some(index);
+        self.focus_line(self.search_matches[index]);
+    }
+
+    /// Destinations of the links on the cursor line, for the footer
+    fn link_summary(&self) -> Option<String> {
+        if self.view_mode == ViewMode::Source {
+            return None;
+        }
+        let line = self.view_line(self.cursor_line);
+        let targets = link_targets(&line);
+        match targets.as_slice() {
+            [] => None,
+            [target] => Some(format!("Link: {}", target)),
+            targets => Some(format!("Links: {}", targets.join(", "))),
+        }
+    }
+
+    /// Footer summary of the active search, like `match 3 of 17`
+    fn match_summary(&self) -> Option<String> {
+        if self.search_matches.is_empty() {
+            return None;
+        }
+        Some(match self.current_match {
+            Some(index) => format!("match {} of {}", index + 1, self.search_matches.len()),
+            None => format!("{} matches", self.search_matches.len()),
+        })
+    }
+
+    fn toggle_selection(&mut self) {
+        self.selection_anchor = match self.selection_anchor {
+            Some(_) => None,
+            None => Some(self.cursor_line),
+        };
+    }
+
+    /// First and last view positions of the selection, inclusive
+    fn selected_range(&self) -> Option<(usize, usize)> {
+        self.selection_anchor
+            .map(|anchor| (anchor.min(self.cursor_line), anchor.max(self.cursor_line)))
+    }
+
+    /// Text of view lines `start..end` as it reads on screen: rendered lines
+    /// without markdown markers, source lines exactly as written
+    fn plain_window(&mut self, theme: &Theme, start: usize, end: usize) -> Vec<String> {
+        let (area, _) = self.scrollbar_columns(self.content_area);
+        let width = area.width.saturating_sub(2 + self.gutter_width()) as usize;
+        if self.diff.is_some() {
+            let end = end.min(self.line_count());
+            return (start.min(end)..end).map(|position| self.view_line(position).into_owned()).collect();
+        }
+        let lines = match self.view_mode {
+            ViewMode::Source => {
+                let end = end.min(self.source_index.len());
+                return (start.min(end)..end).map(|index| self.raw_source_line(index).into_owned()).collect();
+            }
+            ViewMode::Rendered => self.styled_window(theme, width, start, end),
+            ViewMode::SideBySide => self.styled_rendered_lines(theme, width, start, end).to_vec(),
+        };
+        lines
+            .iter()
+            .map(|line| line.spans.iter().map(|span| span.content.as_ref()).collect())
+            .collect()
+    }
+
+    /// Copy the selected lines, or the visible ones without a selection, to the clipboard
+    fn copy_to_clipboard(&mut self, theme: &Theme) {
+        let (start, end) = match self.selected_range() {
+            Some((first, last)) => (first, last + 1),
+            None => {
+                let visible_lines = self.content_area.height.saturating_sub(2) as usize;
+                (self.scroll_offset, self.scroll_offset + visible_lines)
+            }
+        };
+        let lines = self.plain_window(theme, start, end);
+        let count = lines.len();
+
+        let copied = arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(lines.join("\n")));
+        self.set_status(match copied {
+            Ok(()) => format!("Copied {} line{} to the clipboard", count, if count == 1 { "" } else { "s" }),
+            Err(e) => format!("Failed to copy: {}", e),
+        });
+        self.selection_anchor = None;
+    }
+
+    /// Content written by an export: HTML for `.html` paths, otherwise the
+    /// same text as plain output
+    fn export_text(&self, path: &str) -> String {
+        if !path.ends_with(".html") {
+            return self.plain_text();
+        }
+        match &self.renderer {
+            Some(renderer) => renderer.to_html(&self.content),
+            None => format!("<pre>{}</pre>\n", escape_html(&self.content)),
+        }
+    }
+
+    fn export(&self, path: &str) -> Result<()> {
+        let written = match &self.streamed {
+            Some(streamed) => fs::File::create(path).and_then(|mut file| streamed.copy_to(&mut file, path.ends_with(".html"))),
+            None => fs::write(path, self.export_text(path)),
+        };
+        written.map_err(|e| color_eyre::eyre::eyre!("Failed to export to '{}': {}", path, e))
+    }
+
+    /// Export to the file name typed in the footer and report how it went
+    fn confirm_export(&mut self) {
+        let Some(path) = self.export_input.take().filter(|path| !path.is_empty()) else {
+            return;
+        };
+        self.set_status(match self.export(&path) {
+            Ok(()) => format!("Exported to {}", path),
+            Err(e) => e.to_string(),
+        });
+    }
+
+    /// Text sent to a piped command: the selected lines as shown, or the
+    /// whole document source, which a streamed file sends straight from disk
+    fn pipe_text(&mut self, theme: &Theme) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match (self.selected_range(), &self.streamed) {
+            (Some((first, last)), _) => Box::new(io::Cursor::new(self.plain_window(theme, first, last + 1).join("\n") + "\n")),
+            (None, Some(streamed)) => Box::new(fs::File::open(&streamed.path)?),
+            (None, None) => Box::new(io::Cursor::new(self.content.clone())),
+        })
+    }
+
+    fn is_search_match(&self, position: usize) -> bool {
+        self.search_matches.binary_search(&position).is_ok()
+    }
+
+    /// Drop the styled lines so the next frame restyles them
+    fn invalidate_styled_cache(&mut self) {
+        self.styled_cache = None;
+        self.display_rows = None;
+    }
+
+    fn toggle_toc(&mut self) {
+        if self.toc.is_empty() {
+            return;
+        }
+        self.show_toc = !self.show_toc;
+    }
+
+    /// Show the directory listing again, or hide it if a file was opened from it
+    fn toggle_listing(&mut self) {
+        match &self.listing {
+            Some(listing) if self.file_path != listing.directory => self.show_listing = !self.show_listing,
+            _ => {}
+        }
+    }
+
+    fn jump_to_toc_entry(&mut self) {
+        self.focus_heading(self.toc_selected);
+    }
+
+    /// Go to the heading at `index` in the table of contents
+    fn focus_heading(&mut self, index: usize) {
+        let Some(entry) = self.toc.get(index) else {
+            return;
+        };
+        let line = match self.view_mode {
+            ViewMode::Source => entry.source_line,
+            ViewMode::Rendered => self.visible_rendered_line(entry.rendered_line),
+            ViewMode::SideBySide => entry.rendered_line,
+        };
+        self.jump_to_line(line + 1);
+    }
+
+    /// Headings with their positions in the current view
+    fn heading_positions(&self) -> impl DoubleEndedIterator<Item = (usize, &TocEntry)> {
+        self.toc.iter().map(|entry| {
+            let line = match self.view_mode {
+                ViewMode::Source => entry.source_line,
+                ViewMode::Rendered => self.visible_rendered_line(entry.rendered_line),
+                ViewMode::SideBySide => entry.rendered_line,
+            };
+            (line, entry)
+        })
+    }
+
+    /// Whether the whole view fits in the pane at these widths, so `auto` can
+    /// leave out the scrollbar. Documents with more lines than the pane has
+    /// rows never fit, so only short ones are measured.
+    fn fits_in_view(&mut self, theme: &Theme, width: usize, source_width: usize) -> bool {
+        if self.line_count() > self.view_height {
+            return false;
+        }
+        self.measure_rows(theme, width, source_width);
+        self.bottom_offset() == 0
+    }
+
+    /// `area` split into the text and the scrollbar strip beside it, which is
+    /// empty while the scrollbar is hidden
+    fn scrollbar_columns(&self, area: Rect) -> (Rect, Rect) {
+        if self.scrollbar_shown {
+            split_scrollbar(area)
+        } else {
+            (area, Rect { x: area.right(), width: 0, ..area })
+        }
+    }
+
+    /// The part of scrollbar `strip` the track takes, between the arrows if it has them
+    fn scrollbar_track(&self, strip: Rect) -> Rect {
+        match self.scrollbar_style {
+            ScrollbarStyle::Arrows => Rect { y: strip.y + 1, height: strip.height.saturating_sub(2), ..strip },
+            ScrollbarStyle::Plain => strip,
+        }
+    }
+
+    /// Row of a scrollbar track `track_len` rows long that stands for view line `line`
+    fn track_row(&self, line: usize, track_len: u16) -> u16 {
+        let total = self.rows_between(0, self.line_count()).max(1);
+        let row = self.rows_between(0, line) * track_len as usize / total;
+        row.min(track_len.saturating_sub(1) as usize) as u16
+    }
+
+    /// The heading or search match marked at a row of the scrollbar track, if any.
+    /// Headings win over matches, as they do when drawn.
+    fn minimap_mark(&self, row: u16, track_len: u16) -> Option<usize> {
+        let headings = self.heading_positions().map(|(line, _)| line);
+        headings
+            .chain(self.search_matches.iter().copied())
+            .find(|&line| self.track_row(line, track_len) == row)
+    }
+
+    /// Move to the next or previous heading in the current view, showing its
+    /// text in the footer. Past the first or last heading nothing happens.
+    fn jump_to_heading(&mut self, forward: bool) {
+        let cursor_line = self.cursor_line;
+        let target = if forward {
+            self.heading_positions().find(|&(line, _)| line > cursor_line)
+        } else {
+            self.heading_positions().rfind(|&(line, _)| line < cursor_line)
+        };
+        let Some((line, entry)) = target else {
+            return;
+        };
+        self.set_status(entry.text.clone());
+        self.jump_to_line(line + 1);
+    }
+
+    /// Source line `index` as written, read from disk for a streamed file
+    fn raw_source_line(&self, index: usize) -> Cow<'_, str> {
+        match &self.streamed {
+            Some(streamed) => Cow::Owned(streamed.line(&self.source_index, index)),
+            None => Cow::Borrowed(self.source_index.line(&self.content, index)),
+        }
+    }
+
+    /// Whether there is nothing but whitespace to show
+    fn is_blank(&self) -> bool {
+        self.streamed.is_none() && self.content.trim().is_empty()
+    }
+
+    /// Source line `index` with tabs expanded to the configured tab stops
+    fn source_line(&self, index: usize) -> String {
+        expand_tabs(&self.raw_source_line(index), self.tab_width)
+    }
+
+    /// The old and new line on row `index` of a `--diff`, tab-expanded, with
+    /// `None` on the side of a gap
+    fn diff_lines(&self, index: usize) -> (Option<String>, Option<String>) {
+        let Some(diff) = &self.diff else {
+            return (None, None);
+        };
+        let row = diff.rows[index];
+        let old = row.old_line().map(|old| expand_tabs(diff.old_index.line(&diff.old_content, old), self.tab_width));
+        (old, row.new_line().map(|new| self.source_line(new)))
+    }
+
+    /// Source lines `start..end`, tab-expanded
+    fn source_window(&self, start: usize, end: usize) -> Vec<String> {
+        let end = end.min(self.source_index.len());
+        (start.min(end)..end).map(|index| self.source_line(index)).collect()
+    }
+
+
+    /// Text written out in plain mode: raw source in source view, rendered
+    /// otherwise, and for `--diff` every line marked ` `, `-` or `+` as in a
+    /// unified diff
+    fn plain_text(&self) -> String {
+        if let Some(diff) = &self.diff {
+            let old_line = |old| diff.old_index.line(&diff.old_content, old);
+            let new_line = |new| self.source_index.line(&self.content, new);
+            let lines: Vec<String> = diff
+                .rows
+                .iter()
+                .map(|row| match *row {
+                    DiffRow::Same(_, new) => format!(" {}", new_line(new)),
+                    DiffRow::Changed(old, new) => format!("-{}\n+{}", old_line(old), new_line(new)),
+                    DiffRow::Removed(old) => format!("-{}", old_line(old)),
+                    DiffRow::Added(new) => format!("+{}", new_line(new)),
+                })
+                .collect();
+            return lines.join("\n");
+        }
+        match self.view_mode {
+            ViewMode::Source => self.content.clone(),
+            ViewMode::Rendered | ViewMode::SideBySide => {
+                strip_link_targets(&self.rendered_content)
+                    .replace(RULE_MARKER, "---")
+                    .replace([CODE_TOP, CODE_BOTTOM], "```")
+                    .replace(
+                        [
+                            METADATA_MARKER,
+                            ATTRIBUTION_MARKER,
+                            IMAGE_MARKER,
+                            IMAGE_ROW_MARKER,
+                            IMAGE_START,
+                            IMAGE_END,
+                            LINK_START,
+                            LINK_END,
+                            HTML_START,
+                            HTML_END,
+                            CODE_LINE,
+                        ],
+                        "",
+                    )
+            }
+        }
+    }
+
+    /// Number of lines the current view scrolls through; side-by-side
+    /// scrolls as far as the longer of its two panes
+    fn line_count(&self) -> usize {
+        if let Some(diff) = &self.diff {
+            return diff.rows.len();
+        }
+        match self.view_mode {
+            ViewMode::Rendered => self.rendered_view_len(),
+            ViewMode::Source => self.source_index.len(),
+            ViewMode::SideBySide => self.rendered_index.len().max(self.source_index.len()),
+        }
+    }
+
+}
+
+/// The renderer for a document called `name`: markdown when forced, for `.md`
+/// and `.markdown` files and for markdown-looking files without an extension,
+/// reStructuredText for `.rst` and `.rest` files, and none otherwise
+fn renderer_for(name: &str, content: &str, args: &Args) -> Option<Arc<dyn Renderer>> {
+    match args.force_markdown() {
+        Some(true) => return Some(Arc::new(MarkdownRenderer)),
+        Some(false) => return None,
+        None => {}
+    }
+    let is_markdown = match std::path::Path::new(name).extension().and_then(|extension| extension.to_str()) {
+        Some("md" | "markdown") => true,
+        Some("rst" | "rest") => return Some(Arc::new(RstRenderer)),
+        Some("json") => return Some(Arc::new(JsonRenderer)),
+        Some("toml") => return Some(Arc::new(TomlRenderer)),
+        Some(_) => false,
+        None => AppState::looks_like_markdown(content),
+    };
+    is_markdown.then(|| Arc::new(MarkdownRenderer) as Arc<dyn Renderer>)
+}
+
+/// Escape text for use in HTML
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// A byte count in the largest unit that keeps it at least 1, like `42.3 KB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// How long ago a time was, in its largest whole unit, like `2h ago`
+fn format_age(time: SystemTime) -> String {
+    // A modification time in the future (clock skew) reads as just now
+    let seconds = SystemTime::now().duration_since(time).map_or(0, |age| age.as_secs());
+    match seconds {
+        0..60 => "just now".to_string(),
+        60..3_600 => format!("{}m ago", seconds / 60),
+        3_600..86_400 => format!("{}h ago", seconds / 3_600),
+        86_400..2_592_000 => format!("{}d ago", seconds / 86_400),
+        2_592_000..31_536_000 => format!("{}mo ago", seconds / 2_592_000),
+        _ => format!("{}y ago", seconds / 31_536_000),
+    }
+}
+
+/// The file a relative or absolute link in the local file `file_path` points
+/// to, without any `#fragment`; just a fragment points into `file_path` itself.
+/// Links to other files from remote documents aren't followed.
+fn resolve_link(file_path: &str, target: &str) -> Option<String> {
+    let target = target.split('#').next().unwrap_or(target);
+    if target.is_empty() {
+        return Some(file_path.to_string());
+    }
+    if is_url(file_path) || target.contains("://") {
+        return None;
+    }
+    let directory = std::path::Path::new(file_path).parent().unwrap_or(std::path::Path::new(""));
+    Some(directory.join(target).to_string_lossy().into_owned())
+}
+
+/// Hand `url` to the desktop's browser without waiting for it
+fn open_in_browser(url: &str) -> io::Result<()> {
+    let opener = if cfg!(target_os = "macos") { "open" } else { "xdg-open" };
+    Command::new(opener)
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map(|_| ())
+}
+
+/// The file name that stands for standard input
+const STDIN_PATH: &str = "-";
+
+fn is_url(file_path: &str) -> bool {
+    file_path.starts_with("http://") || file_path.starts_with("https://")
+}
+
+/// The path part of a URL, without the query or fragment
+fn url_path(url: &str) -> &str {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = without_scheme.find('/').map_or("", |start| &without_scheme[start..]);
+    path.split(['?', '#']).next().unwrap_or(path)
+}
+
+/// Download a remote document, treating anything but 200 OK as an error
+fn fetch_url(url: &str) -> Result<Vec<u8>> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .map_err(|e| fetch_error(url, e))?;
+    let response = client
+        .get(url)
+        .send()
+        .map_err(|e| fetch_error(url, e))?;
+    let status = response.status();
+    if status != reqwest::StatusCode::OK {
+        return Err(color_eyre::eyre::eyre!("Failed to fetch '{}': server responded with {}", url, status));
+    }
+    let body = response
+        .bytes()
+        .map_err(|e| fetch_error(url, e))?;
+    Ok(body.to_vec())
+}
+
+/// reqwest errors only name the request, so include what went wrong underneath
+fn fetch_error(url: &str, error: reqwest::Error) -> color_eyre::eyre::Report {
+    let error = error.without_url();
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(&error);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    color_eyre::eyre::eyre!("Failed to fetch '{}': {}", url, message)
+}
+
+/// Names of the non-hidden files in `directory` that look like text, sorted.
+/// Files that can't be read are left out.
+fn list_text_files(directory: &std::path::Path) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = entry.path();
+        if name.starts_with('.') || !path.is_file() {
+            continue;
+        }
+        let mut start = Vec::new();
+        let readable = fs::File::open(&path).and_then(|file| file.take(LISTING_SNIFF_BYTES).read_to_end(&mut start));
+        if readable.is_ok() && !looks_binary(&start) {
+            files.push(name);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Run `command` through the shell with `input` on its stdin and return what it
+/// printed. A failing command reports its exit code and the first line of its stderr.
+fn run_command(command: &str, mut input: impl Read + Send + 'static) -> Result<String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| color_eyre::eyre::eyre!("Failed to run '{}': {}", command, e))?;
+
+    // Write from another thread so a command that prints before reading all of
+    // its input can't fill the stdout pipe and stall both sides
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let writer = thread::spawn(move || {
+        // Commands like `head` may exit without reading everything
+        let _ = io::copy(&mut input, &mut stdin);
+    });
+    let output = child
+        .wait_with_output()
+        .map_err(|e| color_eyre::eyre::eyre!("Failed to run '{}': {}", command, e))?;
+    let _ = writer.join();
+
+    if !output.status.success() {
+        let status = match output.status.code() {
+            Some(code) => format!("exited with {}", code),
+            None => output.status.to_string(),
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.lines().map(str::trim).find(|line| !line.is_empty()) {
+            Some(line) => color_eyre::eyre::eyre!("'{}' {}: {}", command, status, line),
+            None => color_eyre::eyre::eyre!("'{}' {}", command, status),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn decompress_gzip(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+/// Guess whether bytes are binary data by sampling the start for NUL
+/// bytes or a high proportion of non-whitespace control characters
+fn looks_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+    if sample.is_empty() {
+        return false;
+    }
+    if sample.contains(&0) {
+        return true;
+    }
+
+    let control = sample
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+        .count();
+    control * 100 / sample.len() > BINARY_CONTROL_PERCENT
+}
+
+/// Directory holding the document at `file_path`, which local image paths are relative to
+fn document_dir(file_path: &str) -> &std::path::Path {
+    std::path::Path::new(file_path).parent().unwrap_or(std::path::Path::new(""))
+}
+
+/// Make the paths of local images relative to the document at `file_path`
+fn resolve_image_paths(file_path: &str, images: &mut [InlineImage]) {
+    for image in images {
+        image.path = document_dir(file_path).join(&image.path);
+    }
+}
+
+/// The number `--number-nonblank` gives each line of `content`, counting only
+/// lines that aren't blank; blank ones get none
+fn nonblank_numbers(lines: impl Iterator<Item = impl AsRef<str>>) -> Vec<Option<usize>> {
+    let mut number = 0;
+    lines
+        .map(|line| {
+            (!line.as_ref().trim().is_empty()).then(|| {
+                number += 1;
+                number
+            })
+        })
+        .collect()
+}
+
+/// Lines `start` to `end` of `content`, counting from 1, with an `end` past the
+/// last line cut short. `None` when the file ends before `start`.
+fn select_lines(content: &str, start: usize, end: usize) -> Option<String> {
+    let mut lines = content.split_inclusive('\n').skip(start - 1).peekable();
+    lines.peek()?;
+    Some(lines.take(end - start + 1).collect())
+}
+
+/// Convert Windows (`\r\n`) and classic Mac (`\r`) line endings to `\n`
+/// so no stray carriage returns reach the terminal
+fn normalize_line_endings(content: String) -> String {
+    if !content.contains('\r') {
+        return content;
+    }
+    content.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// `text` without ANSI escape sequences: CSI sequences such as colors and
+/// cursor moves, OSC sequences such as titles and hyperlinks, and two-character
+/// escapes. The text around them is kept as it is.
+fn strip_ansi(text: &str) -> String {
+    if !text.contains('\x1b') {
+        return text.to_string();
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            // Parameter and intermediate bytes, then a final byte in `@`..`~`
+            Some('[') => {
+                while chars.next_if(|c| (' '..='?').contains(c)).is_some() {}
+                chars.next_if(|c| ('@'..='~').contains(c));
+            }
+            // Up to BEL or the string terminator `ESC \`, stopping at the end of
+            // the line should neither come
+            Some(']') => {
+                while let Some(c) = chars.next_if(|&c| c != '\n') {
+                    if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            // Intermediate bytes, then a final byte, as in `ESC ( B`
+            Some(' '..='/') => {
+                while chars.next_if(|c| (' '..='/').contains(c)).is_some() {}
+                chars.next_if(|c| ('0'..='~').contains(c));
+            }
+            Some(_) | None => {}
+        }
+    }
+    result
+}
+
+/// The files `--watch-command` runs on changes to, and what they looked like
+/// at the last check
+#[derive(Debug)]
+struct Watch {
+    command: String,
+    /// The file in view when watching started, read again after each run
+    viewed: String,
+    paths: Vec<PathBuf>,
+    snapshot: WatchSnapshot,
+    checked: Instant,
+    /// Whether the files changed at the last check, so the command waits for
+    /// a quiet check before running
+    changing: bool,
+    /// The command running on a background thread, which sends its result when done
+    running: Option<mpsc::Receiver<Result<String>>>,
+}
+
+/// Latest modification time among watched files and how many there are, so
+/// a deleted file counts as a change too
+type WatchSnapshot = (Option<SystemTime>, usize);
+
+impl Watch {
+    /// Watch `paths`, or with none given, `viewed` and the directory it is in
+    fn new(command: String, paths: &[String], viewed: &str) -> Watch {
+        let paths: Vec<PathBuf> = match paths {
+            [] if is_url(viewed) || viewed == STDIN_PATH => vec![PathBuf::from(".")],
+            [] => {
+                let directory = document_dir(viewed);
+                let directory = if directory.as_os_str().is_empty() { std::path::Path::new(".") } else { directory };
+                vec![PathBuf::from(viewed), directory.to_path_buf()]
+            }
+            paths => paths.iter().map(PathBuf::from).collect(),
+        };
+        Watch {
+            command,
+            viewed: viewed.to_string(),
+            snapshot: watch_snapshot(&paths),
+            paths,
+            checked: Instant::now(),
+            changing: false,
+            running: None,
+        }
+    }
+
+    /// Look at the files again if it's time, returning whether the command
+    /// should run now that a change has settled
+    fn poll(&mut self) -> bool {
+        if self.running.is_some() || self.checked.elapsed() < WATCH_POLL_INTERVAL {
+            return false;
+        }
+        self.checked = Instant::now();
+        let snapshot = watch_snapshot(&self.paths);
+        let changed = snapshot != self.snapshot;
+        self.snapshot = snapshot;
+        let settled = self.changing && !changed;
+        self.changing = changed;
+        settled
+    }
+}
+
+/// The state of the files at `paths`, going through directories but leaving
+/// out hidden entries like `.git`, build directories like `target`, and not
+/// following symlinks into directories
+fn watch_snapshot(paths: &[PathBuf]) -> WatchSnapshot {
+    let (mut latest, mut count) = (None, 0);
+    let mut pending = paths.to_vec();
+    while let Some(path) = pending.pop() {
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            let entries = fs::read_dir(&path).into_iter().flatten().flatten();
+            pending.extend(
+                entries
+                    .filter(|entry| {
+                        let name = entry.file_name();
+                        let name = name.to_string_lossy();
+                        let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+                        let skipped = is_dir && WATCH_SKIPPED_DIRS.contains(&name.as_ref());
+                        !name.starts_with('.') && !skipped
+                    })
+                    .map(|entry| entry.path()),
+            );
+        } else {
+            count += 1;
+            latest = latest.max(metadata.modified().ok());
+        }
+    }
+    (latest, count)
+}
+
+/// Counts shown in the document statistics popup
+struct DocumentStats {
+    lines: usize,
+    words: usize,
+    characters: usize,
+    /// Words in the rendered markdown, ignoring markup-only tokens like bullets and fences
+    prose_words: Option<usize>,
+}
+
+impl DocumentStats {
+    fn new(app_state: &AppState) -> Self {
+        let prose_words = app_state.renderer.is_some().then(|| {
+            app_state
+                .rendered_content
+                .split_whitespace()
+                .filter(|word| word.chars().any(char::is_alphanumeric))
+                .count()
+        });
+
+        let (words, characters) = match &app_state.streamed {
+            Some(streamed) => streamed.counts(),
+            None => (app_state.content.split_whitespace().count(), app_state.content.chars().count()),
+        };
+        DocumentStats {
+            lines: app_state.source_index.len(),
+            words,
+            characters,
+            prose_words,
+        }
+    }
+
+    fn reading_minutes(&self) -> usize {
+        self.prose_words.unwrap_or(self.words).div_ceil(READING_WORDS_PER_MINUTE)
+    }
+}
+
+/// All open buffers, each keeping its own scroll position and view mode
+#[derive(Debug)]
+struct App {
+    buffers: Vec<AppState>,
+    current: usize,
+    key_bindings: KeyBindings,
+    theme: Theme,
+    smooth_scroll: bool,
+    /// Maximum width of rendered markdown, from `--width` or the `w` presets
+    reading_width: Option<u16>,
+    /// Command line options, kept to open piped output and listed files
+    args: Args,
+    /// Whether the pager is shown, so files opened later may draw images
+    interactive: bool,
+    /// Keywords highlighted while `highlight_todos` is on
+    todo_keywords: Vec<String>,
+    highlight_todos: bool,
+    /// Column guide in source view, from `--ruler`
+    ruler: Option<usize>,
+    /// The `:` command palette, while open
+    palette: Option<CommandPalette>,
+    /// The `Ctrl+t` heading finder, while open
+    finder: Option<HeadingFinder>,
+    image_loader: ImageLoader,
+    /// Images on screen right now, so they are only drawn again when something moves
+    drawn_images: Vec<ImageSlot>,
+    /// Files that links were followed from and where they were scrolled to,
+    /// most recent last
+    back_stack: Vec<(String, usize)>,
+    /// Read the current file again once a frame saying so has been drawn
+    pending_reload: bool,
+    /// `--watch-command` and the files it watches
+    watch: Option<Watch>,
+    /// When auto-scroll last moved the view, while it is on
+    auto_scroll: Option<Instant>,
+    auto_scroll_interval: Duration,
+    /// The action of a bound digit that started a count and when it was
+    /// pressed, run if no other key follows within `DIGIT_ACTION_DELAY`
+    digit_action: Option<(Action, Instant)>,
+}
+
+impl App {
+    fn new(args: &Args, config: &Config) -> Result<Self> {
+        // A saved theme file may have gone since; only a flag naming one is an error
+        let theme = match (&args.theme, args.theme()) {
+            (None, Some(name)) => Theme::load(name).unwrap_or_default(),
+            (_, name) => Theme::load(name.unwrap_or("dark"))?,
+        };
+        let theme = if args.no_color() { theme.without_colors() } else { theme };
+        let interactive = !args.plain && args.export.is_none() && atty::is(atty::Stream::Stdout);
+        let mut buffers = if args.diff {
+            let files: Vec<String> = args.file_args().cloned().collect();
+            let [old, new] = <[String; 2]>::try_from(files)
+                .map_err(|_| color_eyre::eyre::eyre!("--diff compares two files, an old and a new one"))?;
+            vec![AppState::for_diff(old, new, args, &theme, interactive)?]
+        } else {
+            args.file_args()
+                .map(|file| AppState::new(file.clone(), args, &theme, interactive))
+                .collect::<Result<Vec<_>>>()?
+        };
+        if buffers.is_empty() {
+            return Err(color_eyre::eyre::eyre!("No files to view"));
+        }
+        // Files picked from a listing open once the terminal is in raw mode,
+        // when it can no longer be asked about graphics
+        if interactive && buffers.iter().any(|buffer| buffer.listing.is_some()) {
+            images::graphics_supported();
+        }
+        let scroll_margin = args.scroll_margin.or(config.scroll_margin()).unwrap_or(DEFAULT_SCROLL_MARGIN);
+        let watch = args.watch_command.clone().map(|command| Watch::new(command, &args.watch, &buffers[0].file_path));
+        for buffer in &mut buffers {
+            buffer.scroll_margin = scroll_margin;
+        }
+
+        Ok(App {
+            buffers,
+            current: 0,
+            key_bindings: config.key_bindings(),
+            theme,
+            smooth_scroll: args.smooth,
+            reading_width: args.width,
+            todo_keywords: config.todo_keywords(),
+            highlight_todos: args.highlight_todos,
+            ruler: args.ruler,
+            args: args.clone(),
+            interactive,
+            palette: None,
+            finder: None,
+            image_loader: ImageLoader::new(),
+            drawn_images: Vec::new(),
+            back_stack: Vec::new(),
+            pending_reload: false,
+            watch,
+            auto_scroll: None,
+            auto_scroll_interval: Duration::from_millis(args.interval),
+            digit_action: None,
+        })
+    }
+
+    /// The settings for the next session to start with, taken from the file in view
+    fn preferences(&self) -> Preferences {
+        let buffer = &self.buffers[self.current];
+        // Plain text files are always shown as source, which says nothing about markdown
+        let view_mode = match buffer.renderer {
+            Some(_) if buffer.deferred_render.is_none() => Some(buffer.view_mode.clone()),
+            _ => self.args.preferences.view_mode.clone(),
+        };
+        // Theme files are kept by full path so they load from any directory
+        let theme = self.args.theme().map(|name| match name {
+            "dark" | "light" => name.to_string(),
+            path => fs::canonicalize(path).map_or(path.to_string(), |path| path.to_string_lossy().into_owned()),
+        });
+        Preferences {
+            view_mode,
+            wrap: Some(buffer.wrap_mode),
+            wrap_markers: Some(buffer.wrap_markers),
+            theme,
+        }
+    }
+
+    /// Draw the images placed by the last frame over their reserved rows. Terminal
+    /// graphics sit outside ratatui's buffer, so when the images on screen change
+    /// the old ones are wiped and the frame redrawn before drawing the new ones.
+    fn draw_images(&mut self, terminal: &mut Terminal<CrosstermBackend<io::Stdout>>) -> Result<()> {
+        let loader = &mut self.image_loader;
+        let slots: Vec<ImageSlot> = self.buffers[self.current]
+            .image_slots
+            .iter()
+            .filter(|slot| loader.get(&slot.path).is_some())
+            .cloned()
+            .collect();
+        if slots == self.drawn_images {
+            return Ok(());
+        }
+
+        if !self.drawn_images.is_empty() {
+            images::clear()?;
+            terminal.clear()?;
+            terminal.draw(|f| render(f, self))?;
+        }
+        for slot in &slots {
+            if let Some(image) = self.image_loader.get(&slot.path) {
+                // An image the terminal rejects just leaves its rows blank
+                let _ = images::print(image, slot.area);
+            }
+        }
+        self.drawn_images = slots;
+        Ok(())
+    }
+
+    fn current_buffer(&mut self) -> &mut AppState {
+        &mut self.buffers[self.current]
+    }
+
+    fn copy_to_clipboard(&mut self) {
+        self.buffers[self.current].copy_to_clipboard(&self.theme);
+    }
+
+    /// Step through the reading width presets, starting over from a custom `--width`
+    fn cycle_reading_width(&mut self) {
+        let next = match READING_WIDTH_PRESETS.iter().position(|&width| width == self.reading_width) {
+            Some(index) => READING_WIDTH_PRESETS[(index + 1) % READING_WIDTH_PRESETS.len()],
+            None => READING_WIDTH_PRESETS[0],
+        };
+        self.reading_width = next;
+        self.current_buffer().set_status(match next {
+            Some(width) => format!("Reading width: {} columns", width),
+            None => "Reading width: full".to_string(),
+        });
+    }
+
+    /// Run the command typed after `|` with the selection or document on its
+    /// stdin, and open what it prints in a new buffer
+    fn confirm_pipe(&mut self) {
+        let app_state = &mut self.buffers[self.current];
+        let Some(command) = app_state.pipe_input.take().filter(|command| !command.trim().is_empty()) else {
+            return;
+        };
+        let output = app_state
+            .pipe_text(&self.theme)
+            .map_err(|e| color_eyre::eyre::eyre!("Failed to read '{}': {}", app_state.file_path, e))
+            .and_then(|input| run_command(&command, input));
+        app_state.selection_anchor = None;
+        match output {
+            Ok(output) => {
+                let scroll_margin = app_state.scroll_margin;
+                let name = format!("| {}", command);
+                // Image paths in command output aren't relative to anything
+                let mut buffer = AppState::from_content(name, output, &self.args, &self.theme, false);
+                buffer.scroll_margin = scroll_margin;
+                self.buffers.push(buffer);
+                self.current = self.buffers.len() - 1;
+            }
+            Err(e) => app_state.set_status(e.to_string()),
+        }
+    }
+
+    /// Replace the directory listing with the file selected in it. The listing
+    /// stays with the buffer so `-` can bring it back.
+    fn open_listed_file(&mut self) {
+        let app_state = &mut self.buffers[self.current];
+        let Some(listing) = app_state.listing.take() else {
+            return;
+        };
+        let Some(name) = listing.files.get(listing.selected) else {
+            app_state.listing = Some(listing);
+            return;
+        };
+        let path = std::path::Path::new(&listing.directory).join(name).to_string_lossy().into_owned();
+        if path == app_state.file_path {
+            app_state.listing = Some(listing);
+            app_state.show_listing = false;
+            return;
+        }
+
+        match AppState::new(path, &self.args, &self.theme, self.interactive) {
+            Ok(mut buffer) => {
+                // Remember where the file being replaced was left
+                if !self.args.no_resume {
+                    let mut saved_state = SavedState::load();
+                    app_state.save_position(&mut saved_state);
+                    if let Err(e) = saved_state.save() {
+                        buffer.set_status(format!("Failed to save reading positions: {}", e));
+                    }
+                    buffer.restore_position(&saved_state, &self.args);
+                }
+                buffer.scroll_margin = app_state.scroll_margin;
+                buffer.listing = Some(listing);
+                *app_state = buffer;
+            }
+            Err(e) => {
+                app_state.set_status(e.to_string());
+                app_state.listing = Some(listing);
+            }
+        }
+    }
+
+    /// Carry out `action`, repeated or aimed by a typed `count`. Returns false
+    /// once the viewer should quit.
+    fn perform_action(&mut self, action: Action, count: Option<usize>) -> bool {
+        let step = count.unwrap_or(1);
+        let animate = self.smooth_scroll
+            && matches!(
+                action,
+                Action::PageUp | Action::PageDown | Action::HalfPageUp | Action::HalfPageDown | Action::Top | Action::Bottom
+            );
+        let confirm_quit = self.args.confirm_quit;
+        let app_state = self.current_buffer();
+        let offset_before = app_state.scroll_offset;
+
+        match action {
+            Action::Quit if confirm_quit => app_state.pending_command = Some(PendingCommand::Quit),
+            Action::Quit => return false,
+            Action::ToggleView => app_state.toggle_view_mode(),
+            Action::ViewRendered => app_state.show_view_mode(ViewMode::Rendered),
+            Action::ViewSource => app_state.show_view_mode(ViewMode::Source),
+            Action::ViewSideBySide => app_state.show_view_mode(ViewMode::SideBySide),
+            Action::ToggleToc => app_state.toggle_toc(),
+            Action::ScrollUp => app_state.cursor_up(step),
+            Action::ScrollDown => app_state.cursor_down(step),
+            Action::PageUp => app_state.scroll_up(step.saturating_mul(app_state.page_height())),
+            Action::PageDown => app_state.scroll_down(step.saturating_mul(app_state.page_height())),
+            Action::HalfPageUp => app_state.scroll_up(step.saturating_mul(app_state.half_page_height())),
+            Action::HalfPageDown => app_state.scroll_down(step.saturating_mul(app_state.half_page_height())),
+            Action::ViewUp => app_state.shift_view(step, false),
+            Action::ViewDown => app_state.shift_view(step, true),
+            Action::Top => match count {
+                Some(line) => app_state.jump_to_line(line),
+                None => {
+                    app_state.scroll_offset = 0;
+                    app_state.cursor_line = 0;
+                }
+            },
+            Action::Bottom => match count {
+                Some(line) => app_state.jump_to_line(line),
+                None => {
+                    app_state.scroll_offset = app_state.bottom_offset();
+                    app_state.cursor_line = app_state.line_count().saturating_sub(1);
+                }
+            },
+            Action::NextFile => self.next_buffer(),
+            Action::PreviousFile => self.previous_buffer(),
+            Action::Help => {
+                app_state.show_help = true;
+                app_state.help_scroll = 0;
+            }
+            Action::Stats => app_state.show_stats = true,
+            Action::SetMark => app_state.pending_command = Some(PendingCommand::SetMark),
+            Action::JumpToMark => app_state.pending_command = Some(PendingCommand::JumpToMark),
+            Action::Fold => app_state.pending_command = Some(PendingCommand::Fold),
+            Action::NextHeading => app_state.pending_command = Some(PendingCommand::NextHeading),
+            Action::PreviousHeading => app_state.pending_command = Some(PendingCommand::PreviousHeading),
+            Action::Footnote => app_state.follow_footnote(),
+            Action::ToggleSplit => app_state.toggle_split(),
+            Action::ShrinkSplit => app_state.resize_split(-(SPLIT_RATIO_STEP as i16)),
+            Action::GrowSplit => app_state.resize_split(SPLIT_RATIO_STEP as i16),
+            Action::CycleWidth => self.cycle_reading_width(),
+            Action::ToggleTodos => self.highlight_todos = !self.highlight_todos,
+            Action::Percent => app_state.jump_to_percent(count.unwrap_or(0)),
+            Action::Select => app_state.toggle_selection(),
+            Action::Copy => self.copy_to_clipboard(),
+            Action::Export => app_state.export_input = Some(String::new()),
+            Action::Pipe => app_state.pipe_input = Some(String::new()),
+            Action::Listing => app_state.toggle_listing(),
+            Action::ToggleMarkers => app_state.toggle_markers(),
+            Action::ToggleWrapMarkers => app_state.toggle_wrap_markers(),
+            Action::ToggleWhitespace => app_state.toggle_trailing_whitespace(),
+            Action::Search => app_state.start_search(),
+            Action::NextMatch => app_state.jump_to_match(true),
+            Action::PreviousMatch => app_state.jump_to_match(false),
+            Action::Palette => self.palette = Some(CommandPalette::default()),
+            Action::FindHeading => self.finder = Some(HeadingFinder::new(&self.buffers[self.current].toc)),
+            Action::AutoScroll => self.toggle_auto_scroll(),
+            Action::FollowLink => self.follow_link(),
+            Action::Back => self.go_back(),
+            Action::Reload => {
+                app_state.set_status("Reloading…".to_string());
+                self.pending_reload = true;
+            }
+        }
+
+        let app_state = self.current_buffer();
+        app_state.clamp_cursor();
+        if animate {
+            app_state.start_scroll_animation(offset_before);
+        }
+        true
+    }
+
+    /// Start scrolling a line every `--interval`, or pause if it already is
+    fn toggle_auto_scroll(&mut self) {
+        let app_state = &mut self.buffers[self.current];
+        if self.auto_scroll.take().is_some() {
+            app_state.set_status("Auto-scroll paused".to_string());
+        } else if app_state.scroll_offset >= app_state.bottom_offset() {
+            app_state.set_status("Already at the end".to_string());
+        } else {
+            self.auto_scroll = Some(Instant::now());
+        }
+    }
+
+    /// Scroll down a line once the interval since the last one has passed,
+    /// stopping when the end of the document comes into view
+    fn step_auto_scroll(&mut self) {
+        if self.auto_scroll.is_none_or(|last| last.elapsed() < self.auto_scroll_interval) {
+            return;
+        }
+        let app_state = &mut self.buffers[self.current];
+        app_state.scroll_down(1);
+        self.auto_scroll = Some(Instant::now());
+        if app_state.scroll_offset >= app_state.bottom_offset() {
+            self.auto_scroll = None;
+            app_state.set_status("Auto-scroll reached the end".to_string());
+        }
+    }
+
+    /// The action of a bound digit once it has waited long enough for no more
+    /// digits, dropping the count it started
+    fn take_due_digit_action(&mut self) -> Option<Action> {
+        let (action, pressed) = self.digit_action?;
+        if pressed.elapsed() < DIGIT_ACTION_DELAY {
+            return None;
+        }
+        self.digit_action = None;
+        self.buffers[self.current].pending_count = None;
+        Some(action)
+    }
+
+    /// Open the first link on the cursor line: web pages in the browser, files
+    /// in their own buffer, remembering where to come back to
+    fn follow_link(&mut self) {
+        let app_state = &mut self.buffers[self.current];
+        if !self.args.follow_links {
+            app_state.set_status("Start with --follow-links to open links".to_string());
+            return;
+        }
+        let target = match link_targets(&app_state.view_line(app_state.cursor_line)).first() {
+            Some(target) => target.to_string(),
+            None => {
+                app_state.set_status("No link on this line".to_string());
+                return;
+            }
+        };
+        if is_url(&target) || target.starts_with("mailto:") {
+            app_state.set_status(match open_in_browser(&target) {
+                Ok(()) => format!("Opened {}", target),
+                Err(e) => format!("Failed to open {}: {}", target, e),
+            });
+            return;
+        }
+        let Some(path) = resolve_link(&app_state.file_path, &target) else {
+            app_state.set_status(format!("Can't follow {}", target));
+            return;
+        };
+        let back = (app_state.file_path.clone(), app_state.scroll_offset);
+        if !self.switch_to_file(path) {
+            return;
+        }
+        self.back_stack.push(back);
+        if let Some((_, fragment)) = target.split_once('#')
+            && !fragment.is_empty()
+        {
+            let app_state = &mut self.buffers[self.current];
+            if !app_state.go_to_heading(fragment) {
+                app_state.set_status(format!("No heading #{}", fragment));
+            }
+        }
+    }
+
+    /// Read the current file from disk again and render it afresh
+    fn reload(&mut self) -> bool {
+        self.reload_buffer(self.current)
+    }
+
+    /// Read the file of buffer `index` from disk again and render it afresh,
+    /// keeping the view where it was as far as the new content allows. A file
+    /// that can't be read any more leaves the old content up, with the error in
+    /// the footer, and returns false.
+    fn reload_buffer(&mut self, index: usize) -> bool {
+        let app_state = &mut self.buffers[index];
+        if app_state.file_path == STDIN_PATH {
+            app_state.set_status("Standard input can't be read again".to_string());
+            return false;
+        }
+        let buffer = match &app_state.diff {
+            Some(diff) => AppState::for_diff(
+                diff.old_path.clone(),
+                app_state.file_path.clone(),
+                &self.args,
+                &self.theme,
+                self.interactive,
+            ),
+            None => AppState::new(app_state.file_path.clone(), &self.args, &self.theme, self.interactive),
+        };
+        let mut buffer = match buffer {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                app_state.set_status(e.to_string());
+                return false;
+            }
+        };
+        if buffer.renderer.is_some() && buffer.deferred_render.is_none() {
+            buffer.view_mode = app_state.view_mode.clone();
+        }
+        buffer.show_markers = app_state.show_markers;
+        buffer.wrap_markers = app_state.wrap_markers;
+        buffer.trailing_whitespace = app_state.trailing_whitespace;
+        buffer.show_toc = app_state.show_toc;
+        buffer.split = app_state.split;
+        buffer.split_ratio = app_state.split_ratio;
+        buffer.scroll_margin = app_state.scroll_margin;
+        // A directory gets a fresh listing; a file keeps the one it was picked from
+        if buffer.listing.is_none() {
+            buffer.listing = app_state.listing.take();
+            buffer.show_listing = app_state.show_listing;
+        }
+        buffer.marks = std::mem::take(&mut app_state.marks);
+        let last = buffer.line_count().saturating_sub(1);
+        buffer.scroll_offset = app_state.scroll_offset.min(last);
+        buffer.cursor_line = app_state.cursor_line.min(last);
+        if !app_state.search_query.is_empty() {
+            buffer.search_query = std::mem::take(&mut app_state.search_query);
+            buffer.refresh_search();
+        }
+        buffer.set_status("Reloaded".to_string());
+        *app_state = buffer;
+        true
+    }
+
+    /// Start the watch command on a background thread once a watched change
+    /// has settled, and read the watched file again when it is done, wherever
+    /// its buffer is now. A failure
+    /// is shown until the next key, with the first line of its stderr.
+    fn poll_watch(&mut self) {
+        let Some(watch) = self.watch.as_mut() else {
+            return;
+        };
+        let Some(receiver) = &watch.running else {
+            if watch.poll() {
+                let command = watch.command.clone();
+                let (sender, receiver) = mpsc::channel();
+                self.current_buffer().set_lasting_status(format!("Running '{}'…", command));
+                thread::spawn(move || {
+                    // The viewer may have quit in the meantime
+                    let _ = sender.send(run_command(&command, io::empty()));
+                });
+                self.watch.as_mut().expect("watch is set").running = Some(receiver);
+            }
+            return;
+        };
+        let result = match receiver.try_recv() {
+            Ok(result) => result,
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Err(color_eyre::eyre::eyre!("'{}' stopped without finishing", watch.command))
+            }
+        };
+        watch.running = None;
+        // Files the command wrote aren't a change to run it for again
+        watch.snapshot = watch_snapshot(&watch.paths);
+        let command = watch.command.clone();
+        let viewed = self.buffers.iter().position(|buffer| buffer.file_path == watch.viewed);
+        match (result, viewed) {
+            (Ok(_), Some(index)) => {
+                if self.reload_buffer(index) {
+                    self.buffers[index].set_status(format!("'{}' exited with 0; reloaded", command));
+                }
+            }
+            // The watched file's buffer was closed, so there is nothing to reload
+            (Ok(_), None) => self.current_buffer().set_status(format!("'{}' exited with 0", command)),
+            (Err(e), _) => self.current_buffer().set_lasting_status(e.to_string()),
+        }
+    }
+
+    /// Return to the file the last link was followed from, scrolled as it was
+    fn go_back(&mut self) {
+        let Some((file_path, scroll_offset)) = self.back_stack.pop() else {
+            self.buffers[self.current].set_status("No earlier document to go back to".to_string());
+            return;
+        };
+        if self.switch_to_file(file_path) {
+            let app_state = &mut self.buffers[self.current];
+            app_state.scroll_offset = scroll_offset.min(app_state.line_count().saturating_sub(1));
+        }
+    }
+
+    /// Switch to the buffer showing `path`, opening one if there is none (a
+    /// file opened from a listing replaces its buffer). Returns false, with the
+    /// error in the footer, when the file can't be opened.
+    fn switch_to_file(&mut self, path: String) -> bool {
+        let canonical = fs::canonicalize(&path).ok();
+        let open = self.buffers.iter().position(|buffer| {
+            buffer.file_path == path || (canonical.is_some() && buffer.canonical_path == canonical)
+        });
+        if let Some(index) = open {
+            self.current = index;
+            return true;
+        }
+        match AppState::new(path, &self.args, &self.theme, self.interactive) {
+            Ok(mut buffer) => {
+                buffer.scroll_margin = self.buffers[self.current].scroll_margin;
+                self.buffers.push(buffer);
+                self.current = self.buffers.len() - 1;
+                true
+            }
+            Err(e) => {
+                self.buffers[self.current].set_status(e.to_string());
+                false
+            }
+        }
+    }
+
+    fn next_buffer(&mut self) {
+        self.current = (self.current + 1) % self.buffers.len();
+    }
+
+    fn previous_buffer(&mut self) {
+        self.current = (self.current + self.buffers.len() - 1) % self.buffers.len();
+    }
+
+    fn title(&self) -> String {
+        let app_state = &self.buffers[self.current];
+        let file_path = match &app_state.diff {
+            Some(diff) => format!("{} → {}", diff.old_path, app_state.file_path),
+            None => app_state.file_path.clone(),
+        };
+        let title = if self.buffers.len() > 1 {
+            format!("mess - [{}/{}] {}", self.current + 1, self.buffers.len(), file_path)
+        } else {
+            format!("mess - {}", file_path)
+        };
+        match app_state.metadata {
+            Some(FileMetadata { size, modified: Some(modified) }) => {
+                format!("{} · {} · modified {}", title, format_size(size), format_age(modified))
+            }
+            Some(FileMetadata { size, modified: None }) => format!("{} · {}", title, format_size(size)),
+            None => title,
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+    
+    let mut args = Args::parse();
+    args.apply_environment();
+    if !args.no_resume {
+        args.preferences = SavedState::load().preferences().clone();
+    }
+    let start_position = args.start_position()?;
+    args.line_range()?;
+    let config = Config::load();
+    let mut app = App::new(&args, &config)?;
+
+    if let Some(path) = &args.export {
+        return app.buffers[0].export(path);
+    }
+    
+    // Behave like a pager fallback and just dump the content when piped
+    if args.plain || !atty::is(atty::Stream::Stdout) {
+        return print_plain(&app);
+    }
+    if args.quit_if_one_screen && fits_on_one_screen(&app) {
+        return print_plain(&app);
+    }
+    // With standard input used up by the document, keys are read from the
+    // controlling terminal instead, which crossterm opens itself
+    if args.files.iter().any(|file| file == STDIN_PATH)
+        && let Err(e) = fs::OpenOptions::new().read(true).write(true).open("/dev/tty")
+    {
+        eprintln!("Can't open /dev/tty for keyboard input ({}); printing instead", e);
+        return print_plain(&app);
+    }
+
+    // Positions within part of a file don't carry over to the whole of it
+    let resume_positions = !args.no_resume && args.lines.is_none();
+    if resume_positions {
+        let saved_state = SavedState::load();
+        for app_state in &mut app.buffers {
+            app_state.restore_position(&saved_state, &args);
+        }
+    }
+    // An explicit start position wins over a saved one
+    if let Some(start_position) = &start_position {
+        for app_state in &mut app.buffers {
+            app_state.apply_start_position(start_position)?;
+        }
+    }
+    
+    // Initialize terminal using proper Ratatui pattern with alternate screen
+    crossterm::terminal::enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen, EnableMouseCapture)?;
+    
+    let backend = CrosstermBackend::new(io::stdout());
+    let mut terminal = Terminal::new(backend)?;
+    
+    let result = run(&mut terminal, &mut app);
+    
+    // Restore terminal - this is critical for proper cleanup like "less"
+    execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
+    crossterm::terminal::disable_raw_mode()?;
+
+    if result.is_ok() && !args.no_resume {
+        // Reload first so positions saved by other sessions in the meantime are kept
+        let mut saved_state = SavedState::load();
+        if resume_positions {
+            for app_state in &app.buffers {
+                app_state.save_position(&mut saved_state);
+            }
+        }
+        saved_state.set_preferences(app.preferences());
+        if let Err(e) = saved_state.save() {
+            eprintln!("Warning: failed to save reading positions: {}", e);
+        }
+    }
+    
+    result
+}
+
+/// Whether the plain output of every buffer fits in the terminal, leaving a
+/// row for the shell prompt that follows it
+fn fits_on_one_screen(app: &App) -> bool {
+    let Ok((columns, rows)) = crossterm::terminal::size() else {
+        return false;
+    };
+    let columns = columns.max(1) as usize;
+    let mut screen_rows = 0;
+    for app_state in &app.buffers {
+        if app_state.streamed.is_some() {
+            return false;
+        }
+        for line in app_state.plain_text().lines() {
+            let width = expand_tabs(line, app_state.tab_width).width();
+            screen_rows += width.div_ceil(columns).max(1);
+        }
+    }
+    screen_rows < rows as usize
+}
+
+fn print_plain(app: &App) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    for app_state in &app.buffers {
+        let written = match &app_state.streamed {
+            Some(streamed) => streamed.copy_to(&mut stdout, false),
+            None => {
+                let text = app_state.plain_text();
+                // Text that already ends in a newline gets no second one, so piped source
+                // matches a file that uses `\n` line endings and ends with one
+                let newline = if text.is_empty() || text.ends_with('\n') { "" } else { "\n" };
+                write!(stdout, "{}{}", text, newline)
+            }
+        };
+        if let Err(e) = written {
+            // A closed pipe (e.g. `mess file | head`) isn't an error
+            if e.kind() == io::ErrorKind::BrokenPipe {
+                return Ok(());
+            }
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+fn run(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, app: &mut App) -> Result<()> {
+    loop {
+        terminal.draw(|f| render(f, app))?;
+        app.draw_images(terminal)?;
+        if std::mem::take(&mut app.pending_reload) {
+            app.reload();
+            continue;
+        }
+
+        // Poll instead of blocking so a smooth scroll can draw its frames between
+        // input, and a large document can be searched once typing pauses
+        let app_state = app.current_buffer();
+        let animating = app_state.scroll_target.is_some();
+        let search_stale = app_state.search_input.as_ref().is_some_and(|input| input.stale);
+        let timeout = if animating {
+            SMOOTH_SCROLL_FRAME
+        } else if search_stale {
+            SEARCH_DEBOUNCE
+        } else {
+            IDLE_POLL_INTERVAL
+        };
+        let timeout = match app.auto_scroll {
+            Some(last) => timeout.min(app.auto_scroll_interval.saturating_sub(last.elapsed())),
+            None => timeout,
+        };
+        let timeout = match app.digit_action {
+            Some((_, pressed)) => timeout.min(DIGIT_ACTION_DELAY.saturating_sub(pressed.elapsed())),
+            None => timeout,
+        };
+        if !event::poll(timeout)? {
+            if let Some(action) = app.take_due_digit_action()
+                && !app.perform_action(action, None)
+            {
+                break;
+            }
+            app.poll_watch();
+            app.step_auto_scroll();
+            let app_state = app.current_buffer();
+            app_state.step_scroll_animation();
+            app_state.expire_status();
+            app_state.poll_deferred_render();
+            if search_stale {
+                app_state.run_live_search();
+            }
+            continue;
+        }
+
+        let event = event::read()?;
+        let app_state = app.current_buffer();
+        app_state.finish_scroll_animation();
+        // A key or click after a bound digit makes it a count instead
+        if matches!(event, Event::Key(_) | Event::Mouse(_)) {
+            app.digit_action = None;
+        }
+
+        // Heights from the last frame are stale, so lay out again at the new
+        // size before fitting the view to it. The resize wipes any images.
+        if let Event::Resize(..) = event {
+            terminal.autoresize()?;
+            terminal.draw(|f| render(f, app))?;
+            let app_state = app.current_buffer();
+            app_state.fit_to_view();
+            app_state.set_status(format!("width: {}", app_state.view_width));
+            app.drawn_images.clear();
+            continue;
+        }
+
+        if let Event::Mouse(mouse) = event {
+            // Scrolling or clicking takes over from auto-scroll; moving the mouse doesn't
+            if !matches!(mouse.kind, MouseEventKind::Moved) {
+                app.auto_scroll = None;
+            }
+            let app_state = app.current_buffer();
+            if !app_state.show_help && !app_state.show_stats && !app_state.show_listing {
+                app_state.handle_mouse(mouse);
+                app_state.clamp_cursor();
+            }
+            continue;
+        }
+
+        if let Event::Key(key) = event {
+            // Ctrl+c quits from anywhere, without asking
+            if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                break;
+            }
+            let action = app.key_bindings.action_for(&key);
+            // Any other key pauses auto-scroll, leaving the view where it stopped
+            if action != Some(Action::AutoScroll) {
+                app.auto_scroll = None;
+            }
+
+            // While the command palette is open, keys filter it and pick an action
+            if let Some(palette) = app.palette.as_mut() {
+                let last = palette.matches().len().saturating_sub(1);
+                match key.code {
+                    KeyCode::Esc => app.palette = None,
+                    KeyCode::Up => palette.selected = palette.selected.saturating_sub(1),
+                    KeyCode::Down => palette.selected = (palette.selected + 1).min(last),
+                    KeyCode::Backspace => {
+                        palette.query.pop();
+                        palette.selected = 0;
+                    }
+                    KeyCode::Char(c) => {
+                        palette.query.push(c);
+                        palette.selected = 0;
+                    }
+                    KeyCode::Enter => {
+                        let palette = app.palette.take().unwrap_or_default();
+                        if let Some(line) = palette.line() {
+                            app.current_buffer().jump_to_line(line);
+                        } else if let Some(&action) = palette.matches().get(palette.selected)
+                            && !app.perform_action(action, None)
+                        {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            // While the heading finder is open, keys filter it and pick a heading
+            if let Some(finder) = app.finder.as_mut() {
+                let toc = &app.buffers[app.current].toc;
+                let last = finder.matches.len().saturating_sub(1);
+                match key.code {
+                    KeyCode::Esc => app.finder = None,
+                    KeyCode::Up => finder.selected = finder.selected.saturating_sub(1),
+                    KeyCode::Down => finder.selected = (finder.selected + 1).min(last),
+                    KeyCode::Backspace => {
+                        finder.query.pop();
+                        finder.update(toc);
+                    }
+                    KeyCode::Char(c) => {
+                        finder.query.push(c);
+                        finder.update(toc);
+                    }
+                    KeyCode::Enter => {
+                        let finder = app.finder.take().unwrap_or_default();
+                        if let Some(&(index, _)) = finder.matches.get(finder.selected) {
+                            app.current_buffer().focus_heading(index);
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            let app_state = app.current_buffer();
+
+            if app_state.show_help {
+                if let Some(action) = action {
+                    app_state.handle_help_action(action);
+                }
+                continue;
+            }
+
+            // Any key dismisses the statistics popup
+            if app_state.show_stats {
+                app_state.show_stats = false;
+                continue;
+            }
+
+            // Messages go at the next keypress, if they haven't timed out already
+            app_state.status_message = None;
+
+            // While an export file name is being typed, keys edit it
+            if let Some(input) = app_state.export_input.as_mut() {
+                match key.code {
+                    KeyCode::Enter => app_state.confirm_export(),
+                    KeyCode::Esc => app_state.export_input = None,
+                    KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    KeyCode::Char(c) => input.push(c),
+                    _ => {}
+                }
+                continue;
+            }
+
+            // While a command to pipe to is being typed, keys edit it
+            if let Some(input) = app_state.pipe_input.as_mut() {
+                match key.code {
+                    KeyCode::Enter => app.confirm_pipe(),
+                    KeyCode::Esc => app_state.pipe_input = None,
+                    KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    KeyCode::Char(c) => input.push(c),
+                    _ => {}
+                }
+                continue;
+            }
+
+            // While a search is being typed, keys edit the query
+            if app_state.search_input.is_some() {
+                match key.code {
+                    KeyCode::Enter => app_state.confirm_search(),
+                    KeyCode::Esc => app_state.cancel_search(),
+                    KeyCode::Backspace => {
+                        if app_state.search_input.as_ref().is_some_and(|input| input.query.is_empty()) {
+                            app_state.cancel_search();
+                        } else {
+                            app_state.edit_search(|query| {
+                                query.pop();
+                            });
+                        }
+                    }
+                    KeyCode::Char(c) => app_state.edit_search(|query| query.push(c)),
+                    _ => {}
+                }
+                continue;
+            }
+
+            // The key after `m`, `'`, `z`, `]` or `[` completes the command, and
+            // `y` confirms quitting when asked
+            if let Some(command) = app_state.pending_command.take() {
+                if command == PendingCommand::Quit {
+                    if key.code == KeyCode::Char('y') {
+                        break;
+                    }
+                } else if let KeyCode::Char(c) = key.code {
+                    app_state.complete_pending_command(command, c);
+                }
+                continue;
+            }
+
+            // Digits build up a count prefix, except a leading '0' which acts like
+            // Home. A digit bound to an action, like `1` for rendered view, starts
+            // a count too, but runs its action if no other key follows soon.
+            if let KeyCode::Char(c) = key.code
+                && let Some(digit) = c.to_digit(10)
+                && !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+                && (digit != 0 || app_state.pending_count.is_some())
+            {
+                if app_state.pending_count.is_none() && !app_state.show_listing {
+                    app.digit_action = action.map(|action| (action, Instant::now()));
+                }
+                app.current_buffer().push_count_digit(digit);
+                continue;
+            }
+
+            let count = app_state.pending_count.take();
+            let step = count.unwrap_or(1);
+
+            // While a directory listing is shown, keys pick a file from it
+            if app_state.show_listing
+                && let Some(listing) = app_state.listing.as_mut()
+            {
+                let last = listing.files.len().saturating_sub(1);
+                match (key.code, action) {
+                    (KeyCode::Enter, _) => app.open_listed_file(),
+                    (_, Some(Action::ScrollUp)) => listing.selected = listing.selected.saturating_sub(step),
+                    (_, Some(Action::ScrollDown)) => listing.selected = (listing.selected + step).min(last),
+                    (_, Some(Action::Top)) => listing.selected = 0,
+                    (_, Some(Action::Bottom)) => listing.selected = last,
+                    (_, Some(Action::Listing)) => app_state.toggle_listing(),
+                    (_, Some(Action::NextFile)) => app.next_buffer(),
+                    (_, Some(Action::PreviousFile)) => app.previous_buffer(),
+                    (_, Some(Action::Help)) => {
+                        app_state.show_help = true;
+                        app_state.help_scroll = 0;
+                    }
+                    (_, Some(Action::Quit)) if !app.perform_action(Action::Quit, None) => break,
+                    _ => {}
+                }
+                continue;
+            }
+
+            // While the table of contents is open, scrolling and Enter drive the selection
+            if app_state.show_toc {
+                let last = app_state.toc.len().saturating_sub(1);
+                match (key.code, action) {
+                    (_, Some(Action::ScrollUp)) => {
+                        app_state.toc_selected = app_state.toc_selected.saturating_sub(step);
+                        continue;
+                    }
+                    (_, Some(Action::ScrollDown)) => {
+                        app_state.toc_selected = (app_state.toc_selected + step).min(last);
+                        continue;
+                    }
+                    (KeyCode::Enter, _) => {
+                        app_state.jump_to_toc_entry();
+                        continue;
+                    }
+                    (KeyCode::Esc, _) => {
+                        app_state.show_toc = false;
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+
+            // Esc drops a selection before it would quit
+            if key.code == KeyCode::Esc && app_state.selection_anchor.is_some() {
+                app_state.selection_anchor = None;
+                continue;
+            }
+
+            let Some(action) = action else {
+                continue;
+            };
+
+            if !app.perform_action(action, count) {
+                break;
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+/// Give lines matching the active search the search background and show
+/// selected lines reversed. `start` is the view position of the first line.
+fn highlight_view_lines(
+    lines: Vec<Line<'static>>,
+    app_state: &AppState,
+    start: usize,
+    theme: &Theme,
+) -> Vec<Line<'static>> {
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(index, line)| {
+            let position = start + index;
+            let line = if app_state.is_search_match(position) {
+                // Matches styling hides, like in link destinations, mark the whole line
+                let highlight = theme.background(theme.search_match);
+                let highlighted = highlight_matches(line.clone(), &app_state.search_query, highlight);
+                if highlighted == line { line.patch_style(highlight) } else { highlighted }
+            } else {
+                line
+            };
+            match app_state.selected_range() {
+                Some((first, last)) if (first..=last).contains(&position) => {
+                    line.patch_style(Style::default().add_modifier(Modifier::REVERSED))
+                }
+                _ => line,
+            }
+        })
+        .collect()
+}
+
+/// Give whole-word occurrences of `keywords` a highlighted background
+fn highlight_keywords(lines: Vec<Line<'static>>, keywords: &[String], theme: &Theme) -> Vec<Line<'static>> {
+    if keywords.is_empty() {
+        return lines;
+    }
+    let highlight = theme.background(theme.todo).add_modifier(Modifier::BOLD);
+    let highlight = if theme.monochrome { highlight } else { highlight.fg(Color::Black) };
+    lines
+        .into_iter()
+        .map(|line| {
+            let Line { style, alignment, spans } = line;
+            let spans = spans
+                .into_iter()
+                .flat_map(|span| split_keywords(span, keywords, highlight))
+                .collect();
+            Line { style, alignment, spans }
+        })
+        .collect()
+}
+
+/// Split a span around the keywords in it, styling each keyword with `highlight`
+fn split_keywords(span: Span<'static>, keywords: &[String], highlight: Style) -> Vec<Span<'static>> {
+    let text = span.content.as_ref();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut pieces = Vec::new();
+    let mut piece_start = 0;
+    let mut index = 0;
+    while index < text.len() {
+        let starts_word = !text[..index].chars().next_back().is_some_and(is_word);
+        let keyword = keywords.iter().find(|keyword| {
+            let end = index + keyword.len();
+            starts_word
+                && text[index..].starts_with(keyword.as_str())
+                && !text[end..].chars().next().is_some_and(is_word)
+        });
+        match keyword {
+            Some(keyword) => {
+                if piece_start < index {
+                    pieces.push(Span::styled(text[piece_start..index].to_string(), span.style));
+                }
+                pieces.push(Span::styled(keyword.clone(), span.style.patch(highlight)));
+                index += keyword.len();
+                piece_start = index;
+            }
+            None => index += text[index..].chars().next().map_or(1, char::len_utf8),
+        }
+    }
+
+    if pieces.is_empty() {
+        return vec![span];
+    }
+    if piece_start < text.len() {
+        pieces.push(Span::styled(text[piece_start..].to_string(), span.style));
+    }
+    pieces
+}
+
+fn render_single_view(
+    frame: &mut Frame,
+    app_state: &mut AppState,
+    area: ratatui::layout::Rect,
+    theme: &Theme,
+    todo_keywords: &[String],
+    ruler: Option<usize>,
+) {
+    let total_lines = app_state.line_count();
+    let visible_lines = area.height as usize;
+    app_state.view_height = visible_lines.saturating_sub(2);
+    
+    let gutter_width = app_state.gutter_width();
+    app_state.scrollbar_shown = match app_state.scrollbar {
+        ScrollbarMode::Always => true,
+        ScrollbarMode::Never => false,
+        ScrollbarMode::Auto => {
+            let width = area.width.saturating_sub(2 + gutter_width) as usize;
+            !app_state.fits_in_view(theme, width, width)
+        }
+    };
+    let (area, scrollbar_area) = app_state.scrollbar_columns(area);
+    let inner_width = area.width.saturating_sub(2 + gutter_width) as usize;
+    app_state.view_width = inner_width;
+    app_state.measure_rows(theme, inner_width, inner_width);
+    app_state.apply_pending_bottom();
+    
+    let start_line = app_state.scroll_offset;
+    let end_line = (start_line + visible_lines).min(total_lines);
+    
+    // Create visible content - markdown styling in Rendered mode, syntax highlighting
+    // for recognized source files, plain text otherwise
+    let lines = if app_state.view_mode == ViewMode::Rendered {
+        app_state.styled_window(theme, inner_width, start_line, end_line)
+    } else if let Some(highlighter) = app_state.highlighter.as_mut() {
+        let (content, source_index, tab_width) = (&app_state.content, &app_state.source_index, app_state.tab_width);
+        let source_line = |index| expand_tabs(source_index.line(content, index), tab_width);
+        highlighter.lines(start_line, end_line, source_line).to_vec()
+    } else {
+        app_state
+            .source_window(start_line, end_line)
+            .into_iter()
+            .map(Line::from)
+            .collect()
+    };
+    let block = Block::default().borders(Borders::ALL).border_style(Style::default().fg(theme.border));
+    let inner = block.inner(area);
+    let (gutter, inner) = (
+        Rect { width: gutter_width.min(inner.width), ..inner },
+        Rect { x: inner.x + gutter_width.min(inner.width), width: inner.width.saturating_sub(gutter_width), ..inner },
+    );
+    let rendered = app_state.view_mode == ViewMode::Rendered;
+    let rows: Vec<usize> = lines
+        .iter()
+        .map(|line| match rendered {
+            true => app_state.rendered_rows(line, inner_width),
+            false => wrapped_rows(line, inner_width),
+        })
+        .collect();
+    let number_width = app_state.number_width().min(gutter.width);
+    if number_width > 0 {
+        draw_line_numbers(frame, app_state, &rows, start_line, Rect { width: number_width, ..gutter }, theme);
+    }
+    if app_state.wrap_markers {
+        let markers = Rect { x: gutter.x + number_width, width: gutter.width - number_width, ..gutter };
+        draw_wrap_markers(frame, &rows, markers, Symbols::get(app_state.ascii), theme);
+    }
+    if rendered {
+        app_state.image_slots = place_images(app_state, &rows, start_line, inner, true);
+    }
+    highlight_cursor_row(frame, &rows, start_line, app_state.cursor_line, inner, theme);
+    let lines = highlight_keywords(lines, todo_keywords, theme);
+    let lines = match app_state.trailing_whitespace && !rendered {
+        true => lines.into_iter().map(|line| mark_trailing_whitespace(line, Symbols::get(app_state.ascii), theme)).collect(),
+        false => lines,
+    };
+    let ruler = ruler.filter(|_| app_state.view_mode == ViewMode::Source);
+    let lines = match ruler {
+        Some(column) => lines.into_iter().map(|line| mark_overflow(line, column, theme)).collect(),
+        None => lines,
+    };
+    let lines = highlight_view_lines(lines, app_state, start_line, theme);
+    let lines = if rendered { app_state.wrap_rendered(lines, inner_width) } else { lines };
+    // Wrapping puts a line of only spaces on two rows, throwing the gutter and
+    // cursor row out of line with the text, so those are drawn empty instead
+    let lines: Vec<Line> = lines
+        .into_iter()
+        .map(|line| match line.spans.iter().all(|span| span.content.trim().is_empty()) {
+            true => Line::default().style(line.style),
+            false => line,
+        })
+        .collect();
+    let visible_text = Text::from(lines);
+
+    let paragraph = Paragraph::new(visible_text).wrap(ratatui::widgets::Wrap { trim: false });
+
+    frame.render_widget(block, area);
+    frame.render_widget(paragraph, inner);
+    if let Some(column) = ruler {
+        draw_ruler(frame, inner, column, Symbols::get(app_state.ascii), theme);
+    }
+    if app_state.is_blank() {
+        draw_empty_placeholder(frame, inner, theme);
+    }
+
+    draw_scrollbar(frame, app_state, scrollbar_area, theme);
+}
+
+/// Number the first row of each line in the gutter, right-aligned, given the
+/// screen rows each line from `start` takes
+fn draw_line_numbers(frame: &mut Frame, app_state: &AppState, rows: &[usize], start: usize, gutter: Rect, theme: &Theme) {
+    let style = Style::default().fg(theme.footer).add_modifier(Modifier::DIM);
+    let digits = gutter.width.saturating_sub(1) as usize;
+    let buffer = frame.buffer_mut();
+    let mut row = 0;
+    for (index, &height) in rows.iter().enumerate() {
+        if row >= gutter.height {
+            break;
+        }
+        if let Some(number) = app_state.line_number(start + index) {
+            buffer.set_stringn(gutter.x, gutter.y + row, format!("{:>digits$}", number), digits, style);
+        }
+        row += height as u16;
+    }
+}
+
+/// Put a dim `↳` in the gutter beside every row after the first of each
+/// wrapped line, given the screen rows each line takes
+fn draw_wrap_markers(frame: &mut Frame, rows: &[usize], gutter: Rect, symbols: &Symbols, theme: &Theme) {
+    if gutter.width == 0 {
+        return;
+    }
+    let style = Style::default().fg(theme.footer).add_modifier(Modifier::DIM);
+    let buffer = frame.buffer_mut();
+    let mut row = 0;
+    for &height in rows {
+        let height = height as u16;
+        for continuation in row + 1..(row + height).min(gutter.height) {
+            buffer[(gutter.x, gutter.y + continuation)].set_symbol(symbols.continuation).set_style(style);
+        }
+        row += height;
+        if row >= gutter.height {
+            break;
+        }
+    }
+}
+
+/// Say so in the middle of `inner` when the file is empty or only whitespace,
+/// which would otherwise look like nothing was loaded
+fn draw_empty_placeholder(frame: &mut Frame, inner: Rect, theme: &Theme) {
+    let middle = Rect {
+        y: inner.y + inner.height / 2,
+        height: inner.height.min(1),
+        ..inner
+    };
+    let placeholder = Paragraph::new("(empty file)")
+        .style(Style::default().fg(theme.footer).add_modifier(Modifier::DIM))
+        .alignment(ratatui::layout::Alignment::Center);
+    frame.render_widget(placeholder, middle);
+}
+
+/// Split a one-column strip off the right of `area` for the scrollbar, lined
+/// up with the rows inside the border so it covers neither text nor borders
+fn split_scrollbar(area: Rect) -> (Rect, Rect) {
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(1), Constraint::Length(1)])
+        .split(area);
+    let strip = Rect {
+        y: columns[1].y + 1,
+        height: columns[1].height.saturating_sub(2),
+        ..columns[1]
+    };
+    (columns[0], strip)
+}
+
+/// Scrollbar position in screen rows, full once the last line reaches the bottom
+fn scrollbar_state(app_state: &AppState) -> ScrollbarState {
+    let bottom = app_state.rows_between(0, app_state.bottom_offset());
+    ScrollbarState::new(bottom + 1)
+        .position(app_state.rows_between(0, app_state.scroll_offset))
+        .viewport_content_length(app_state.view_height)
+}
+
+/// Draw the scrollbar in `strip` in its style, unless it is hidden
+fn draw_scrollbar(frame: &mut Frame, app_state: &AppState, strip: Rect, theme: &Theme) {
+    if strip.width == 0 {
+        return;
+    }
+    let symbols = Symbols::get(app_state.ascii);
+    let mut scrollbar_state = scrollbar_state(app_state);
+    let (begin, end) = match app_state.scrollbar_style {
+        ScrollbarStyle::Arrows => (Some(symbols.scroll_up), Some(symbols.scroll_down)),
+        ScrollbarStyle::Plain => (None, None),
+    };
+
+    let scrollbar = Scrollbar::default()
+        .orientation(ScrollbarOrientation::VerticalRight)
+        .begin_symbol(begin)
+        .end_symbol(end)
+        .track_symbol(Some(symbols.track))
+        .thumb_symbol(symbols.thumb);
+
+    frame.render_stateful_widget(scrollbar, strip, &mut scrollbar_state);
+    draw_minimap(frame, app_state, strip, theme);
+}
+
+/// Tick the scrollbar track in `strip` where headings and search matches are,
+/// leaving the thumb as it is
+fn draw_minimap(frame: &mut Frame, app_state: &AppState, strip: Rect, theme: &Theme) {
+    let track = app_state.scrollbar_track(strip);
+    let track_len = track.height;
+    if strip.width == 0 || track_len == 0 {
+        return;
+    }
+    let symbols = Symbols::get(app_state.ascii);
+    let matches = app_state.search_matches.iter().map(|&line| (line, symbols.match_tick, theme.minimap_match));
+    let headings = app_state.heading_positions().map(|(line, _)| (line, symbols.heading_tick, theme.minimap_heading));
+    let buffer = frame.buffer_mut();
+    // Headings come last so they stay visible where a match shares their row
+    for (line, symbol, color) in matches.chain(headings) {
+        let cell = &mut buffer[(strip.x, track.y + app_state.track_row(line, track_len))];
+        if cell.symbol() != symbols.thumb {
+            cell.set_symbol(symbol).set_fg(color);
+        }
+    }
+}
+
+/// Screen rows `line` takes when wrapped to `width`
+fn wrapped_rows(line: &Line, width: usize) -> usize {
+    if line.width() <= width {
+        return 1;
+    }
+    Paragraph::new(line.clone())
+        .wrap(ratatui::widgets::Wrap { trim: false })
+        .line_count(width as u16)
+        .max(1)
+}
+
+/// Break `line` between words into rows no wider than `width`, splitting only
+/// words too long for a row. Rows after the first are indented to line up with
+/// the text after any list bullet, and repeat the bars of quotes and code boxes.
+fn wrap_words(line: &Line<'static>, width: usize, symbols: &Symbols) -> Vec<Line<'static>> {
+    if width == 0 || line.width() <= width {
+        return vec![line.clone()];
+    }
+    let text: String = line.spans.iter().map(|span| span.content.as_ref()).collect();
+
+    // The indentation, bars and bullet leading the text
+    let mut prefix = 0;
+    loop {
+        let rest = &text[prefix..];
+        let trimmed = rest.trim_start_matches(' ');
+        prefix += rest.len() - trimmed.len();
+        if let Some(after) = trimmed.strip_prefix(symbols.vertical).and_then(|after| after.strip_prefix(' ')) {
+            prefix = text.len() - after.len();
+        } else if let Some(after) = trimmed.strip_prefix(symbols.bullet).and_then(|after| after.strip_prefix(' ')) {
+            prefix = text.len() - after.len();
+            break;
+        } else {
+            break;
+        }
+    }
+    let mut indent_width = text[..prefix].width();
+    if indent_width * 2 > width {
+        prefix = 0;
+        indent_width = 0;
+    }
+
+    let body = &text[prefix..];
+    let words = textwrap::WordSeparator::AsciiSpace.find_words(body);
+    let words = textwrap::core::break_words(words, width - indent_width);
+    let rows = textwrap::wrap_algorithms::wrap_first_fit(&words, &[(width - indent_width) as f64]);
+
+    // Continuation rows keep the bars of the prefix, in their styles
+    let mut indent = Vec::new();
+    let mut offset = 0;
+    for span in &line.spans {
+        for (index, c) in span.content.char_indices() {
+            if offset + index >= prefix {
+                break;
             }
+            let (symbol, style) = match symbols.vertical.starts_with(c) {
+                true => (c.to_string(), span.style),
+                false => (" ".repeat(c.width().unwrap_or(0)), Style::default()),
+            };
+            indent.push(Span::styled(symbol, style));
         }
+        offset += span.content.len();
     }
+
+    let word_offset = |word: &str| word.as_ptr() as usize - body.as_ptr() as usize + prefix;
+    let wrapped: Vec<Line<'static>> = rows
+        .iter()
+        .enumerate()
+        .filter_map(|(index, words)| {
+            let (first, last) = (words.first()?, words.last()?);
+            let start = if index == 0 { 0 } else { word_offset(first.word) };
+            let end = word_offset(last.word) + last.word.len();
+            let mut spans = if index == 0 { Vec::new() } else { indent.clone() };
+            spans.extend(slice_spans(&line.spans, start, end));
+            Some(Line { style: line.style, alignment: line.alignment, spans })
+        })
+        .collect();
+    if wrapped.is_empty() {
+        return vec![line.clone()];
+    }
+    wrapped
 }
 
-fn main() -> Result<()> {
-    color_eyre::install()?;
-    
-    let args = Args::parse();
-    let app_state = AppState::new(args.file)?;
-    
-    // Check if we're in an interactive terminal
-    if !atty::is(atty::Stream::Stdout) {
-        eprintln!("Error: mess requires an interactive terminal");
-        std::process::exit(1);
+/// Show the spaces ending `line` (tabs are expanded by now) as dots on the
+/// trailing whitespace color, content before them kept as it was
+fn mark_trailing_whitespace(line: Line<'static>, symbols: &Symbols, theme: &Theme) -> Line<'static> {
+    let text: String = line.spans.iter().map(|span| span.content.as_ref()).collect();
+    let content_end = text.trim_end_matches(' ').len();
+    if content_end == text.len() {
+        return line;
     }
-    
-    // Initialize terminal using proper Ratatui pattern with alternate screen
-    crossterm::terminal::enable_raw_mode()?;
-    execute!(io::stdout(), EnterAlternateScreen)?;
-    
-    let backend = CrosstermBackend::new(io::stdout());
-    let mut terminal = Terminal::new(backend)?;
-    
-    let result = run(&mut terminal, app_state);
-    
-    // Restore terminal - this is critical for proper cleanup like "less"
-    execute!(io::stdout(), LeaveAlternateScreen)?;
-    crossterm::terminal::disable_raw_mode()?;
-    
-    result
+    let Line { style, alignment, spans } = line;
+    let mut marked = slice_spans(&spans, 0, content_end);
+    let tail = symbols.space.repeat(text.len() - content_end);
+    marked.push(Span::styled(tail, theme.background(theme.trailing_whitespace)));
+    Line { style, alignment, spans: marked }
 }
 
-fn run(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, mut app_state: AppState) -> Result<()> {
-    loop {
-        terminal.draw(|f| render(f, &mut app_state))?;
-        
-        if let Event::Key(key) = event::read()? {
-            match key.code {
-                KeyCode::Char('q') | KeyCode::Esc => break,
-                KeyCode::Tab => app_state.toggle_view_mode(),
-                KeyCode::Up => app_state.scroll_up(1),
-                KeyCode::Down => {
-                    let content_lines = app_state.get_content_lines();
-                    app_state.scroll_down(1, content_lines.len());
-                }
-                KeyCode::PageUp => app_state.scroll_up(10),
-                KeyCode::PageDown => {
-                    let content_lines = app_state.get_content_lines();
-                    app_state.scroll_down(10, content_lines.len());
-                }
-                KeyCode::Home => app_state.scroll_offset = 0,
-                KeyCode::End => {
-                    let content_lines = app_state.get_content_lines();
-                    app_state.scroll_offset = content_lines.len().saturating_sub(1);
-                }
-                KeyCode::Char('h') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                    show_help(terminal)?;
-                    continue;
-                }
-                _ => {}
+/// Give the text of `line` past display column `column` the overflow color
+fn mark_overflow(line: Line<'static>, column: usize, theme: &Theme) -> Line<'static> {
+    let overflow = Style::default().fg(theme.overflow);
+    let Line { style, alignment, spans } = line;
+    let mut width = 0;
+    let mut marked = Vec::with_capacity(spans.len());
+    for span in spans {
+        if width >= column {
+            marked.push(Span::styled(span.content, span.style.patch(overflow)));
+            continue;
+        }
+        let span_width = span.content.width();
+        if width + span_width <= column {
+            width += span_width;
+            marked.push(span);
+            continue;
+        }
+
+        // The span crosses the ruler, so split it at the first character past it
+        let text = span.content.as_ref();
+        let mut split = text.len();
+        for (index, c) in text.char_indices() {
+            if width + c.width().unwrap_or(0) > column {
+                split = index;
+                break;
             }
+            width += c.width().unwrap_or(0);
         }
+        marked.push(Span::styled(text[..split].to_string(), span.style));
+        marked.push(Span::styled(text[split..].to_string(), span.style.patch(overflow)));
+        width = column;
     }
-    
-    Ok(())
+    Line { style, alignment, spans: marked }
 }
 
-fn render_single_view(frame: &mut Frame, app_state: &AppState, area: ratatui::layout::Rect) {
-    let content_lines = app_state.get_content_lines();
-    let visible_lines = area.height as usize;
-    
-    let start_line = app_state.scroll_offset;
-    let end_line = (start_line + visible_lines).min(content_lines.len());
-    
-    // Create visible content - apply styling only in Rendered mode
-    let visible_text = if start_line < content_lines.len() {
-        let lines: Vec<Line> = content_lines[start_line..end_line]
-            .iter()
-            .map(|line| {
-                // Only apply styling for Rendered view
-                if matches!(app_state.view_mode, ViewMode::Rendered) {
-                    // Apply basic styling for markdown elements
-                    let mut spans = Vec::new();
-                    let mut remaining = line.as_str();
-                    
-                    while !remaining.is_empty() {
-                        if remaining.starts_with("**") {
-                            // Bold text
-                            if let Some(end) = remaining[2..].find("**") {
-                                let text = &remaining[2..end + 2];
-                                spans.push(Span::styled(text, Style::default().add_modifier(Modifier::BOLD)));
-                                remaining = &remaining[end + 4..];
-                            } else {
-                                spans.push(Span::raw(remaining));
-                                break;
-                            }
-                        } else if remaining.starts_with("*") {
-                            // Italic text
-                            if let Some(end) = remaining[1..].find("*") {
-                                let text = &remaining[1..end + 1];
-                                spans.push(Span::styled(text, Style::default().add_modifier(Modifier::ITALIC)));
-                                remaining = &remaining[end + 2..];
-                            } else {
-                                spans.push(Span::raw(remaining));
-                                break;
-                            }
-                        } else if remaining.starts_with("`") {
-                            // Code text
-                            if let Some(end) = remaining[1..].find("`") {
-                                let text = &remaining[1..end + 1];
-                                spans.push(Span::styled(text, Style::default().fg(Color::Yellow)));
-                                remaining = &remaining[end + 2..];
-                            } else {
-                                spans.push(Span::raw(remaining));
-                                break;
-                            }
-                        } else if remaining.starts_with("#") {
-                            // Headers
-                            let header_level = remaining.chars().take_while(|&c| c == '#').count();
-                            if header_level > 0 && remaining.len() > header_level && remaining.chars().nth(header_level) == Some(' ') {
-                                let text = &remaining[header_level + 1..];
-                                spans.push(Span::styled(text, Style::default().add_modifier(Modifier::BOLD)));
-                                remaining = "";
-                            } else {
-                                spans.push(Span::raw(remaining));
-                                break;
-                            }
-                        } else {
-                            // Regular text
-                            let next_special = remaining.find(|c| c == '*' || c == '`' || c == '#').unwrap_or(remaining.len());
-                            spans.push(Span::raw(&remaining[..next_special]));
-                            remaining = &remaining[next_special..];
-                        }
-                    }
-                    
-                    Line::from(spans)
-                } else {
-                    // For Source view, show raw text without styling
-                    Line::from(line.as_str())
-                }
-            })
-            .collect();
-        Text::from(lines)
-    } else {
-        Text::default()
+/// Draw a dim vertical guide down `inner` at `column`, behind any text there
+fn draw_ruler(frame: &mut Frame, inner: Rect, column: usize, symbols: &Symbols, theme: &Theme) {
+    let Some(x) = u16::try_from(column).ok().and_then(|column| inner.x.checked_add(column)) else {
+        return;
     };
+    if x >= inner.right() {
+        return;
+    }
+    let buffer = frame.buffer_mut();
+    for y in inner.top()..inner.bottom() {
+        if let Some(cell) = buffer.cell_mut((x, y))
+            && cell.symbol() == " "
+        {
+            cell.set_symbol(symbols.vertical).set_fg(theme.ruler);
+        }
+    }
+}
 
-    let paragraph = Paragraph::new(visible_text)
-        .block(Block::default().borders(Borders::ALL))
-        .wrap(ratatui::widgets::Wrap { trim: false });
+/// Give the screen rows of the cursor line the cursor background, across the
+/// whole pane so it shows on blank lines too. Text drawn afterwards keeps it.
+fn highlight_cursor_row(frame: &mut Frame, rows: &[usize], start: usize, cursor: usize, inner: Rect, theme: &Theme) {
+    let Some(index) = cursor.checked_sub(start).filter(|&index| index < rows.len()) else {
+        return;
+    };
+    let row: usize = rows[..index].iter().sum();
+    if row >= inner.height as usize {
+        return;
+    }
+    let height = rows[index].clamp(1, inner.height as usize - row);
+    let area = Rect {
+        y: inner.y + row as u16,
+        height: height as u16,
+        ..inner
+    };
+    frame.buffer_mut().set_style(area, Style::default().bg(theme.cursor_line));
+}
 
-    frame.render_widget(paragraph, area);
+/// Screen areas for the images captioned among the view lines from `start`,
+/// which take `rows` screen rows each inside `inner`. Images are only placed when all of their
+/// reserved rows are in view. `folded` says whether collapsed sections are hidden.
+fn place_images(app_state: &AppState, rows: &[usize], start: usize, inner: Rect, folded: bool) -> Vec<ImageSlot> {
+    let mut slots = Vec::new();
+    if app_state.images.is_empty() {
+        return slots;
+    }
 
-    // Scrollbar
-    let total_lines = content_lines.len();
-    let mut scrollbar_state = ScrollbarState::new(total_lines)
-        .position(app_state.scroll_offset);
-    
-    let scrollbar = Scrollbar::default()
-        .orientation(ScrollbarOrientation::VerticalRight)
-        .begin_symbol(Some("↑"))
-        .end_symbol(Some("↓"));
-    
-    frame.render_stateful_widget(scrollbar, area, &mut scrollbar_state);
+    let mut row = 0;
+    for (index, height) in rows.iter().enumerate() {
+        if row >= inner.height as usize {
+            break;
+        }
+        let position = start + index;
+        let rendered_line = if folded { app_state.rendered_line_at(position) } else { position };
+        let image = app_state.images.iter().find(|image| image.rendered_line == rendered_line);
+        if let Some(image) = image
+            && index + IMAGE_ROWS < rows.len()
+            && row + 1 + IMAGE_ROWS <= inner.height as usize
+        {
+            // Quoted images are indented past the quote bars
+            let text = app_state.rendered_index.line(&app_state.rendered_content, rendered_line);
+            let indent = (split_quote_prefix(text).0 * 2) as u16;
+            slots.push(ImageSlot {
+                path: image.path.clone(),
+                area: Rect {
+                    x: inner.x + indent.min(inner.width),
+                    y: inner.y + row as u16 + 1,
+                    width: inner.width.saturating_sub(indent),
+                    height: IMAGE_ROWS as u16,
+                },
+            });
+        }
+        row += height;
+    }
+    slots
 }
 
-fn render_side_by_side(frame: &mut Frame, app_state: &AppState, area: ratatui::layout::Rect) {
-    // Split the content area into two columns
-    let columns = Layout::default()
-        .direction(Direction::Horizontal)
-        .constraints([
-            Constraint::Percentage(50),
-            Constraint::Percentage(50),
-        ])
-        .split(area);
-    
-    let rendered_lines: Vec<String> = app_state.rendered_content.lines().map(|s| s.to_string()).collect();
-    let source_lines: Vec<String> = app_state.content.lines().map(|s| s.to_string()).collect();
-    
-    let visible_lines = area.height as usize;
+fn render_side_by_side(
+    frame: &mut Frame,
+    app_state: &mut AppState,
+    area: ratatui::layout::Rect,
+    theme: &Theme,
+    todo_keywords: &[String],
+) {
+    // Split the content area into two columns, or two rows when stacked
+    let direction = match app_state.split_orientation(area.width) {
+        SplitOrientation::Horizontal => Direction::Vertical,
+        SplitOrientation::Vertical => Direction::Horizontal,
+    };
+    let split_ratio = app_state.split_ratio;
+    let panes = |area: Rect| {
+        Layout::default()
+            .direction(direction)
+            .constraints([Constraint::Percentage(split_ratio), Constraint::Percentage(100 - split_ratio)])
+            .split(area)
+    };
+    // Both panes scroll together, so show as many lines as the shorter one fits
+    let pane_lines = |columns: &[Rect]| columns[0].height.min(columns[1].height).saturating_sub(2) as usize;
+
+    app_state.scrollbar_shown = match app_state.scrollbar {
+        ScrollbarMode::Always => true,
+        ScrollbarMode::Never => false,
+        ScrollbarMode::Auto => {
+            let columns = panes(area);
+            app_state.view_height = pane_lines(&columns);
+            let (width, source_width) = (columns[0].width.saturating_sub(2), columns[1].width.saturating_sub(2));
+            !app_state.fits_in_view(theme, width as usize, source_width as usize)
+        }
+    };
+    let (area, scrollbar_area) = app_state.scrollbar_columns(area);
+    let columns = panes(area);
+    let visible_lines = pane_lines(&columns);
+    app_state.view_height = visible_lines;
+    let rendered_width = columns[0].width.saturating_sub(2) as usize;
+    app_state.view_width = rendered_width;
+    app_state.measure_rows(theme, rendered_width, columns[1].width.saturating_sub(2) as usize);
+    app_state.apply_pending_bottom();
     let start_line = app_state.scroll_offset;
-    let end_line_rendered = (start_line + visible_lines).min(rendered_lines.len());
-    let end_line_source = (start_line + visible_lines).min(source_lines.len());
+    let end_line = start_line + visible_lines;
+    if app_state.diff.is_some() {
+        render_diff_panes(frame, app_state, &columns, start_line, end_line, theme);
+        draw_scrollbar(frame, app_state, scrollbar_area, theme);
+        return;
+    }
     
     // Left panel - Rendered view with styling
-    let left_text = if start_line < rendered_lines.len() {
-        let lines: Vec<Line> = rendered_lines[start_line..end_line_rendered]
-            .iter()
-            .map(|line| {
-                // Apply styling for rendered view
-                let mut spans = Vec::new();
-                let mut remaining = line.as_str();
-                
-                while !remaining.is_empty() {
-                    if remaining.starts_with("**") {
-                        if let Some(end) = remaining[2..].find("**") {
-                            let text = &remaining[2..end + 2];
-                            spans.push(Span::styled(text, Style::default().add_modifier(Modifier::BOLD)));
-                            remaining = &remaining[end + 4..];
-                        } else {
-                            spans.push(Span::raw(remaining));
-                            break;
-                        }
-                    } else if remaining.starts_with("*") {
-                        if let Some(end) = remaining[1..].find("*") {
-                            let text = &remaining[1..end + 1];
-                            spans.push(Span::styled(text, Style::default().add_modifier(Modifier::ITALIC)));
-                            remaining = &remaining[end + 2..];
-                        } else {
-                            spans.push(Span::raw(remaining));
-                            break;
-                        }
-                    } else if remaining.starts_with("`") {
-                        if let Some(end) = remaining[1..].find("`") {
-                            let text = &remaining[1..end + 1];
-                            spans.push(Span::styled(text, Style::default().fg(Color::Yellow)));
-                            remaining = &remaining[end + 2..];
-                        } else {
-                            spans.push(Span::raw(remaining));
-                            break;
-                        }
-                    } else if remaining.starts_with("#") {
-                        let header_level = remaining.chars().take_while(|&c| c == '#').count();
-                        if header_level > 0 && remaining.len() > header_level && remaining.chars().nth(header_level) == Some(' ') {
-                            let text = &remaining[header_level + 1..];
-                            spans.push(Span::styled(text, Style::default().add_modifier(Modifier::BOLD)));
-                            remaining = "";
-                        } else {
-                            spans.push(Span::raw(remaining));
-                            break;
-                        }
-                    } else {
-                        let next_special = remaining.find(|c| c == '*' || c == '`' || c == '#').unwrap_or(remaining.len());
-                        spans.push(Span::raw(&remaining[..next_special]));
-                        remaining = &remaining[next_special..];
-                    }
-                }
-                
-                Line::from(spans)
-            })
-            .collect();
-        Text::from(lines)
-    } else {
-        Text::default()
-    };
+    let rendered_lines = app_state.styled_rendered_lines(theme, rendered_width, start_line, end_line).to_vec();
+    let rendered_inner = Block::default().borders(Borders::ALL).inner(columns[0]);
+    let rendered_rows: Vec<usize> = rendered_lines.iter().map(|line| app_state.rendered_rows(line, rendered_width)).collect();
+    app_state.image_slots = place_images(app_state, &rendered_rows, start_line, rendered_inner, false);
+    highlight_cursor_row(frame, &rendered_rows, start_line, app_state.cursor_line, rendered_inner, theme);
+    let rendered_lines = highlight_keywords(rendered_lines, todo_keywords, theme);
+    let rendered_lines = highlight_view_lines(rendered_lines, app_state, start_line, theme);
+    let left_text = Text::from(app_state.wrap_rendered(rendered_lines, rendered_width));
     
     // Right panel - Source view (raw text)
-    let right_text = if start_line < source_lines.len() {
-        Text::from(source_lines[start_line..end_line_source].join("\n"))
-    } else {
-        Text::default()
-    };
+    let source_lines: Vec<Line> = app_state.source_window(start_line, end_line).into_iter().map(Line::from).collect();
+    let source_inner = Block::default().borders(Borders::ALL).inner(columns[1]);
+    let source_width = source_inner.width as usize;
+    let source_rows: Vec<usize> = source_lines.iter().map(|line| wrapped_rows(line, source_width)).collect();
+    highlight_cursor_row(frame, &source_rows, start_line, app_state.cursor_line, source_inner, theme);
+    let right_text = Text::from(highlight_keywords(source_lines, todo_keywords, theme));
     
     let left_paragraph = Paragraph::new(left_text)
-        .block(Block::default().borders(Borders::ALL).title("Rendered"))
+        .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(theme.border)).title("Rendered"))
         .wrap(ratatui::widgets::Wrap { trim: false });
     
     let right_paragraph = Paragraph::new(right_text)
-        .block(Block::default().borders(Borders::ALL).title("Source"))
+        .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(theme.border)).title("Source"))
         .wrap(ratatui::widgets::Wrap { trim: false });
     
     frame.render_widget(left_paragraph, columns[0]);
     frame.render_widget(right_paragraph, columns[1]);
+    if app_state.is_blank() {
+        draw_empty_placeholder(frame, rendered_inner, theme);
+        draw_empty_placeholder(frame, source_inner, theme);
+    }
     
     // Scrollbar for the whole area
-    let max_lines = rendered_lines.len().max(source_lines.len());
-    let mut scrollbar_state = ScrollbarState::new(max_lines)
-        .position(app_state.scroll_offset);
-    
-    let scrollbar = Scrollbar::default()
-        .orientation(ScrollbarOrientation::VerticalRight)
-        .begin_symbol(Some("↑"))
-        .end_symbol(Some("↓"));
-    
-    frame.render_stateful_widget(scrollbar, area, &mut scrollbar_state);
+    draw_scrollbar(frame, app_state, scrollbar_area, theme);
+}
+
+/// Draw a `--diff` in `columns`, the old file beside the new one. Each row
+/// takes the height of its taller side, so changes stay across from each other.
+fn render_diff_panes(frame: &mut Frame, app_state: &AppState, columns: &[Rect], start: usize, end: usize, theme: &Theme) {
+    let Some(diff) = &app_state.diff else {
+        return;
+    };
+    let old_inner = Block::default().borders(Borders::ALL).inner(columns[0]);
+    let new_inner = Block::default().borders(Borders::ALL).inner(columns[1]);
+    let change_style = |color: Color| match theme.monochrome {
+        true => Style::default().add_modifier(Modifier::REVERSED),
+        false => Style::default().fg(color),
+    };
+
+    let (mut old_text, mut new_text, mut rows) = (Vec::new(), Vec::new(), Vec::new());
+    for index in start..end.min(app_state.line_count()) {
+        let (old, new) = app_state.diff_lines(index);
+        let (old_style, new_style) = match diff.rows[index] {
+            DiffRow::Same(..) => (Style::default(), Style::default()),
+            _ => (change_style(theme.diff_removed), change_style(theme.diff_added)),
+        };
+        let old = Line::styled(old.unwrap_or_default(), old_style);
+        let new = Line::styled(new.unwrap_or_default(), new_style);
+        let old_rows = wrapped_rows(&old, old_inner.width as usize);
+        let new_rows = wrapped_rows(&new, new_inner.width as usize);
+        let height = old_rows.max(new_rows);
+        old_text.push(old);
+        old_text.extend(std::iter::repeat_n(Line::default(), height - old_rows));
+        new_text.push(new);
+        new_text.extend(std::iter::repeat_n(Line::default(), height - new_rows));
+        rows.push(height);
+    }
+    highlight_cursor_row(frame, &rows, start, app_state.cursor_line, old_inner, theme);
+    highlight_cursor_row(frame, &rows, start, app_state.cursor_line, new_inner, theme);
+
+    let panes = [(old_text, columns[0], diff.old_path.as_str()), (new_text, columns[1], app_state.file_path.as_str())];
+    for (text, column, title) in panes {
+        let paragraph = Paragraph::new(text)
+            .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(theme.border)).title(title))
+            .wrap(ratatui::widgets::Wrap { trim: false });
+        frame.render_widget(paragraph, column);
+    }
 }
 
-fn render(frame: &mut Frame, app_state: &mut AppState) {
+fn render(frame: &mut Frame, app: &mut App) {
     let area = frame.area();
+    let title = app.title();
+    let reading_width = app.reading_width;
+    let theme = &app.theme;
+    let todo_keywords: &[String] = if app.highlight_todos { &app.todo_keywords } else { &[] };
+    let auto_scroll = app.auto_scroll.is_some();
+    let app_state = &mut app.buffers[app.current];
     
     // Create layout
     let chunks = Layout::default()
@@ -498,78 +4878,613 @@ fn render(frame: &mut Frame, app_state: &mut AppState) {
 
     // Header
     let header_text = match app_state.view_mode {
+        _ if app_state.show_listing => "DIRECTORY",
+        _ if app_state.diff.is_some() => "DIFF VIEW",
         ViewMode::Rendered => "RENDERED VIEW",
         ViewMode::Source => "SOURCE VIEW", 
         ViewMode::SideBySide => "SIDE-BY-SIDE VIEW",
     };
     
     let header = Paragraph::new(Line::from(header_text))
-        .style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD))
-        .block(Block::default().borders(Borders::ALL).title(format!("mess - {}", app_state.file_path)));
+        .style(Style::default().fg(theme.header).add_modifier(Modifier::BOLD))
+        .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(theme.border)).title(title));
     
     frame.render_widget(header, chunks[0]);
     
+    // Make room for the table of contents sidebar when it's open
+    let content_area = if app_state.show_toc {
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([
+                Constraint::Percentage(TOC_WIDTH_PERCENT),
+                Constraint::Min(1),
+            ])
+            .split(chunks[1]);
+        render_toc(frame, app_state, columns[0], theme);
+        columns[1]
+    } else {
+        chunks[1]
+    };
+
+    // Center rendered markdown in a narrower column when a reading width is set
+    let content_area = match reading_width {
+        // Leave room for the borders and the scrollbar
+        Some(width) if app_state.view_mode == ViewMode::Rendered => centered_columns(width + 3, content_area),
+        _ => content_area,
+    };
+    
+    app_state.content_area = content_area;
+    app_state.image_slots.clear();
+    
     // Check if we're in side-by-side mode - if so, render differently
-    if matches!(app_state.view_mode, ViewMode::SideBySide) {
-        render_side_by_side(frame, app_state, chunks[1]);
+    if app_state.show_listing {
+        render_listing(frame, app_state, content_area, theme);
+    } else if matches!(app_state.view_mode, ViewMode::SideBySide) {
+        render_side_by_side(frame, app_state, content_area, theme, todo_keywords);
     } else {
-        render_single_view(frame, app_state, chunks[1]);
+        render_single_view(frame, app_state, content_area, theme, todo_keywords, app.ruler);
     }
 
     // Footer
     let footer_text = match app_state.view_mode {
+        _ if app_state.diff.is_some() => "s: Split | </>: Resize | ↑↓: Scroll | q: Quit | Ctrl+h: Help",
+        _ if app_state.show_listing => "Enter: Open | ↑↓: Select | q: Quit | Ctrl+h: Help",
+        ViewMode::Source if app_state.deferred_render.is_some() => "TAB: Render | ↑↓: Scroll | q: Quit | Ctrl+h: Help",
         ViewMode::Rendered => "TAB: Source | ↑↓: Scroll | q: Quit | Ctrl+h: Help",
         ViewMode::Source => "TAB: Side-by-side | ↑↓: Scroll | q: Quit | Ctrl+h: Help",
-        ViewMode::SideBySide => "TAB: Rendered | ↑↓: Scroll | q: Quit | Ctrl+h: Help",
+        ViewMode::SideBySide => "TAB: Rendered | s: Split | </>: Resize | ↑↓: Scroll | q: Quit | Ctrl+h: Help",
+    };
+
+    let search_prompt = match (&app_state.export_input, &app_state.pipe_input, &app_state.search_input) {
+        (Some(path), _, _) => Some(format!("Export to (.html for HTML): {}", path)),
+        (None, Some(command), _) => Some(format!("|{}", command)),
+        (None, None, Some(input)) => Some(format!("/{}", input.query)),
+        (None, None, None) => None,
+    };
+    let footer_text = match &search_prompt {
+        Some(prompt) => prompt.as_str(),
+        None => footer_text,
+    };
+
+    let footer_text = match (&app_state.status_message, app_state.pending_command) {
+        (_, Some(PendingCommand::SetMark)) => "Set mark: press a letter",
+        (_, Some(PendingCommand::JumpToMark)) => "Jump to mark: press a letter",
+        (_, Some(PendingCommand::Fold)) => "z: press a to toggle the fold",
+        (_, Some(PendingCommand::NextHeading)) => "]: press ] for the next heading",
+        (_, Some(PendingCommand::PreviousHeading)) => "[: press [ for the previous heading",
+        (_, Some(PendingCommand::Quit)) => "Quit? (y/n)",
+        (Some((message, _)), None) => message.as_str(),
+        (None, None) => footer_text,
+    };
+    
+    let footer_text = match app_state.selected_range() {
+        Some((first, last)) if app_state.status_message.is_none() => {
+            format!("VISUAL: {} lines | y: Copy | Esc: Cancel", last - first + 1)
+        }
+        _ => footer_text.to_string(),
+    };
+
+    let footer_text = match app_state.link_summary() {
+        Some(summary) if app_state.status_message.is_none() && search_prompt.is_none() => {
+            format!("{} | {}", summary, footer_text)
+        }
+        _ => footer_text,
+    };
+
+    let footer_text = match app_state.match_summary() {
+        Some(summary) if app_state.search_input.is_none() => format!("{} | {}", summary, footer_text),
+        _ => footer_text.to_string(),
     };
+
+    // Show where a percent jump would land while the count is typed
+    let footer_text = match app_state.pending_count {
+        Some(count) => format!(
+            "Count: {} (%: line {}) | {}",
+            count,
+            app_state.percent_line(count) + 1,
+            footer_text
+        ),
+        None => footer_text,
+    };
+    let footer_text = if auto_scroll { format!("AUTO | {}", footer_text) } else { footer_text };
     
     let footer = Paragraph::new(Line::from(footer_text))
-        .style(Style::default().fg(Color::Gray))
-        .block(Block::default().borders(Borders::ALL));
+        .style(Style::default().fg(theme.footer))
+        .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(theme.border)));
     
     frame.render_widget(footer, chunks[2]);
+
+    // Popups would end up underneath the images
+    if app_state.show_help || app_state.show_stats {
+        app_state.image_slots.clear();
+    }
+
+    if app_state.show_help {
+        render_help(frame, app_state);
+    }
+
+    if app_state.show_stats {
+        render_stats(frame, app_state);
+    }
+
+    if let Some(palette) = &app.palette {
+        app_state.image_slots.clear();
+        render_palette(frame, palette, &app.key_bindings, theme);
+    }
+
+    if let Some(finder) = &app.finder {
+        app_state.image_slots.clear();
+        render_heading_finder(frame, finder, &app_state.toc, theme);
+    }
 }
 
-fn show_help(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>) -> Result<()> {
-    terminal.draw(|f| {
-        let area = f.area();
-        let help_text = vec![
-            "mess - A less-like viewer with markdown support",
-            "Version: 0.1.0",
-            "",
-            "Keyboard Shortcuts:",
-            "  TAB          - Toggle view mode (rendered/source/side-by-side)",
-            "  ↑/↓          - Scroll up/down one line",
-            "  Page Up/Down - Scroll up/down 10 lines",
-            "  Home/End     - Go to beginning/end of file",
-            "  q/Esc        - Quit",
-            "  Ctrl+h       - Show this help",
-            "",
-            "View Modes (for markdown files):",
-            "  Rendered     - Shows rendered markdown",
-            "  Source       - Shows raw markdown source",
-            "  Side-by-side - Shows both rendered and source",
-            "",
-            "Press any key to continue...",
-        ];
-        
-        let help_items: Vec<ListItem> = help_text
-            .iter()
-            .map(|line| ListItem::new(Line::from(*line)))
-            .collect();
-        
-        let help_list = List::new(help_items)
-            .block(Block::default().borders(Borders::ALL).title("Help"));
-        
-        f.render_widget(help_list, area);
-    })?;
-    
-    // Wait for any key press
-    loop {
-        if let Event::Key(_) = event::read()? {
-            break;
+/// A column at most `width` wide, centered horizontally in `area`
+fn centered_columns(width: u16, area: Rect) -> Rect {
+    if width >= area.width {
+        return area;
+    }
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        width,
+        ..area
+    }
+}
+
+fn render_toc(frame: &mut Frame, app_state: &AppState, area: Rect, theme: &Theme) {
+    let items: Vec<ListItem> = app_state
+        .toc
+        .iter()
+        .map(|entry| {
+            let indent = "  ".repeat(entry.level.saturating_sub(1) as usize);
+            ListItem::new(Line::from(format!("{}{}", indent, entry.text)))
+        })
+        .collect();
+
+    let toc_list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(theme.border)).title("Contents"))
+        .highlight_style(Style::default().fg(theme.selection).add_modifier(Modifier::REVERSED));
+
+    let mut list_state = ListState::default().with_selected(Some(app_state.toc_selected));
+    frame.render_stateful_widget(toc_list, area, &mut list_state);
+}
+
+fn render_listing(frame: &mut Frame, app_state: &AppState, area: Rect, theme: &Theme) {
+    let Some(listing) = &app_state.listing else {
+        return;
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(theme.border))
+        .title(listing.directory.as_str());
+    if listing.files.is_empty() {
+        let empty = Paragraph::new("No text files in this directory").style(Style::default().fg(theme.footer));
+        frame.render_widget(empty.block(block), area);
+        return;
+    }
+
+    let items: Vec<ListItem> = listing.files.iter().map(|name| ListItem::new(name.as_str())).collect();
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(Style::default().fg(theme.selection).add_modifier(Modifier::REVERSED));
+    let mut list_state = ListState::default().with_selected(Some(listing.selected));
+    frame.render_stateful_widget(list, area, &mut list_state);
+}
+
+/// Returns a rectangle of the given percentage size centered within `area`
+fn centered_rect(percent_x: u16, percent_y: u16, area: Rect) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(area);
+
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(vertical[1])[1]
+}
+
+/// Dim everything already drawn so a popup stands out
+fn dim_background(frame: &mut Frame) {
+    let area = frame.area();
+    let buffer = frame.buffer_mut();
+    for y in area.top()..area.bottom() {
+        for x in area.left()..area.right() {
+            buffer[(x, y)].modifier.insert(Modifier::DIM);
         }
     }
+}
+
+fn render_help(frame: &mut Frame, app_state: &AppState) {
+    let area = frame.area();
+    dim_background(frame);
+
+    let popup_area = centered_rect(70, 80, area);
     
-    Ok(())
-}
\ No newline at end of file
+    let help_items: Vec<ListItem> = HELP_TEXT
+        .iter()
+        .skip(app_state.help_scroll)
+        .map(|line| ListItem::new(Line::from(*line)))
+        .collect();
+    
+    let help_list = List::new(help_items)
+        .block(Block::default().borders(Borders::ALL).title("Help"));
+    
+    frame.render_widget(Clear, popup_area);
+    frame.render_widget(help_list, popup_area);
+}
+
+fn render_palette(frame: &mut Frame, palette: &CommandPalette, key_bindings: &KeyBindings, theme: &Theme) {
+    let area = frame.area();
+    dim_background(frame);
+
+    let popup_area = centered_rect(60, 60, area);
+    let items: Vec<ListItem> = match palette.line() {
+        Some(line) => vec![ListItem::new(format!("Go to line {}", line))],
+        None => palette
+            .matches()
+            .into_iter()
+            .map(|action| {
+                let keys = key_bindings.keys_for(action).join(", ");
+                ListItem::new(Line::from(vec![
+                    Span::raw(format!("{:<40}", action.description())),
+                    Span::styled(keys, Style::default().fg(theme.footer)),
+                ]))
+            })
+            .collect(),
+    };
+    let selected = if items.is_empty() { None } else { Some(palette.selected) };
+
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(format!(":{}", palette.query)))
+        .highlight_style(Style::default().fg(theme.selection).add_modifier(Modifier::REVERSED));
+    let mut list_state = ListState::default().with_selected(selected);
+
+    frame.render_widget(Clear, popup_area);
+    frame.render_stateful_widget(list, popup_area, &mut list_state);
+}
+
+fn render_heading_finder(frame: &mut Frame, finder: &HeadingFinder, toc: &[TocEntry], theme: &Theme) {
+    let area = frame.area();
+    dim_background(frame);
+
+    let popup_area = centered_rect(60, 60, area);
+    let block = Block::default().borders(Borders::ALL).title(format!("Heading: {}", finder.query));
+    // Only the rows in view are built, however many headings match
+    let rows = block.inner(popup_area).height as usize;
+    let first = (finder.selected + 1).saturating_sub(rows);
+    let matched = Style::default().fg(theme.search_match).add_modifier(Modifier::BOLD);
+
+    let items: Vec<ListItem> = if finder.matches.is_empty() {
+        let message = if toc.is_empty() { "No headings" } else { "No matching headings" };
+        vec![ListItem::new(Span::styled(message, Style::default().fg(theme.footer)))]
+    } else {
+        finder
+            .matches
+            .iter()
+            .skip(first)
+            .take(rows)
+            .map(|(index, positions)| {
+                let entry = &toc[*index];
+                let mut spans = vec![Span::raw("  ".repeat(entry.level.saturating_sub(1) as usize))];
+                // Runs of matched and unmatched characters
+                let mut run = String::new();
+                let mut run_matched = false;
+                for (position, c) in entry.text.chars().enumerate() {
+                    let is_matched = positions.contains(&position);
+                    if is_matched != run_matched && !run.is_empty() {
+                        let style = if run_matched { matched } else { Style::default() };
+                        spans.push(Span::styled(std::mem::take(&mut run), style));
+                    }
+                    run_matched = is_matched;
+                    run.push(c);
+                }
+                spans.push(Span::styled(run, if run_matched { matched } else { Style::default() }));
+                ListItem::new(Line::from(spans))
+            })
+            .collect()
+    };
+    let selected = if finder.matches.is_empty() { None } else { Some(finder.selected - first) };
+
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(Style::default().fg(theme.selection).add_modifier(Modifier::REVERSED));
+    let mut list_state = ListState::default().with_selected(selected);
+
+    frame.render_widget(Clear, popup_area);
+    frame.render_stateful_widget(list, popup_area, &mut list_state);
+}
+
+fn render_stats(frame: &mut Frame, app_state: &AppState) {
+    let area = frame.area();
+    dim_background(frame);
+
+    let stats = DocumentStats::new(app_state);
+    let mut lines = vec![
+        format!("Lines:        {}", stats.lines),
+        format!("Words:        {}", stats.words),
+        format!("Characters:   {}", stats.characters),
+    ];
+    if let Some(prose_words) = stats.prose_words {
+        lines.push(format!("Prose words:  {}", prose_words));
+    }
+    lines.push(format!("Reading time: ~{} min", stats.reading_minutes()));
+    lines.push(String::new());
+    lines.push("Press any key to close".to_string());
+
+    let width = STATS_POPUP_WIDTH.min(area.width);
+    let height = (lines.len() as u16 + 2).min(area.height);
+    let popup_area = Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    );
+
+    let stats_items: Vec<ListItem> = lines.into_iter().map(ListItem::new).collect();
+    let stats_list = List::new(stats_items)
+        .block(Block::default().borders(Borders::ALL).title("Statistics"));
+
+    frame.render_widget(Clear, popup_area);
+    frame.render_widget(stats_list, popup_area);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixed_line_endings_leave_no_carriage_returns() {
+        let path = std::env::temp_dir().join(format!("mess-mixed-{}.md", std::process::id()));
+        fs::write(&path, "# Title\r\n\r\nfirst line\rsecond line\r\n\r- item\r- other\n").unwrap();
+        let args = Args::parse_from(["mess", path.to_str().unwrap()]);
+        let app_state = AppState::new(path.to_string_lossy().into_owned(), &args, &Theme::dark(), true).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(!app_state.content.contains('\r'));
+        assert!(!app_state.rendered_content.contains('\r'));
+        assert_eq!(app_state.content, "# Title\n\nfirst line\nsecond line\n\n- item\n- other\n");
+        let lines: Vec<&str> = app_state.rendered_content.lines().collect();
+        assert_eq!(lines, ["# Title", "", "first line", "second line", "", "• item", "• other"]);
+    }
+
+    #[test]
+    fn strip_ansi_removes_every_escape_in_the_example_log() {
+        let stripped = strip_ansi(include_str!("../examples/ansi.log"));
+        assert!(!stripped.contains('\x1b'));
+        assert_eq!(
+            stripped.lines().collect::<Vec<_>>(),
+            [
+                "INFO server started on port 8080",
+                "ERROR connection refused link done",
+                "plain line with [brackets] kept",
+                "after title",
+                "bold reset by tput sgr0",
+            ]
+        );
+    }
+
+    #[test]
+    fn strip_ansi_removes_two_character_escapes() {
+        assert_eq!(strip_ansi("a\x1b(Bb\x1b)0c\x1b=d\x1b#8e"), "abcde");
+    }
+
+    #[test]
+    fn strip_ansi_keeps_text_at_a_trailing_escape() {
+        assert_eq!(strip_ansi("text\x1b"), "text");
+        assert_eq!(strip_ansi("text\x1b["), "text");
+        assert_eq!(strip_ansi("no escapes"), "no escapes");
+    }
+
+    /// Display column of each `│` in `line`
+    fn bar_columns(line: &str) -> Vec<usize> {
+        line.char_indices().filter(|&(_, c)| c == '│').map(|(index, _)| line[..index].width()).collect()
+    }
+
+    #[test]
+    fn table_columns_line_up_with_full_width_text() {
+        let options = RenderOptions { image_rows: 0, html: HtmlMode::Strip, tab_width: 4, ascii: false, image_dir: None };
+        let rendered = render_markdown("| 名前 | 値 |\n|---|---|\n| 東京都 | 1 |\n| a | ２２２ |\n", &options);
+        // 東京都 is the widest first cell at six columns, so every bar sits at column 7
+        let rows: Vec<&str> = rendered.text.lines().filter(|line| line.contains('│')).collect();
+        assert_eq!(rows.len(), 3);
+        assert!(rows.iter().all(|row| bar_columns(row) == [7]), "{}", rendered.text);
+    }
+
+    #[test]
+    fn tabs_after_full_width_text_reach_the_same_stop() {
+        assert_eq!(expand_tabs("日本\tx", 4).width(), expand_tabs("abcd\tx", 4).width());
+        assert_eq!(expand_tabs("日\tx", 4), "日  x");
+        assert_eq!(expand_tabs("a\tx", 4), "a   x");
+    }
+
+    #[test]
+    fn overflow_splits_before_a_full_width_character_it_cuts() {
+        let theme = Theme::dark();
+        // Column 3 falls in the middle of the second character
+        let line = mark_overflow(Line::from("日本語"), 3, &theme);
+        let parts: Vec<&str> = line.spans.iter().map(|span| span.content.as_ref()).collect();
+        assert_eq!(parts, ["日", "本語"]);
+        assert_eq!(line.spans[1].style.fg, Some(theme.overflow));
+    }
+
+    #[test]
+    fn wrapping_full_width_text_keeps_rows_within_width() {
+        let line = Line::from("日本語のテキストを折り返す");
+        for width in 1..=7 {
+            let rows = wrap_words(&line, width, Symbols::get(false));
+            // A character two cells wide can't fit a row one cell wide
+            assert!(rows.iter().all(|row| row.width() <= width.max(2)), "width {}", width);
+            let text: String = rows.iter().map(|row| row.to_string()).collect();
+            assert_eq!(text, "日本語のテキストを折り返す");
+        }
+        assert_eq!(wrapped_rows(&line, 6), 5);
+    }
+
+    #[test]
+    fn slicing_spans_of_full_width_text_at_character_boundaries() {
+        let spans = [Span::raw("日本"), Span::raw("語")];
+        let sliced = slice_spans(&spans, 3, 9);
+        let parts: Vec<&str> = sliced.iter().map(|span| span.content.as_ref()).collect();
+        assert_eq!(parts, ["本", "語"]);
+    }
+
+    #[test]
+    fn empty_files_take_scrolling_searching_and_jumps() {
+        let dir = std::env::temp_dir().join(format!("mess-empty-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in [("empty.txt", ""), ("blank.md", "  \n\n\t\n")] {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap();
+            let args = Args::parse_from(["mess", "--no-resume", path.to_str().unwrap()]);
+            let mut app = App::new(&args, &Config::default()).unwrap();
+            let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(60, 20)).unwrap();
+
+            let actions = [
+                (Action::ScrollDown, None),
+                (Action::PageDown, Some(3)),
+                (Action::HalfPageDown, None),
+                (Action::Bottom, None),
+                (Action::Bottom, Some(7)),
+                (Action::Top, Some(3)),
+                (Action::ScrollUp, Some(5)),
+                (Action::PageUp, None),
+                (Action::HalfPageUp, None),
+                (Action::ViewDown, None),
+                (Action::ViewUp, None),
+                (Action::Percent, Some(50)),
+                (Action::Top, None),
+                (Action::NextMatch, None),
+                (Action::Search, None),
+            ];
+            for (action, count) in actions {
+                assert!(app.perform_action(action, count));
+                terminal.draw(|f| render(f, &mut app)).unwrap();
+            }
+            let app_state = app.current_buffer();
+            app_state.edit_search(|query| query.push_str("text"));
+            app_state.confirm_search();
+            assert!(app_state.search_matches.is_empty());
+            for action in [Action::NextMatch, Action::PreviousMatch] {
+                assert!(app.perform_action(action, None));
+                terminal.draw(|f| render(f, &mut app)).unwrap();
+            }
+
+            let app_state = app.current_buffer();
+            assert_eq!((app_state.scroll_offset, app_state.cursor_line), (0, 0));
+            let screen: String = terminal.backend().buffer().content.iter().map(|cell| cell.symbol()).collect();
+            assert!(screen.contains("(empty file)"), "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_defaults_to_the_viewed_file_and_skips_build_directories() {
+        let dir = std::env::temp_dir().join(format!("mess-watch-{}", std::process::id()));
+        for subdir in ["target/debug", ".git", "notes"] {
+            fs::create_dir_all(dir.join(subdir)).unwrap();
+        }
+        let doc = dir.join("doc.md");
+        fs::write(&doc, "# Doc").unwrap();
+        fs::write(dir.join("notes/todo.md"), "- one").unwrap();
+        let watch = Watch::new("true".to_string(), &[], doc.to_str().unwrap());
+        assert_eq!(watch.paths, [doc.clone(), dir.clone()]);
+
+        // Build output and version control files come and go without a change
+        let snapshot = watch_snapshot(&watch.paths);
+        fs::write(dir.join("target/debug/out.bin"), "built").unwrap();
+        fs::write(dir.join(".git/index"), "staged").unwrap();
+        assert_eq!(watch_snapshot(&watch.paths), snapshot);
+        fs::write(dir.join("notes/more.md"), "- two").unwrap();
+        assert_ne!(watch_snapshot(&watch.paths), snapshot);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(Watch::new("true".to_string(), &[], "doc.md").paths, [PathBuf::from("doc.md"), PathBuf::from(".")]);
+        assert_eq!(Watch::new("true".to_string(), &[], STDIN_PATH).paths, [PathBuf::from(".")]);
+    }
+
+    #[test]
+    fn watch_reloads_the_watched_file_from_another_buffer() {
+        let dir = std::env::temp_dir().join(format!("mess-watch-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (doc, other) = (dir.join("doc.md"), dir.join("other.md"));
+        fs::write(&doc, "# Before").unwrap();
+        fs::write(&other, "# Other").unwrap();
+        let (doc, other) = (doc.to_str().unwrap(), other.to_str().unwrap());
+        let args = Args::parse_from(["mess", "--no-resume", "--watch-command", "true", doc, other]);
+        let mut app = App::new(&args, &Config::default()).unwrap();
+        app.next_buffer();
+        assert_eq!(app.current_buffer().file_path, other);
+
+        // The command finishing reloads the watched file, not the one in view
+        fs::write(doc, "# After").unwrap();
+        let (sender, receiver) = mpsc::channel();
+        sender.send(Ok(String::new())).unwrap();
+        app.watch.as_mut().unwrap().running = Some(receiver);
+        app.poll_watch();
+        assert!(app.buffers[0].content.contains("After"));
+        assert_eq!(app.current_buffer().file_path, other);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn line_index_read_in_blocks_matches_one_built_from_text() {
+        for text in ["", "one", "one\n", "one\ntwo", "one\n\ntwo\n", "\n\n"] {
+            let streamed = LineIndex::from_reader(text.as_bytes()).unwrap();
+            let index = LineIndex::new(text);
+            assert_eq!(streamed.len(), index.len(), "{:?}", text);
+            assert!((0..index.len()).all(|line| streamed.range(line) == index.range(line)), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn streamed_lines_read_across_blocks() {
+        let dir = std::env::temp_dir().join(format!("mess-streamed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("large.txt");
+        // Short lines run over the first block boundaries, then one line is longer than a block
+        let mut lines: Vec<String> = (0..STREAM_BLOCK_SIZE / 8).map(|i| format!("line {}", i)).collect();
+        lines.push("x".repeat(STREAM_BLOCK_SIZE + 10));
+        lines.push("crlf end".to_string());
+        let text = lines.join("\n").replace("crlf end", "crlf end\r\n");
+        fs::write(&path, &text).unwrap();
+
+        let file = fs::File::open(&path).unwrap();
+        let index = LineIndex::from_reader(&file).unwrap();
+        let streamed = StreamedFile { path: path.clone(), file, block: RefCell::default(), counts: OnceCell::new() };
+        assert_eq!(index.len(), lines.len());
+        // Backwards, so blocks are read again from before the lines asked for
+        for line in (0..lines.len()).rev() {
+            assert_eq!(streamed.line(&index, line), lines[line], "line {}", line);
+        }
+        assert_eq!(streamed.lines().unwrap().collect::<Vec<_>>(), lines);
+        assert_eq!(streamed.counts(), (text.split_whitespace().count(), text.chars().count() - 1));
+
+        let mut copied = Vec::new();
+        streamed.copy_to(&mut copied, false).unwrap();
+        assert_eq!(String::from_utf8(copied).unwrap(), lines.join("\n") + "\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deferred_render_switches_to_the_mode_asked_for() {
+        let args = Args::parse_from(["mess", "--max-render-size", "8", "big.md"]);
+        let mut app_state =
+            AppState::from_content("big.md".to_string(), "# Big\n\nMore than eight bytes\n".to_string(), &args, &Theme::dark(), true);
+        assert_eq!(app_state.view_mode, ViewMode::Source);
+        app_state.show_view_mode(ViewMode::SideBySide);
+        let started = Instant::now();
+        while app_state.deferred_render.is_some() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+            app_state.poll_deferred_render();
+        }
+        assert_eq!(app_state.view_mode, ViewMode::SideBySide);
+        assert!(app_state.rendered_content.contains("Big"));
+    }
+}
@@ -0,0 +1,1735 @@
+//! Rendering of markdown into terminal lines. Documents are first rendered to
+//! text, with private-use markers standing in for quotes, rules, code boxes,
+//! links and the like, and each line of that text is then styled for drawing.
+
+use crate::theme::Theme;
+use pulldown_cmark::{Alignment, Options, Parser};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Markers placed around blockquotes while rendering, replaced by `>` prefixes afterwards
+const QUOTE_START: char = '\u{F8F0}';
+const QUOTE_END: char = '\u{F8F1}';
+
+/// Stands alone on a rendered line in place of a horizontal rule, expanded to the pane width when drawn
+pub const RULE_MARKER: char = '\u{F8F2}';
+
+/// Starts a `key: value` line of YAML frontmatter, drawn as a metadata header
+pub const METADATA_MARKER: char = '\u{F8F3}';
+
+/// Starts the caption line of an inline image; the rows reserved for the
+/// picture below it each hold a lone `IMAGE_ROW_MARKER`
+pub const IMAGE_MARKER: char = '\u{F8F4}';
+pub const IMAGE_ROW_MARKER: char = '\u{F8F5}';
+
+/// Placed around the placeholder of an image that isn't drawn, drawn dimmed
+pub const IMAGE_START: char = '\u{F8EE}';
+pub const IMAGE_END: char = '\u{F8EF}';
+
+/// Starts the closing `— Author` line of a blockquote, drawn apart from the quote
+pub const ATTRIBUTION_MARKER: char = '\u{F8FE}';
+
+/// Indentation of definitions under their term in a definition list
+const DEFINITION_INDENT: &str = "    ";
+
+/// Placed around links in rendered text, drawn as links. Written links keep
+/// their destination after a `LINK_TARGET`, hidden unless markers are shown;
+/// bare URLs are their own destination.
+pub const LINK_START: char = '\u{F8F6}';
+pub const LINK_END: char = '\u{F8F7}';
+const LINK_TARGET: char = '\u{F8F8}';
+
+/// Placed around HTML kept in rendered text, drawn dimmed
+pub const HTML_START: char = '\u{F8F9}';
+pub const HTML_END: char = '\u{F8FA}';
+
+/// Code blocks are drawn in a box: `CODE_TOP` starts the top border and is
+/// followed by the language, each code line starts with `CODE_LINE` and
+/// `CODE_BOTTOM` stands alone as the bottom border
+pub const CODE_TOP: char = '\u{F8FB}';
+pub const CODE_LINE: char = '\u{F8FC}';
+pub const CODE_BOTTOM: char = '\u{F8FD}';
+
+/// How raw HTML in markdown is rendered
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum HtmlMode {
+    /// Drop the tags and comments, keeping the text between them
+    Strip,
+    /// Show a dimmed `[html]` in place of each block or tag
+    Placeholder,
+    /// Show the HTML as written, dimmed
+    Raw,
+}
+
+/// A heading collected while rendering, used for the table of contents
+#[derive(Debug, Clone)]
+pub struct TocEntry {
+    pub level: u8,
+    pub text: String,
+    /// The text as a URL fragment, as `#slug` links name the heading
+    pub slug: String,
+    pub source_line: usize,
+    pub rendered_line: usize,
+}
+
+/// A footnote reference and the definition it points to, as rendered lines
+#[derive(Debug, Clone)]
+pub struct FootnoteLink {
+    pub reference_line: usize,
+    pub definition_line: usize,
+}
+
+/// A local image shown inline in rendered view, below its caption line
+#[derive(Debug, Clone)]
+pub struct InlineImage {
+    pub path: PathBuf,
+    pub rendered_line: usize,
+}
+
+/// Characters the viewer draws itself, in Unicode or plain ASCII
+#[derive(Debug)]
+pub struct Symbols {
+    pub bullet: &'static str,
+    /// Repeated across the pane for horizontal rules
+    pub rule: &'static str,
+    /// Lines of code block boxes and table rules
+    pub horizontal: &'static str,
+    /// Quote bars, code block sides, table columns and the ruler
+    pub vertical: &'static str,
+    /// Code block box corners: top left, top right, bottom left, bottom right
+    pub corners: [&'static str; 4],
+    /// Where a table's column lines cross its header rule
+    pub cross: &'static str,
+    pub scroll_up: &'static str,
+    pub scroll_down: &'static str,
+    pub track: &'static str,
+    pub thumb: &'static str,
+    pub continuation: &'static str,
+    pub heading_tick: &'static str,
+    pub match_tick: &'static str,
+    pub image: &'static str,
+    pub metadata: &'static str,
+    /// Trailing whitespace, when shown
+    pub space: &'static str,
+}
+
+const UNICODE_SYMBOLS: Symbols = Symbols {
+    bullet: "•",
+    rule: "─",
+    horizontal: "─",
+    vertical: "│",
+    corners: ["┌", "┐", "└", "┘"],
+    cross: "┼",
+    scroll_up: "↑",
+    scroll_down: "↓",
+    track: "║",
+    thumb: "█",
+    continuation: "↳",
+    heading_tick: "━",
+    match_tick: "•",
+    image: "▣",
+    metadata: "▍",
+    space: "·",
+};
+
+const ASCII_SYMBOLS: Symbols = Symbols {
+    bullet: "-",
+    rule: "#",
+    horizontal: "-",
+    vertical: "|",
+    corners: ["+", "+", "+", "+"],
+    cross: "+",
+    scroll_up: "^",
+    scroll_down: "v",
+    track: "|",
+    thumb: "#",
+    continuation: ">",
+    heading_tick: "=",
+    match_tick: "*",
+    image: "#",
+    metadata: "|",
+    space: ".",
+};
+
+impl Symbols {
+    pub fn get(ascii: bool) -> &'static Symbols {
+        if ascii { &ASCII_SYMBOLS } else { &UNICODE_SYMBOLS }
+    }
+}
+
+/// Settings from the command line that shape a rendered view
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    /// Rows reserved for each inline image, or 0 to show images as text
+    pub image_rows: usize,
+    pub html: HtmlMode,
+    pub tab_width: usize,
+    /// Write bullets and table lines in ASCII
+    pub ascii: bool,
+    /// Directory local image paths are relative to, to read the pixel size of
+    /// images that aren't drawn from; `None` leaves sizes out
+    pub image_dir: Option<PathBuf>,
+}
+
+/// Output of a renderer
+#[derive(Default)]
+pub struct RenderedMarkdown {
+    pub text: String,
+    pub toc: Vec<TocEntry>,
+    pub footnotes: Vec<FootnoteLink>,
+    pub images: Vec<InlineImage>,
+}
+
+/// Local images get a caption line followed by `image_rows` blank rows to draw
+/// them in; with no rows, or for remote images, a placeholder shows the alt text
+/// and URL, along with the size of local images found in `image_dir`.
+/// Code blocks keep their text as written, with tabs expanded to `tab_width` stops.
+///
+/// ```
+/// use mess::markdown::{HtmlMode, RenderOptions, render_markdown};
+///
+/// let options = RenderOptions { image_rows: 0, html: HtmlMode::Strip, tab_width: 4, ascii: true, image_dir: None };
+/// let rendered = render_markdown("See ![the logo](logo.png) here", &options);
+/// assert!(rendered.text.contains("# the logo (logo.png)"));
+/// ```
+///
+/// Blocks are kept apart by at most one blank line, and headings always have
+/// one below them, as lists do above them:
+///
+/// ```
+/// use mess::markdown::{HtmlMode, RenderOptions, render_markdown};
+///
+/// let options = RenderOptions { image_rows: 0, html: HtmlMode::Strip, tab_width: 4, ascii: true, image_dir: None };
+/// let rendered = render_markdown("# Title\nIntro\n- one\n- two\n\n\n\nEnd", &options);
+/// assert_eq!(rendered.text, "# Title\n\nIntro\n\n- one\n- two\n\nEnd");
+///
+/// let rendered = render_markdown("Setup\n-----\n- step\n  - detail", &options);
+/// assert_eq!(rendered.text, "## Setup\n\n- step\n- detail");
+/// ```
+pub fn render_markdown(content: &str, options: &RenderOptions) -> RenderedMarkdown {
+    let RenderOptions { image_rows, html, tab_width, ascii, ref image_dir } = *options;
+    let symbols = Symbols::get(ascii);
+    let options = markdown_options();
+    // References are only linked when a definition exists anywhere in the document
+    let defined_footnotes: Vec<String> = Parser::new_ext(content, options)
+        .filter_map(|event| match event {
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::FootnoteDefinition(label)) => {
+                Some(label.to_string())
+            }
+            _ => None,
+        })
+        .collect();
+    // Footnotes are numbered in order of their first reference
+    let mut footnote_numbers: Vec<String> = Vec::new();
+    let mut footnote_definitions: HashMap<String, String> = HashMap::new();
+    // Document text set aside while a footnote definition is being rendered
+    let mut outside_footnote: Option<(String, String)> = None;
+    // Raw YAML of a frontmatter block while it is being read
+    let mut frontmatter: Option<String> = None;
+    // Column alignments and rows of the table being read, plus the document
+    // text set aside while a cell is rendered
+    let mut table: Option<(Vec<Alignment>, Vec<Vec<String>>)> = None;
+    let mut outside_cell: Option<String> = None;
+    // Source of the image being read, plus where its alt text starts in `result`
+    let mut image: Option<(String, usize)> = None;
+    let mut image_sources = Vec::new();
+    // Language and text of the code block being read
+    let mut code_block: Option<(String, String)> = None;
+    let mut in_definition = false;
+    // Destination of the link being read; inline, reference and shortcut
+    // links all arrive resolved
+    let mut link: Option<String> = None;
+    // HTML block being read
+    let mut html_block: Option<String> = None;
+    // Document text collected until the next non-text event, since the parser
+    // splits text at characters like `_` that could be emphasis, even inside URLs
+    let mut pending_text = String::new();
+
+    let parser = Parser::new_ext(content, options);
+    let mut result = String::new();
+    let mut toc = Vec::new();
+    // Heading being rendered: its entry plus where its line starts in `result`
+    let mut current_heading: Option<(TocEntry, usize)> = None;
+    // Source lines before a byte offset, counted on from the last heading
+    // rather than from the start for each one
+    let mut counted_lines = (0, 0);
+    // Rendered heading lines, used to locate each heading after cleanup
+    let mut heading_lines = Vec::new();
+    // Lists being read, which only get a blank line above when not nested
+    let mut list_depth = 0;
+    
+    for (event, range) in parser.into_offset_iter() {
+        if !matches!(event, pulldown_cmark::Event::Text(_)) && !pending_text.is_empty() {
+            result.push_str(&mark_bare_urls(&pending_text));
+            pending_text.clear();
+        }
+        match event {
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Heading { level, .. }) => {
+                result.push('\n');
+                if range.start < counted_lines.0 {
+                    counted_lines = (0, 0);
+                }
+                counted_lines = (range.start, counted_lines.1 + content[counted_lines.0..range.start].matches('\n').count());
+                let entry = TocEntry {
+                    level: level as u8,
+                    text: String::new(),
+                    slug: String::new(),
+                    source_line: counted_lines.1,
+                    rendered_line: 0,
+                };
+                current_heading = Some((entry, result.len()));
+                for _ in 0..level as usize {
+                    result.push('#');
+                }
+                result.push(' ');
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Heading(_)) => {
+                if let Some((mut entry, line_start)) = current_heading.take() {
+                    heading_lines.push(result[line_start..].to_string());
+                    entry.slug = slugify(&entry.text);
+                    toc.push(entry);
+                }
+                end_with_blank_line(&mut result);
+            }
+            // Paragraphs of a definition continue its indented lines
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Paragraph)
+                if in_definition && !result.ends_with(DEFINITION_INDENT) =>
+            {
+                result.push('\n');
+                result.push_str(DEFINITION_INDENT);
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Paragraph) if in_definition => {}
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Paragraph) if in_definition => {}
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Paragraph) if !result.ends_with('\n') => {
+                result.push('\n');
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Paragraph) => {
+                result.push('\n');
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::CodeBlock(kind)) => {
+                let language = match kind {
+                    pulldown_cmark::CodeBlockKind::Fenced(info) => {
+                        info.split([' ', ',']).next().unwrap_or_default().to_string()
+                    }
+                    pulldown_cmark::CodeBlockKind::Indented => String::new(),
+                };
+                code_block = Some((language, String::new()));
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::CodeBlock) => {
+                if let Some((language, code)) = code_block.take() {
+                    let language = if language.is_empty() { "code" } else { &language };
+                    result.push('\n');
+                    result.push(CODE_TOP);
+                    result.push_str(language);
+                    for line in code.strip_suffix('\n').unwrap_or(&code).split('\n') {
+                        result.push('\n');
+                        result.push(CODE_LINE);
+                        result.push_str(&expand_tabs(line, tab_width));
+                    }
+                    result.push('\n');
+                    result.push(CODE_BOTTOM);
+                    result.push_str("\n\n");
+                }
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::List(_)) => {
+                if list_depth == 0 {
+                    end_with_blank_line(&mut result);
+                } else {
+                    result.push('\n');
+                }
+                list_depth += 1;
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::List(_)) => {
+                list_depth -= 1;
+                result.push('\n');
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Item) => {
+                result.push_str(symbols.bullet);
+                result.push(' ');
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Item) => {
+                result.push('\n');
+            }
+            // Definition list terms are bold, with each definition indented on its own line
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::DefinitionList) => {
+                result.push('\n');
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::DefinitionList) => {
+                result.push('\n');
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::DefinitionListTitle) => {
+                if !result.ends_with('\n') {
+                    result.push('\n');
+                }
+                result.push_str("**");
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::DefinitionListTitle) => {
+                result.push_str("**\n");
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::DefinitionListDefinition) => {
+                in_definition = true;
+                if !result.ends_with('\n') {
+                    result.push('\n');
+                }
+                result.push_str(DEFINITION_INDENT);
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::DefinitionListDefinition) => {
+                in_definition = false;
+                result.push('\n');
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::BlockQuote(_)) => {
+                result.push('\n');
+                result.push(QUOTE_START);
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::BlockQuote(_)) => {
+                mark_attribution(&mut result);
+                result.push(QUOTE_END);
+                result.push('\n');
+            }
+            // Table cells are plain text so columns can be padded to line up
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Strong) if outside_cell.is_none() => {
+                result.push_str("**");
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Strong) if outside_cell.is_none() => {
+                result.push_str("**");
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Emphasis) if outside_cell.is_none() => {
+                result.push('*');
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Emphasis) if outside_cell.is_none() => {
+                result.push('*');
+            }
+            pulldown_cmark::Event::Code(text) => {
+                if let Some((entry, _)) = current_heading.as_mut() {
+                    entry.text.push_str(&text);
+                }
+                if outside_cell.is_some() {
+                    result.push_str(&text);
+                } else {
+                    result.push('`');
+                    result.push_str(&text);
+                    result.push('`');
+                }
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Table(alignments)) => {
+                table = Some((alignments, Vec::new()));
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::TableHead | pulldown_cmark::Tag::TableRow) => {
+                if let Some((_, rows)) = table.as_mut() {
+                    rows.push(Vec::new());
+                }
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::TableCell) => {
+                outside_cell = Some(std::mem::take(&mut result));
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::TableCell) => {
+                if let Some(document) = outside_cell.take() {
+                    let cell = std::mem::replace(&mut result, document);
+                    if let Some(row) = table.as_mut().and_then(|(_, rows)| rows.last_mut()) {
+                        row.push(cell.trim().to_string());
+                    }
+                }
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Table) => {
+                if let Some((alignments, rows)) = table.take() {
+                    result.push('\n');
+                    result.push_str(&layout_table(&alignments, &rows, symbols));
+                    result.push('\n');
+                }
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Image { dest_url, .. }) => {
+                image = Some((dest_url.to_string(), result.len()));
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Image) => {
+                if let Some((url, alt_start)) = image.take() {
+                    // Reserved rows only fit between blocks of ordinary document text
+                    let inline = image_rows > 0
+                        && !url.contains("://")
+                        && outside_cell.is_none()
+                        && outside_footnote.is_none()
+                        && current_heading.is_none();
+                    if inline {
+                        let alt = result.split_off(alt_start);
+                        result.push('\n');
+                        result.push(IMAGE_MARKER);
+                        result.push_str(&alt);
+                        result.push('\n');
+                        for _ in 0..image_rows {
+                            result.push(IMAGE_ROW_MARKER);
+                            result.push('\n');
+                        }
+                        image_sources.push(url);
+                    } else {
+                        let alt_space = if result.len() > alt_start { " " } else { "" };
+                        let details = image_details(&url, image_dir.as_deref());
+                        // Cells are laid out by width, which markers would throw off
+                        let (start, end) = match outside_cell {
+                            Some(_) => (String::new(), String::new()),
+                            None => (IMAGE_START.to_string(), IMAGE_END.to_string()),
+                        };
+                        result.insert_str(alt_start, &format!("{}{}{}", start, symbols.image, alt_space));
+                        result.push_str(&format!(" ({}){}", details, end));
+                    }
+                }
+            }
+            // Table cells are laid out by width, so links in them stay plain text
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Link { dest_url, .. }) if outside_cell.is_none() => {
+                result.push(LINK_START);
+                link = Some(dest_url.to_string());
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Link) => {
+                if let Some(url) = link.take() {
+                    result.push(LINK_TARGET);
+                    result.push_str(&url);
+                    result.push(LINK_END);
+                }
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::HtmlBlock) => {
+                html_block = Some(String::new());
+            }
+            pulldown_cmark::Event::Html(text) => {
+                if let Some(block) = html_block.as_mut() {
+                    block.push_str(&text);
+                }
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::HtmlBlock) => {
+                if let Some(block) = html_block.take() {
+                    result.push_str(&render_html_block(&block, html));
+                }
+            }
+            pulldown_cmark::Event::InlineHtml(tag) => {
+                // Table cells are laid out by width, so they only ever get the text
+                let html = if outside_cell.is_some() { HtmlMode::Strip } else { html };
+                result.push_str(&render_inline_html(&tag, html));
+            }
+            pulldown_cmark::Event::Rule => {
+                result.push('\n');
+                result.push(RULE_MARKER);
+                result.push('\n');
+            }
+            pulldown_cmark::Event::Text(text) => {
+                if let Some(yaml) = frontmatter.as_mut() {
+                    yaml.push_str(&text);
+                    continue;
+                }
+                let text = replace_emoji_shortcodes(&text);
+                if let Some((entry, _)) = current_heading.as_mut() {
+                    entry.text.push_str(&text);
+                }
+                // Code, table cells and link text are shown as written
+                if let Some((_, code)) = code_block.as_mut() {
+                    code.push_str(&text);
+                } else if outside_cell.is_some() || link.is_some() {
+                    result.push_str(&text);
+                } else {
+                    pending_text.push_str(&text);
+                }
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::MetadataBlock(_)) => {
+                frontmatter = Some(String::new());
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::MetadataBlock(_)) => {
+                if let Some(yaml) = frontmatter.take() {
+                    result.push_str(&render_frontmatter(&yaml));
+                }
+            }
+            // A setext heading may span lines, but is drawn as one
+            pulldown_cmark::Event::SoftBreak if current_heading.is_some() => {
+                result.push(' ');
+                if let Some((entry, _)) = current_heading.as_mut() {
+                    entry.text.push(' ');
+                }
+            }
+            pulldown_cmark::Event::SoftBreak => {
+                result.push('\n');
+                if in_definition {
+                    result.push_str(DEFINITION_INDENT);
+                }
+            }
+            pulldown_cmark::Event::HardBreak => {
+                result.push('\n');
+            }
+            pulldown_cmark::Event::FootnoteReference(label) => {
+                if defined_footnotes.iter().any(|defined| *defined == *label) {
+                    let number = match footnote_numbers.iter().position(|known| *known == *label) {
+                        Some(index) => index + 1,
+                        None => {
+                            footnote_numbers.push(label.to_string());
+                            footnote_numbers.len()
+                        }
+                    };
+                    result.push_str(&superscript(number));
+                } else {
+                    result.push_str(&format!("[^{}]", label));
+                }
+            }
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::FootnoteDefinition(label)) => {
+                let document = std::mem::take(&mut result);
+                outside_footnote = Some((label.to_string(), document));
+            }
+            pulldown_cmark::Event::End(pulldown_cmark::TagEnd::FootnoteDefinition) => {
+                if let Some((label, document)) = outside_footnote.take() {
+                    let definition = std::mem::replace(&mut result, document);
+                    footnote_definitions
+                        .entry(label)
+                        .or_insert_with(|| definition.trim().to_string());
+                }
+            }
+            _ => {
+                // Handle other events as needed
+            }
+        }
+    }
+    result.push_str(&mark_bare_urls(&pending_text));
+    
+    // Definitions nobody references still get listed, after the referenced ones
+    let mut unreferenced: Vec<String> = footnote_definitions
+        .keys()
+        .filter(|label| !footnote_numbers.contains(label))
+        .cloned()
+        .collect();
+    unreferenced.sort();
+    footnote_numbers.extend(unreferenced);
+    if !footnote_numbers.is_empty() {
+        result.push_str("\n\n");
+        result.push(RULE_MARKER);
+        result.push('\n');
+        for (index, label) in footnote_numbers.iter().enumerate() {
+            let definition = footnote_definitions.get(label).map_or("", String::as_str);
+            result.push_str(&format!("{} {}\n", superscript(index + 1), definition));
+        }
+    }
+
+    let result = collapse_blank_lines(&apply_quote_prefixes(&result));
+    let result = result.trim().to_string();
+
+    // Headings keep their order, so match each one to the next identical line
+    let mut lines = result.lines().enumerate();
+    for (entry, heading_line) in toc.iter_mut().zip(&heading_lines) {
+        // A heading that isn't found leaves the search where it was
+        let mut search = lines.clone();
+        if let Some((index, _)) = search.find(|(_, line)| split_quote_prefix(line).1 == heading_line.as_str()) {
+            entry.rendered_line = index;
+            lines = search;
+        }
+    }
+
+    let footnotes = locate_footnotes(&result, footnote_numbers.len());
+
+    let images = result
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.contains(IMAGE_MARKER))
+        .zip(image_sources)
+        .map(|((rendered_line, _), source)| InlineImage { path: PathBuf::from(source), rendered_line })
+        .collect();
+
+    RenderedMarkdown { text: result, toc, footnotes, images }
+}
+
+/// What the placeholder of an image that isn't drawn says after its alt text:
+/// the URL, cut short for inline `data:` images, and the pixel size of a local
+/// image found in `image_dir`
+fn image_details(url: &str, image_dir: Option<&Path>) -> String {
+    if url.starts_with("data:") {
+        let media_type = url.split([';', ',']).next().unwrap_or(url);
+        return format!("{}…", media_type);
+    }
+    let size = image_dir
+        .filter(|_| !url.contains("://"))
+        .and_then(|dir| image::image_dimensions(dir.join(url)).ok());
+    match size {
+        Some((width, height)) => format!("{}, {}x{}", url, width, height),
+        None => url.to_string(),
+    }
+}
+
+/// Lay out YAML frontmatter as `key: value` metadata lines above a rule.
+/// Frontmatter that isn't a YAML mapping is shown as it was written.
+fn render_frontmatter(yaml: &str) -> String {
+    let mut result = String::new();
+    match serde_yaml::from_str::<serde_yaml::Mapping>(yaml) {
+        Ok(mapping) => {
+            for (key, value) in &mapping {
+                result.push(METADATA_MARKER);
+                result.push_str(&format!("{}: {}\n", yaml_text(key), yaml_text(value)));
+            }
+        }
+        Err(_) => {
+            result.push_str(yaml.trim_end());
+            result.push('\n');
+        }
+    }
+    result.push(RULE_MARKER);
+    result.push('\n');
+    result
+}
+
+/// Find the rendered lines of footnote references and their definitions,
+/// which are listed after the last rule of the document
+fn locate_footnotes(text: &str, count: usize) -> Vec<FootnoteLink> {
+    if count == 0 {
+        return Vec::new();
+    }
+    let lines: Vec<&str> = text.lines().collect();
+    let rule = RULE_MARKER.to_string();
+    let Some(section_start) = lines.iter().rposition(|line| line.trim() == rule) else {
+        return Vec::new();
+    };
+
+    let mut links = Vec::new();
+    for (line_index, line) in lines[..section_start].iter().enumerate() {
+        let mut digits = String::new();
+        for c in line.chars().chain(std::iter::once(' ')) {
+            if let Some(digit) = superscript_digit(c) {
+                digits.push(digit);
+                continue;
+            }
+            if let Ok(number) = digits.parse::<usize>() {
+                let marker = format!("{} ", superscript(number));
+                if let Some(offset) = lines[section_start..].iter().position(|l| l.starts_with(&marker)) {
+                    links.push(FootnoteLink {
+                        reference_line: line_index,
+                        definition_line: section_start + offset,
+                    });
+                }
+            }
+            digits.clear();
+        }
+    }
+    links
+}
+
+/// Replace blockquote markers with a `>` per nesting level at the start
+/// of every non-empty line inside the quote
+fn apply_quote_prefixes(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut depth: usize = 0;
+
+    for (index, line) in text.split('\n').enumerate() {
+        if index > 0 {
+            result.push('\n');
+        }
+        let mut at_line_start = true;
+        for c in line.chars() {
+            match c {
+                QUOTE_START => depth += 1,
+                QUOTE_END => depth = depth.saturating_sub(1),
+                _ => {
+                    if at_line_start && depth > 0 {
+                        result.push_str(&"> ".repeat(depth));
+                    }
+                    at_line_start = false;
+                    result.push(c);
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// End `result` with a blank line, as headings and lists that aren't nested
+/// need one between them and the block next to them
+fn end_with_blank_line(result: &mut String) {
+    while !result.is_empty() && !result.ends_with("\n\n") {
+        result.push('\n');
+    }
+}
+
+/// Leave at most one blank line between blocks, however many the blocks
+/// wrote. Blank lines inside code blocks still hold a `CODE_LINE`, so they
+/// are left alone.
+fn collapse_blank_lines(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut after_blank = false;
+    for line in text.split('\n') {
+        if line.is_empty() && after_blank {
+            continue;
+        }
+        after_blank = line.is_empty();
+        result.push_str(line);
+        result.push('\n');
+    }
+    result
+}
+
+/// Mark the last line of the blockquote ending here as its attribution when it
+/// starts with a dash, like `— Ada Lovelace` or `-- Ada Lovelace`. A quote
+/// nested in this one ends before it, so its last line isn't this quote's.
+fn mark_attribution(result: &mut String) {
+    let text = result.trim_end_matches('\n');
+    let line_start = text.rfind('\n').map_or(0, |index| index + 1);
+    let line = &text[line_start..];
+    let unquoted = line.trim_start_matches(QUOTE_START);
+    if !line.contains(QUOTE_END) && ["— ", "― ", "-- "].iter().any(|dash| unquoted.starts_with(dash)) {
+        result.insert(line_start + line.len() - unquoted.len(), ATTRIBUTION_MARKER);
+    }
+}
+
+/// How many blockquotes a rendered line is nested in, going by its `> `
+/// prefixes, and the text after them
+///
+/// ```
+/// use mess::markdown::{HtmlMode, RenderOptions, render_markdown, split_quote_prefix};
+///
+/// let options = RenderOptions { image_rows: 0, html: HtmlMode::Strip, tab_width: 4, ascii: false, image_dir: None };
+/// let rendered = render_markdown("> outer\n>\n> > inner\n> > more\n>\n> outer again", &options);
+/// let depths: Vec<_> = rendered.text.lines().map(split_quote_prefix).collect();
+/// assert_eq!(depths, [(1, "outer"), (0, ""), (2, "inner"), (2, "more"), (0, ""), (1, "outer again")]);
+/// ```
+pub fn split_quote_prefix(line: &str) -> (usize, &str) {
+    let mut depth = 0;
+    let mut rest = line;
+    while let Some(quoted) = rest.strip_prefix("> ") {
+        depth += 1;
+        rest = quoted;
+    }
+    (depth, rest)
+}
+
+/// Parser options shared by the renderer and HTML export
+pub fn markdown_options() -> Options {
+    Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_YAML_STYLE_METADATA_BLOCKS
+        | Options::ENABLE_TABLES
+        | Options::ENABLE_DEFINITION_LIST
+}
+
+/// Lay out table rows in aligned columns, with a rule under the header row.
+/// Columns are measured in display cells so wide characters line up.
+fn layout_table(alignments: &[Alignment], rows: &[Vec<String>], symbols: &Symbols) -> String {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.width())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let mut result = String::new();
+    for (index, row) in rows.iter().enumerate() {
+        let cells: Vec<String> = widths
+            .iter()
+            .enumerate()
+            .map(|(column, &width)| {
+                let cell = row.get(column).map_or("", String::as_str);
+                let alignment = alignments.get(column).copied().unwrap_or(Alignment::None);
+                pad_to_width(cell, width, alignment)
+            })
+            .collect();
+        result.push_str(cells.join(&format!(" {} ", symbols.vertical)).trim_end());
+        result.push('\n');
+
+        if index == 0 {
+            let rule: Vec<String> = widths.iter().map(|&width| symbols.horizontal.repeat(width)).collect();
+            result.push_str(&rule.join(&format!("{}{}{}", symbols.horizontal, symbols.cross, symbols.horizontal)));
+            result.push('\n');
+        }
+    }
+    result
+}
+
+/// Pad `text` with spaces to `width` display cells according to its alignment
+fn pad_to_width(text: &str, width: usize, alignment: Alignment) -> String {
+    let padding = width.saturating_sub(text.width());
+    let (left, right) = match alignment {
+        Alignment::Right => (padding, 0),
+        Alignment::Center => (padding / 2, padding - padding / 2),
+        Alignment::Left | Alignment::None => (0, padding),
+    };
+    format!("{}{}{}", " ".repeat(left), text, " ".repeat(right))
+}
+
+/// A YAML value written on one line: lists joined by commas, nested mappings inline
+fn yaml_text(value: &serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::Null => String::new(),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        serde_yaml::Value::String(s) => s.clone(),
+        serde_yaml::Value::Sequence(items) => items.iter().map(yaml_text).collect::<Vec<_>>().join(", "),
+        serde_yaml::Value::Mapping(mapping) => mapping
+            .iter()
+            .map(|(key, value)| format!("{}: {}", yaml_text(key), yaml_text(value)))
+            .collect::<Vec<_>>()
+            .join(", "),
+        serde_yaml::Value::Tagged(tagged) => yaml_text(&tagged.value),
+    }
+}
+
+/// Wrap bare `http://` and `https://` URLs in link markers. A URL runs until
+/// whitespace, minus trailing punctuation that more likely ends the sentence.
+fn mark_bare_urls(text: &str) -> std::borrow::Cow<'_, str> {
+    if !text.contains("http") {
+        return std::borrow::Cow::Borrowed(text);
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("http") {
+        let candidate = &rest[start..];
+        let at_word_start = rest[..start].chars().next_back().is_none_or(|c| !c.is_alphanumeric());
+        let is_url = candidate.starts_with("https://") || candidate.starts_with("http://");
+        let url_len = candidate.find(char::is_whitespace).unwrap_or(candidate.len());
+        let url = trim_url_punctuation(&candidate[..url_len]);
+        let has_host = url.split_once("://").is_some_and(|(_, host)| !host.is_empty());
+        if !(at_word_start && is_url && has_host) {
+            result.push_str(&rest[..start + 4]);
+            rest = &rest[start + 4..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        result.push(LINK_START);
+        result.push_str(url);
+        result.push(LINK_END);
+        rest = &candidate[url.len()..];
+    }
+    result.push_str(rest);
+    std::borrow::Cow::Owned(result)
+}
+
+/// An HTML block as rendered text, on lines of its own
+fn render_html_block(block: &str, html: HtmlMode) -> String {
+    let lines: Vec<String> = match html {
+        HtmlMode::Strip => strip_html(block)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+        // A block of nothing but comments shows nothing
+        HtmlMode::Placeholder if remove_html_comments(block).trim().is_empty() => Vec::new(),
+        HtmlMode::Placeholder => vec![format!("{}[html]{}", HTML_START, HTML_END)],
+        HtmlMode::Raw => block
+            .trim_end()
+            .lines()
+            .map(|line| format!("{}{}{}", HTML_START, line, HTML_END))
+            .collect(),
+    };
+    if lines.is_empty() {
+        return String::new();
+    }
+    format!("\n{}\n", lines.join("\n"))
+}
+
+/// A tag or comment of inline HTML as rendered text
+fn render_inline_html(tag: &str, html: HtmlMode) -> String {
+    let is_comment = tag.starts_with("<!--");
+    match html {
+        HtmlMode::Raw => format!("{}{}{}", HTML_START, tag, HTML_END),
+        _ if is_comment => String::new(),
+        HtmlMode::Strip => strip_html(tag),
+        HtmlMode::Placeholder if is_line_break(tag) => "\n".to_string(),
+        HtmlMode::Placeholder => format!("{}[html]{}", HTML_START, HTML_END),
+    }
+}
+
+/// The text of some HTML: comments and tags dropped, `<br>` as a line break
+fn strip_html(html: &str) -> String {
+    let html = remove_html_comments(html);
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html.as_str();
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let tag_end = rest.find('>').filter(|_| starts_with_tag(rest));
+        match tag_end {
+            Some(end) => {
+                if is_line_break(&rest[..=end]) {
+                    text.push('\n');
+                }
+                rest = &rest[end + 1..];
+            }
+            None => {
+                // A lone `<`, as in `a < b`
+                text.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+fn remove_html_comments(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("<!--") {
+        text.push_str(&rest[..start]);
+        let comment = &rest[start + 4..];
+        rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+    }
+    text.push_str(rest);
+    text
+}
+
+/// Whether `html` starts with a tag, like `<b>`, `</b>` or `<!DOCTYPE html>`
+fn starts_with_tag(html: &str) -> bool {
+    html.strip_prefix('<')
+        .is_some_and(|after| after.starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!'))
+}
+
+fn is_line_break(tag: &str) -> bool {
+    let name = tag.trim_start_matches('<').trim_end_matches('>').trim_end_matches('/').trim();
+    name.eq_ignore_ascii_case("br")
+}
+
+/// Destinations of the links in a rendered line, in order
+pub fn link_targets(line: &str) -> Vec<&str> {
+    let mut targets = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(LINK_START) {
+        let link = &rest[start + LINK_START.len_utf8()..];
+        let end = link.find(LINK_END).unwrap_or(link.len());
+        let (text, target) = link[..end].split_once(LINK_TARGET).unwrap_or((&link[..end], &link[..end]));
+        targets.push(if target.is_empty() { text } else { target });
+        rest = &link[end..];
+    }
+    targets
+}
+
+/// Rendered text without the hidden link destinations
+pub fn strip_link_targets(text: &str) -> std::borrow::Cow<'_, str> {
+    if !text.contains(LINK_TARGET) {
+        return std::borrow::Cow::Borrowed(text);
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(LINK_TARGET) {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        rest = &rest[rest.find(LINK_END).unwrap_or(rest.len())..];
+    }
+    result.push_str(rest);
+    std::borrow::Cow::Owned(result)
+}
+
+/// Drop sentence punctuation from the end of a URL, keeping closing
+/// parentheses that pair with one inside it, as in wiki links
+fn trim_url_punctuation(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        let trimmed = url.trim_end_matches(['.', ',', ':', ';', '!', '?', '\'', '"']);
+        let trimmed = match trimmed.strip_suffix(')') {
+            Some(inner) if trimmed.matches('(').count() < trimmed.matches(')').count() => inner,
+            _ => trimmed,
+        };
+        if trimmed.len() == url.len() {
+            return url;
+        }
+        url = trimmed;
+    }
+}
+
+/// Replace `:shortcode:` tokens like `:rocket:` with their emoji, leaving unknown ones as written
+fn replace_emoji_shortcodes(text: &str) -> std::borrow::Cow<'_, str> {
+    if !text.contains(':') {
+        return std::borrow::Cow::Borrowed(text);
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let shortcode_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')))
+            .unwrap_or(after.len());
+        let emoji = after[shortcode_len..]
+            .starts_with(':')
+            .then(|| emojis::get_by_shortcode(&after[..shortcode_len]))
+            .flatten();
+        match emoji {
+            Some(emoji) => {
+                result.push_str(emoji.as_str());
+                rest = &after[shortcode_len + 1..];
+            }
+            None => {
+                result.push(':');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    std::borrow::Cow::Owned(result)
+}
+
+/// Write a footnote number with superscript digits, like `¹²`
+fn superscript(number: usize) -> String {
+    const DIGITS: [char; 10] = ['⁰', '¹', '²', '³', '⁴', '⁵', '⁶', '⁷', '⁸', '⁹'];
+    number
+        .to_string()
+        .chars()
+        .map(|c| DIGITS[c.to_digit(10).unwrap_or(0) as usize])
+        .collect()
+}
+
+/// The plain digit for a superscript digit character
+fn superscript_digit(c: char) -> Option<char> {
+    let digit = match c {
+        '⁰' => 0,
+        '¹' => 1,
+        '²' => 2,
+        '³' => 3,
+        '⁴'..='⁹' => c as u32 - '⁴' as u32 + 4,
+        _ => return None,
+    };
+    char::from_digit(digit, 10)
+}
+
+/// `text` as a URL fragment: lowercase, spaces as hyphens, punctuation dropped
+pub fn slugify(text: &str) -> String {
+    text.trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Copy a line's text so it no longer borrows from the string it was styled from
+pub fn into_owned_line(line: Line<'_>) -> Line<'static> {
+    let Line { style, alignment, spans } = line;
+    let spans = spans
+        .into_iter()
+        .map(|span| Span::styled(span.content.into_owned(), span.style))
+        .collect();
+    Line { style, alignment, spans }
+}
+
+/// Style a line of rendered markdown, turning inline markers into span styles
+pub fn style_rendered_line<'a>(line: &'a str, theme: &Theme, width: usize, show_markers: bool, symbols: &Symbols) -> Line<'a> {
+    // Blockquotes get a bar per nesting level and dim italic text, apart from
+    // an attribution, which isn't dimmed
+    let (quote_depth, line) = split_quote_prefix(line);
+    let (line, base_style) = match line.strip_prefix(ATTRIBUTION_MARKER) {
+        Some(attribution) => (attribution, Style::default().fg(theme.blockquote).add_modifier(Modifier::ITALIC)),
+        None if quote_depth > 0 => (
+            line,
+            Style::default().fg(theme.blockquote).add_modifier(Modifier::DIM | Modifier::ITALIC),
+        ),
+        None => (line, Style::default()),
+    };
+
+    // Frontmatter lines get a bar with the key highlighted and the value dimmed
+    if let Some(metadata) = line.strip_prefix(METADATA_MARKER) {
+        let (key, value) = metadata.split_once(": ").unwrap_or((metadata, ""));
+        return Line::from(vec![
+            Span::styled(format!("{} ", symbols.metadata), Style::default().fg(theme.blockquote)),
+            Span::styled(key, Style::default().fg(theme.heading).add_modifier(Modifier::BOLD)),
+            Span::raw(": "),
+            Span::styled(value, Style::default().add_modifier(Modifier::DIM)),
+        ]);
+    }
+
+    let mut spans = Vec::new();
+    let mut remaining = line;
+
+    // Horizontal rules stretch across whatever width is left after the quote bars
+    if remaining.strip_prefix(RULE_MARKER) == Some("") {
+        let rule_width = width.saturating_sub(quote_depth * 2);
+        spans.push(Span::styled(symbols.rule.repeat(rule_width), Style::default().add_modifier(Modifier::DIM)));
+        remaining = "";
+    }
+
+    // Code blocks sit in a box titled with their language
+    let box_width = width.saturating_sub(quote_depth * 2).max(4);
+    let border_style = Style::default().fg(theme.code).add_modifier(Modifier::DIM);
+    if let Some(language) = remaining.strip_prefix(CODE_TOP) {
+        let [top_left, top_right, ..] = symbols.corners;
+        let title = format!("{}{} {} ", top_left, symbols.horizontal, language);
+        let fill = box_width.saturating_sub(title.width() + 1);
+        spans.push(Span::styled(format!("{}{}{}", title, symbols.horizontal.repeat(fill), top_right), border_style));
+        remaining = "";
+    } else if remaining.strip_prefix(CODE_BOTTOM) == Some("") {
+        let [_, _, bottom_left, bottom_right] = symbols.corners;
+        let bottom = symbols.horizontal.repeat(box_width - 2);
+        spans.push(Span::styled(format!("{}{}{}", bottom_left, bottom, bottom_right), border_style));
+        remaining = "";
+    } else if let Some(code) = remaining.strip_prefix(CODE_LINE) {
+        spans.push(Span::styled(format!("{} ", symbols.vertical), border_style));
+        spans.push(Span::styled(code, Style::default().fg(theme.code)));
+        // Lines too long for the box wrap, so only short ones get a right edge
+        if code.width() + 4 <= box_width {
+            spans.push(Span::raw(" ".repeat(box_width - code.width() - 3)));
+            spans.push(Span::styled(symbols.vertical, border_style));
+        }
+        remaining = "";
+    }
+
+    // Inline images: a caption above blank rows the picture is drawn over
+    if let Some(caption) = remaining.strip_prefix(IMAGE_MARKER) {
+        spans.push(Span::styled(format!("{} ", symbols.image), Style::default().add_modifier(Modifier::DIM)));
+        spans.push(Span::styled(caption, Style::default().add_modifier(Modifier::DIM | Modifier::ITALIC)));
+        remaining = "";
+    } else if remaining.strip_prefix(IMAGE_ROW_MARKER) == Some("") {
+        remaining = "";
+    }
+
+    // Headers, only at the start of the line so `see #3` stays text
+    let header_level = remaining.chars().take_while(|&c| c == '#').count();
+    let heading = match remaining[header_level..].strip_prefix(' ') {
+        Some(text) if header_level > 0 => {
+            if show_markers {
+                spans.push(Span::styled(&remaining[..header_level + 1], Style::default().add_modifier(Modifier::DIM)));
+            }
+            remaining = text;
+            Some(heading_style(header_level, theme))
+        }
+        _ => None,
+    };
+
+    for span in style_inline(remaining, theme, show_markers) {
+        let span_style = heading.unwrap_or_default().patch(span.style);
+        spans.push(span.style(span_style));
+    }
+
+    let mut spans: Vec<Span> = spans
+        .into_iter()
+        .map(|span| {
+            let style = base_style.patch(span.style);
+            span.style(style)
+        })
+        .collect();
+    let bars = (1..=quote_depth).map(|level| Span::styled(format!("{} ", symbols.vertical), quote_bar_style(level, theme)));
+    spans.splice(0..0, bars);
+
+    Line::from(spans)
+}
+
+/// Spans for the inline markdown in `text`: bold, italic, code and links.
+/// A delimiter without a matching close is kept as literal text.
+fn style_inline<'a>(text: &'a str, theme: &Theme, show_markers: bool) -> Vec<Span<'a>> {
+    let mut spans = Vec::new();
+    let mut remaining = text;
+
+    // Markers are either dropped or kept dimmed, the same way for every kind
+    let marker_style = Style::default().add_modifier(Modifier::DIM);
+    let push_marker = |spans: &mut Vec<Span<'a>>, marker: &'a str| {
+        if show_markers {
+            spans.push(Span::styled(marker, marker_style));
+        }
+    };
+
+    while !remaining.is_empty() {
+        if let Some(delimiter) = opening_delimiter(remaining) {
+            let Some(end) = closing_delimiter(remaining, delimiter) else {
+                spans.push(Span::raw(&remaining[..delimiter.len()]));
+                remaining = &remaining[delimiter.len()..];
+                continue;
+            };
+            let inner = &remaining[delimiter.len()..end];
+            push_marker(&mut spans, &remaining[..delimiter.len()]);
+            if delimiter == "`" {
+                // Code text, on a background in place of the backticks
+                spans.push(Span::styled(inner, theme.background(theme.code_background).fg(theme.code)));
+            } else {
+                // Bold or italic text, which may hold other inline markdown
+                let modifier = if delimiter == "**" { Modifier::BOLD } else { Modifier::ITALIC };
+                let style = Style::default().fg(theme.emphasis).add_modifier(modifier);
+                for span in style_inline(inner, theme, show_markers) {
+                    let span_style = style.patch(span.style);
+                    spans.push(span.style(span_style));
+                }
+            }
+            push_marker(&mut spans, &remaining[end..end + delimiter.len()]);
+            remaining = &remaining[end + delimiter.len()..];
+        } else if let Some(image) = remaining.strip_prefix(IMAGE_START) {
+            // Placeholder of an image that isn't drawn
+            let end = image.find(IMAGE_END).unwrap_or(image.len());
+            let style = Style::default().add_modifier(Modifier::DIM | Modifier::ITALIC);
+            for span in style_inline(&image[..end], theme, show_markers) {
+                let span_style = style.patch(span.style);
+                spans.push(span.style(span_style));
+            }
+            remaining = image[end..].strip_prefix(IMAGE_END).unwrap_or("");
+        } else if let Some(html) = remaining.strip_prefix(HTML_START) {
+            // HTML kept as written, or its placeholder
+            let end = html.find(HTML_END).unwrap_or(html.len());
+            spans.push(Span::styled(&html[..end], Style::default().add_modifier(Modifier::DIM)));
+            remaining = html[end..].strip_prefix(HTML_END).unwrap_or("");
+        } else if let Some(link) = remaining.strip_prefix(LINK_START) {
+            // Links, with the destination of written ones shown among the markers
+            let end = link.find(LINK_END).unwrap_or(link.len());
+            let (text, target) = match link[..end].split_once(LINK_TARGET) {
+                Some((text, target)) => (text, Some(target)),
+                None => (&link[..end], None),
+            };
+            let style = Style::default().fg(theme.link).add_modifier(Modifier::UNDERLINED);
+            if target.is_some() {
+                push_marker(&mut spans, "[");
+            }
+            for span in style_inline(text, theme, show_markers) {
+                let span_style = style.patch(span.style);
+                spans.push(span.style(span_style));
+            }
+            if let Some(target) = target {
+                push_marker(&mut spans, "](");
+                push_marker(&mut spans, target);
+                push_marker(&mut spans, ")");
+            }
+            remaining = link[end..].strip_prefix(LINK_END).unwrap_or("");
+        } else {
+            // Regular text
+            let next_special = remaining.find(['*', '`', LINK_START, HTML_START, IMAGE_START]).unwrap_or(remaining.len());
+            spans.push(Span::raw(&remaining[..next_special]));
+            remaining = &remaining[next_special..];
+        }
+    }
+    spans
+}
+
+/// The emphasis or code delimiter `text` starts with. A run of three `*`
+/// opens both bold and italic, and whichever closes last is the outer one.
+fn opening_delimiter(text: &str) -> Option<&'static str> {
+    if text.starts_with("***") {
+        return match (closing_delimiter(text, "**"), closing_delimiter(text, "*")) {
+            (Some(bold), Some(italic)) if italic > bold => Some("*"),
+            (None, Some(_)) => Some("*"),
+            _ => Some("**"),
+        };
+    }
+    ["**", "*", "`"].into_iter().find(|&delimiter| text.starts_with(delimiter))
+}
+
+/// Byte offset of the delimiter closing the one `text` starts with. Code spans
+/// are stepped over, and so are runs of `*` of another length, so nested
+/// emphasis pairs up with its own kind; a longer run only closes when no run
+/// of the same length does, taking its last characters. Emphasis has to hug
+/// its text, so `2 * 3 * 4` stays literal.
+fn closing_delimiter(text: &str, delimiter: &str) -> Option<usize> {
+    let marker = if delimiter == "`" { '`' } else { '*' };
+    let body = &text[delimiter.len()..];
+    if body.is_empty() || (marker == '*' && body.starts_with(char::is_whitespace)) {
+        return None;
+    }
+
+    let find = |exact: bool| {
+        let mut index = delimiter.len();
+        while index < text.len() {
+            let rest = &text[index..];
+            if marker == '*'
+                && let Some(code) = rest.strip_prefix('`')
+                && let Some(code_end) = code.find('`')
+            {
+                index += code_end + 2;
+                continue;
+            }
+            if rest.starts_with(marker) {
+                let run = rest.len() - rest.trim_start_matches(marker).len();
+                let hugs_text = marker == '`' || !text[..index].ends_with(char::is_whitespace);
+                // The rest of a longer opening run can't close it again
+                let fits = index > delimiter.len()
+                    && if exact { run == delimiter.len() } else { run > delimiter.len() };
+                if fits && hugs_text {
+                    return Some(index + run - delimiter.len());
+                }
+                index += run;
+                continue;
+            }
+            index += rest.chars().next().map_or(1, char::len_utf8);
+        }
+        None
+    };
+    find(true).or_else(|| find(false))
+}
+
+/// Style of the bar for blockquote nesting `level`, counting from 1. Each level
+/// is fainter than the one outside it: true colors darken step by step, while
+/// named colors can only be dimmed, so every nested bar shares one dim shade.
+fn quote_bar_style(level: usize, theme: &Theme) -> Style {
+    let style = Style::default();
+    match theme.blockquote {
+        Color::Rgb(red, green, blue) => {
+            let fade = |channel: u8| (channel as usize * 3 / (level + 2)) as u8;
+            style.fg(Color::Rgb(fade(red), fade(green), fade(blue)))
+        }
+        color if level > 1 => style.fg(color).add_modifier(Modifier::DIM),
+        color => style.fg(color),
+    }
+}
+
+/// Style for a heading, fading from underlined bold at H1 to dim italic at H5/H6
+fn heading_style(level: usize, theme: &Theme) -> Style {
+    let modifiers = match level {
+        1 => Modifier::BOLD | Modifier::UNDERLINED,
+        2 => Modifier::BOLD,
+        3 => Modifier::BOLD | Modifier::ITALIC,
+        4 => Modifier::ITALIC,
+        _ => Modifier::ITALIC | Modifier::DIM,
+    };
+    Style::default().fg(theme.heading).add_modifier(modifiers)
+}
+
+/// The parts of `spans` between byte offsets `start` and `end` of their joined text
+pub fn slice_spans(spans: &[Span<'static>], start: usize, end: usize) -> Vec<Span<'static>> {
+    let mut sliced = Vec::new();
+    let mut offset = 0;
+    for span in spans {
+        let (from, to) = (start.max(offset), end.min(offset + span.content.len()));
+        if from < to {
+            sliced.push(Span::styled(span.content[from - offset..to - offset].to_string(), span.style));
+        }
+        offset += span.content.len();
+    }
+    sliced
+}
+
+/// Byte ranges of the occurrences of `query` in `text`, without overlaps.
+/// Case is ignored unless the query has an uppercase letter, as in searches.
+///
+/// ```
+/// use mess::markdown::match_ranges;
+///
+/// assert_eq!(match_ranges("go, Go, GO", "go"), [(0, 2), (4, 6), (8, 10)]);
+/// assert_eq!(match_ranges("aaaa", "aa"), [(0, 2), (2, 4)]);
+/// ```
+pub fn match_ranges(text: &str, query: &str) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    if query.is_empty() {
+        return ranges;
+    }
+    let ignore_case = !query.chars().any(char::is_uppercase);
+    let same = |a: char, b: char| if ignore_case { a.to_lowercase().eq(b.to_lowercase()) } else { a == b };
+    let mut index = 0;
+    while index < text.len() {
+        let mut end = index;
+        let matched = query.chars().all(|expected| match text[end..].chars().next() {
+            Some(c) if same(c, expected) => {
+                end += c.len_utf8();
+                true
+            }
+            _ => false,
+        });
+        if matched {
+            ranges.push((index, end));
+            index = end;
+        } else {
+            index += text[index..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    ranges
+}
+
+/// Give the occurrences of `query` in `line` the `highlight` style on top of
+/// their own. Spans are split where matches start and end, so a match running
+/// into bold text or code keeps those styles under the highlight.
+///
+/// ```
+/// use mess::markdown::highlight_matches;
+/// use mess::theme::Theme;
+/// use ratatui::style::{Color, Modifier, Style};
+///
+/// let theme = Theme::dark();
+/// let highlight = Style::default().bg(Color::Yellow);
+/// let line = mess::markdown_to_lines("plain **bold** and `code`", &theme).lines.remove(0);
+///
+/// // Into bold text
+/// let highlighted = highlight_matches(line.clone(), "in bo", highlight);
+/// let bold = highlighted.spans.iter().find(|span| span.content == "bo").unwrap();
+/// assert!(bold.style.add_modifier.contains(Modifier::BOLD));
+/// assert_eq!(bold.style.bg, Some(Color::Yellow));
+/// let rest = highlighted.spans.iter().find(|span| span.content == "ld").unwrap();
+/// assert!(rest.style.add_modifier.contains(Modifier::BOLD));
+/// assert_eq!(rest.style.bg, None);
+///
+/// // An uppercase letter makes the query match case
+/// let highlighted = highlight_matches(line.clone(), "AND co", highlight);
+/// assert_eq!(highlighted, line);
+///
+/// // Into inline code, which keeps its color under the highlight
+/// let highlighted = highlight_matches(line, "and co", highlight);
+/// let code = highlighted.spans.iter().find(|span| span.content == "co").unwrap();
+/// assert_eq!((code.style.fg, code.style.bg), (Some(theme.code), Some(Color::Yellow)));
+/// assert_eq!(highlighted.to_string(), "plain bold and code");
+///
+/// // Every occurrence on the line, each with the text around it left alone
+/// let line = mess::markdown_to_lines("echo **echo** echo", &theme).lines.remove(0);
+/// let highlighted = highlight_matches(line, "echo", highlight);
+/// let marked: Vec<_> = highlighted.spans.iter().filter(|span| span.style.bg == Some(Color::Yellow)).collect();
+/// assert_eq!(marked.len(), 3);
+/// assert!(marked.iter().all(|span| span.content == "echo"));
+/// assert!(marked[1].style.add_modifier.contains(Modifier::BOLD));
+/// ```
+pub fn highlight_matches(line: Line<'static>, query: &str, highlight: Style) -> Line<'static> {
+    let text: String = line.spans.iter().map(|span| span.content.as_ref()).collect();
+    let ranges = match_ranges(&text, query);
+    if ranges.is_empty() {
+        return line;
+    }
+    let Line { style, alignment, spans } = line;
+    let mut highlighted = Vec::new();
+    let mut offset = 0;
+    for (start, end) in ranges {
+        highlighted.extend(slice_spans(&spans, offset, start));
+        highlighted.extend(slice_spans(&spans, start, end).into_iter().map(|span| {
+            let style = span.style.patch(highlight);
+            span.style(style)
+        }));
+        offset = end;
+    }
+    highlighted.extend(slice_spans(&spans, offset, text.len()));
+    Line { style, alignment, spans: highlighted }
+}
+
+/// Replace tabs with spaces up to the next tab stop, counting display
+/// columns so wide characters before a tab don't throw off alignment
+pub fn expand_tabs(line: &str, tab_width: usize) -> String {
+    if !line.contains('\t') {
+        return line.to_string();
+    }
+
+    let mut expanded = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = tab_width - column % tab_width;
+            expanded.extend(std::iter::repeat_n(' ', spaces));
+            column += spaces;
+        } else {
+            expanded.push(c);
+            column += c.width().unwrap_or(0);
+        }
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(content: &str) -> String {
+        let options = RenderOptions { image_rows: 0, html: HtmlMode::Strip, tab_width: 4, ascii: false, image_dir: None };
+        render_markdown(content, &options).text
+    }
+
+    /// The text and modifiers of each span `line` is styled into
+    fn styled(line: &str) -> Vec<(String, Modifier)> {
+        style_rendered_line(line, &Theme::dark(), 80, false, Symbols::get(false))
+            .spans
+            .into_iter()
+            .map(|span| (span.content.into_owned(), span.style.add_modifier))
+            .collect()
+    }
+
+    #[test]
+    fn definition_list_puts_each_definition_under_its_term() {
+        let text = render("Apple\n: A red fruit\n: A company\n\nPear\n: A green fruit\n");
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines, ["**Apple**", "    A red fruit", "    A company", "**Pear**", "    A green fruit"]);
+        assert_eq!(styled(lines[0]), [("Apple".to_string(), Modifier::BOLD)]);
+    }
+
+    /// `(text, modifiers)` pairs for `styled` to return, from string slices
+    fn spans(expected: &[(&str, Modifier)]) -> Vec<(String, Modifier)> {
+        expected.iter().map(|&(text, modifier)| (text.to_string(), modifier)).collect()
+    }
+
+    #[test]
+    fn unmatched_star_is_kept_as_text() {
+        let none = Modifier::empty();
+        assert_eq!(styled("a * b"), spans(&[("a ", none), ("*", none), (" b", none)]));
+        assert_eq!(styled("a ** b"), spans(&[("a ", none), ("**", none), (" b", none)]));
+        assert_eq!(
+            styled("2 * 3 * 4"),
+            spans(&[("2 ", none), ("*", none), (" 3 ", none), ("*", none), (" 4", none)])
+        );
+    }
+
+    #[test]
+    fn unmatched_backtick_is_kept_as_text() {
+        let line = style_rendered_line("use `code", &Theme::dark(), 80, false, Symbols::get(false));
+        let parts: Vec<&str> = line.spans.iter().map(|span| span.content.as_ref()).collect();
+        assert_eq!(parts, ["use ", "`", "code"]);
+        assert!(line.spans.iter().all(|span| span.style.bg.is_none()));
+    }
+
+    #[test]
+    fn unmatched_marker_inside_matched_ones_is_kept_as_text() {
+        let none = Modifier::empty();
+        assert_eq!(styled("**a *b**"), spans(&[("a ", Modifier::BOLD), ("*", Modifier::BOLD), ("b", Modifier::BOLD)]));
+        // A star inside code can't close one outside it
+        assert_eq!(styled("*a `b*` c"), spans(&[("*", none), ("a ", none), ("b*", none), (" c", none)]));
+        assert_eq!(styled("`a *b` c*"), spans(&[("a *b", none), (" c", none), ("*", none)]));
+    }
+
+    #[test]
+    fn interleaved_markers_pair_with_their_own_kind() {
+        assert_eq!(
+            styled("*bold **nested** more*"),
+            spans(&[("bold ", Modifier::ITALIC), ("nested", Modifier::BOLD | Modifier::ITALIC), (" more", Modifier::ITALIC)])
+        );
+    }
+
+    #[test]
+    fn hash_in_the_middle_of_a_line_is_not_a_heading() {
+        let text = render("see section #3\nissue #12 # is open\n");
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines, ["see section #3", "issue #12 # is open"]);
+        for line in lines {
+            let line = style_rendered_line(line, &Theme::dark(), 80, false, Symbols::get(false));
+            assert_eq!(line.spans.len(), 1);
+            assert_eq!(line.spans[0].style, Style::default());
+        }
+        // Only a run of `#` and a space at the start of a line is a heading marker
+        assert_eq!(styled("## Title"), spans(&[("Title", Modifier::BOLD)]));
+    }
+
+    #[test]
+    fn inline_reference_and_shortcut_links_keep_their_targets() {
+        let text = render(concat!(
+            "[inline](https://a.example), [full][ref], [collapsed][] and [shortcut]\n",
+            "\n",
+            "[ref]: https://b.example\n",
+            "[collapsed]: https://c.example\n",
+            "[shortcut]: https://d.example\n",
+        ));
+        assert_eq!(text.lines().count(), 1);
+        assert_eq!(
+            link_targets(&text),
+            ["https://a.example", "https://b.example", "https://c.example", "https://d.example"]
+        );
+        let none = Modifier::empty();
+        let link = Modifier::UNDERLINED;
+        assert_eq!(
+            styled(&text),
+            spans(&[
+                ("inline", link),
+                (", ", none),
+                ("full", link),
+                (", ", none),
+                ("collapsed", link),
+                (" and ", none),
+                ("shortcut", link),
+            ])
+        );
+    }
+
+    #[test]
+    fn undefined_reference_stays_text() {
+        let text = render("[missing][nowhere] and [alone]\n");
+        assert!(link_targets(&text).is_empty());
+        assert_eq!(text, "[missing][nowhere] and [alone]");
+    }
+
+    #[test]
+    fn code_keeps_its_indentation_and_blank_lines() {
+        let text = render("```python\ndef f():\n    if x:\n\n\n        return 1\n\n    return 2\n```\n");
+        let code: Vec<&str> = text.lines().filter_map(|line| line.strip_prefix(CODE_LINE)).collect();
+        assert_eq!(code, ["def f():", "    if x:", "", "", "        return 1", "", "    return 2"]);
+    }
+
+    #[test]
+    fn indented_code_keeps_its_indentation_and_blank_lines() {
+        let text = render("Text\n\n    key:\n\n\n\n      - nested\n    end\n");
+        let code: Vec<&str> = text.lines().filter_map(|line| line.strip_prefix(CODE_LINE)).collect();
+        assert_eq!(code, ["key:", "", "", "", "  - nested", "end"]);
+    }
+
+    #[test]
+    fn nested_emphasis_combines_modifiers() {
+        let bold_italic = Modifier::BOLD | Modifier::ITALIC;
+        assert_eq!(styled("***x***"), spans(&[("x", bold_italic)]));
+        assert_eq!(
+            styled("*a **b** c*"),
+            spans(&[("a ", Modifier::ITALIC), ("b", bold_italic), (" c", Modifier::ITALIC)])
+        );
+        // Rendered markdown keeps the markers the same way
+        assert_eq!(render("***x*** and *a **b** c*"), "***x*** and *a **b** c*");
+    }
+
+    #[test]
+    fn code_inside_bold_is_bold_code() {
+        let theme = Theme::dark();
+        assert_eq!(
+            styled("**a `b` c**"),
+            spans(&[("a ", Modifier::BOLD), ("b", Modifier::BOLD), (" c", Modifier::BOLD)])
+        );
+        let line = style_rendered_line("**a `b` c**", &theme, 80, false, Symbols::get(false));
+        assert_eq!(line.spans[1].style.fg, Some(theme.code));
+        assert!(line.spans[0].style.bg.is_none() && line.spans[1].style.bg.is_some());
+    }
+
+    #[test]
+    fn setext_and_atx_headings_render_alike_apart_from_rules() {
+        let options = RenderOptions { image_rows: 0, html: HtmlMode::Strip, tab_width: 4, ascii: false, image_dir: None };
+        let rendered = render_markdown(include_str!("../examples/headings.md"), &options);
+        let headings: Vec<&str> = rendered.text.lines().filter(|line| line.starts_with('#')).collect();
+        assert_eq!(
+            headings,
+            [
+                "# Setext Title",
+                "## Setext Section",
+                "# ATX Title",
+                "## ATX Section",
+                "## A setext heading can run over two lines",
+                "### Last Heading",
+            ]
+        );
+        let levels: Vec<u8> = rendered.toc.iter().map(|entry| entry.level).collect();
+        assert_eq!(levels, [1, 2, 1, 2, 2, 3]);
+        // Setext headings start at their text, not their underline
+        let lines: Vec<usize> = rendered.toc.iter().map(|entry| entry.source_line).collect();
+        assert_eq!(lines, [0, 5, 13, 15, 17, 29]);
+        // The `---` after a blank line and the three rules further down
+        let rules = rendered.text.lines().filter(|line| *line == RULE_MARKER.to_string()).count();
+        assert_eq!(rules, 4);
+    }
+
+    #[test]
+    fn nested_quotes_and_attributions() {
+        let text = render(include_str!("../examples/quotes.md"));
+        let lines: Vec<&str> = text.lines().collect();
+        let depths: Vec<usize> = lines.iter().map(|line| split_quote_prefix(line).0).collect();
+        assert_eq!(depths[..8], [0, 0, 1, 0, 2, 2, 0, 1]);
+        assert_eq!(split_quote_prefix(lines[4]).1, "A quote nested in it gets a second, fainter bar.");
+
+        // Each level gets a bar, the inner one fainter than the outer one
+        let theme = Theme::dark();
+        let nested = style_rendered_line(lines[4], &theme, 80, false, Symbols::get(false));
+        assert_eq!((nested.spans[0].content.as_ref(), nested.spans[1].content.as_ref()), ("│ ", "│ "));
+        assert_ne!(nested.spans[0].style, nested.spans[1].style);
+
+        // Only a dash line ending a quote is an attribution, and it is italic without being dimmed
+        let attributions: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| split_quote_prefix(line).1.strip_prefix(ATTRIBUTION_MARKER))
+            .collect();
+        assert_eq!(attributions, ["— Douglas Adams", "-- Anonymous"]);
+        let attribution = lines.iter().find(|line| line.contains("Douglas Adams")).unwrap();
+        let attribution = style_rendered_line(attribution, &theme, 80, false, Symbols::get(false));
+        let author = attribution.spans.last().unwrap();
+        assert_eq!(author.content, "— Douglas Adams");
+        assert!(author.style.add_modifier.contains(Modifier::ITALIC));
+        assert!(!author.style.add_modifier.contains(Modifier::DIM));
+    }
+
+    #[test]
+    fn headings_have_exactly_one_blank_line_below() {
+        assert_eq!(render("# A\nText"), "# A\n\nText");
+        assert_eq!(render("# A\n\n\n\nText"), "# A\n\nText");
+        assert_eq!(render("# A\n## B\nText"), "# A\n\n## B\n\nText");
+    }
+
+    #[test]
+    fn lists_have_exactly_one_blank_line_above() {
+        assert_eq!(render("Text\n- x\n- y"), "Text\n\n• x\n• y");
+        assert_eq!(render("Text\n\n\n\n- x\n\n\n\nMore"), "Text\n\n• x\n\nMore");
+    }
+
+    #[test]
+    fn heading_right_before_a_list_has_one_blank_line_between() {
+        assert_eq!(render("# A\n- x\n- y"), "# A\n\n• x\n• y");
+        assert_eq!(render("## A\n\n\n1. one\n2. two"), "## A\n\n• one\n• two");
+    }
+}
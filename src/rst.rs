@@ -0,0 +1,299 @@
+/// Characters RST allows for section title underlines and overlines
+const ADORNMENT_CHARS: &str = "=-`:'\"~^_*+#<>";
+
+/// Directives shown as a quoted, labelled note
+const ADMONITIONS: [&str; 10] = [
+    "note", "tip", "hint", "important", "warning", "caution", "attention", "danger", "error", "admonition",
+];
+
+/// Rewrite reStructuredText as markdown for the markdown renderer to show. Each
+/// input line gives one output line, so headings found in the result point at
+/// the right source lines; only a literal block running to the end of the
+/// document gets an extra line, its closing fence.
+pub fn to_markdown(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut output: Vec<String> = Vec::with_capacity(lines.len());
+    // Title styles in the order they first appear, which sets their level
+    let mut title_styles: Vec<(char, bool)> = Vec::new();
+    let mut index = 0;
+
+    while index < lines.len() {
+        let line = lines[index];
+
+        // Section titles, underlined and optionally overlined
+        if let Some((style, title, length)) = section_title(&lines, index) {
+            let level = match title_styles.iter().position(|&known| known == style) {
+                Some(position) => position + 1,
+                None => {
+                    title_styles.push(style);
+                    title_styles.len()
+                }
+            };
+            if style.1 {
+                output.push(String::new());
+            }
+            output.push(format!("{} {}", "#".repeat(level.min(6)), inline(title)));
+            output.push(String::new());
+            index += length;
+            continue;
+        }
+
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+
+        // Directives and comments, with their indented bodies
+        if let Some(directive) = trimmed.strip_prefix("..") {
+            let body_end = indented_block_end(&lines, index + 1, indent);
+            let directive = directive.trim();
+            let (name, argument) = match directive.split_once("::") {
+                Some((name, argument)) => (name.trim(), argument.trim()),
+                None => ("", directive),
+            };
+
+            if matches!(name, "code-block" | "code" | "sourcecode") {
+                index = literal_block(&lines, index, indent, argument, String::new(), &mut output);
+                continue;
+            }
+
+            let prefix = " ".repeat(indent);
+            if matches!(name, "image" | "figure") {
+                output.push(format!("{}![]({})", prefix, argument));
+                output.extend((index + 1..body_end).map(|_| String::new()));
+            } else if ADMONITIONS.contains(&name) {
+                let label = if name == "admonition" { argument.to_string() } else { capitalize(name) };
+                let first = if name == "admonition" || argument.is_empty() {
+                    String::new()
+                } else {
+                    format!(" {}", inline(argument))
+                };
+                output.push(format!("{}> **{}:**{}", prefix, label, first));
+                let body = dedent(&lines[index + 1..body_end]);
+                let trailing_blanks = body.iter().rev().take_while(|line| line.is_empty()).count();
+                let quoted = body[..body.len() - trailing_blanks].iter().map(|line| format!("{}> {}", prefix, inline(line)));
+                output.extend(quoted.map(|line| line.trim_end().to_string()));
+                output.extend((0..trailing_blanks).map(|_| String::new()));
+            } else if let Some(target) = name.is_empty().then_some(argument).and_then(|target| target.strip_prefix('_'))
+                && let Some((label, url)) = target.split_once(": ")
+            {
+                // Hyperlink targets become reference definitions
+                output.push(format!("{}[{}]: {}", prefix, label.trim_matches('`'), url.trim()));
+                output.extend((index + 1..body_end).map(|_| String::new()));
+            } else {
+                // Comments and directives without a markdown counterpart
+                output.extend((index..body_end).map(|_| String::new()));
+            }
+            index = body_end;
+            continue;
+        }
+
+        // A paragraph ending in `::` introduces a literal block
+        if let Some(paragraph) = trimmed.strip_suffix("::")
+            && lines.get(index + 1).is_some_and(|next| next.trim().is_empty())
+        {
+            let text = match paragraph.strip_suffix(' ') {
+                Some(text) => text.trim_end().to_string(),
+                None if paragraph.is_empty() => String::new(),
+                None => format!("{}:", paragraph),
+            };
+            let text = format!("{}{}", " ".repeat(indent), inline(&text));
+            index = literal_block(&lines, index, indent, "", text, &mut output);
+            continue;
+        }
+
+        // Field lists, like `:Author: Jane`
+        if indent == 0
+            && let Some(field) = line.strip_prefix(':')
+            && let Some((name, value)) = field.split_once(": ")
+            && !name.is_empty()
+            && !name.contains('`')
+        {
+            output.push(format!("**{}:** {}", name, inline(value.trim())));
+            index += 1;
+            continue;
+        }
+
+        // List items keep their indentation for nesting. Other indented text is
+        // a block quote or a continuation, which markdown would take for code.
+        let is_item = ["- ", "* ", "+ ", "#. "].iter().any(|bullet| trimmed.starts_with(bullet))
+            || trimmed.split_once(". ").is_some_and(|(number, _)| number.parse::<u32>().is_ok());
+        let line = match trimmed.strip_prefix("#. ") {
+            Some(item) => format!("{}1. {}", " ".repeat(indent), item),
+            None if is_item => line.to_string(),
+            None => trimmed.to_string(),
+        };
+        output.push(inline(&line));
+        index += 1;
+    }
+
+    let mut markdown = output.join("\n");
+    markdown.push('\n');
+    markdown
+}
+
+/// The title at `index`, its style (adornment character and whether it is
+/// overlined) and how many lines it spans
+fn section_title<'a>(lines: &[&'a str], index: usize) -> Option<((char, bool), &'a str, usize)> {
+    let adornment = |line: &str| {
+        let mut chars = line.trim_end().chars();
+        let first = chars.next()?;
+        let length = line.trim_end().chars().count();
+        (ADORNMENT_CHARS.contains(first) && length >= 3 && chars.all(|c| c == first)).then_some((first, length))
+    };
+
+    // Overline, title, underline
+    if let Some((overline, _)) = adornment(lines[index])
+        && let Some(&title) = lines.get(index + 1)
+        && !title.trim().is_empty()
+        && adornment(title).is_none()
+        && lines.get(index + 2).and_then(|line| adornment(line)).is_some_and(|(c, _)| c == overline)
+    {
+        return Some(((overline, true), title.trim(), 3));
+    }
+
+    // Title, underline at least as long
+    let title = lines[index];
+    if title.trim().is_empty() || title.starts_with(char::is_whitespace) || adornment(title).is_some() {
+        return None;
+    }
+    let (underline, length) = lines.get(index + 1).and_then(|line| adornment(line))?;
+    (length >= title.trim_end().chars().count()).then_some(((underline, false), title.trim_end(), 2))
+}
+
+/// Write a literal block introduced at `start`, whose own line becomes `intro`.
+/// The blank line after it opens the fence and the last blank line before the
+/// text resumes closes it. Returns the index of the first line after the block.
+fn literal_block(
+    lines: &[&str],
+    start: usize,
+    indent: usize,
+    language: &str,
+    intro: String,
+    output: &mut Vec<String>,
+) -> usize {
+    output.push(intro);
+    let mut index = start + 1;
+    // Directive options, like `:linenos:`
+    while lines.get(index).is_some_and(|line| line.trim_start().starts_with(':') && indentation(line) > indent) {
+        output.push(String::new());
+        index += 1;
+    }
+    if !lines.get(index).is_some_and(|line| line.trim().is_empty()) {
+        return index;
+    }
+
+    let end = indented_block_end(lines, index + 1, indent);
+    let mut body = dedent(&lines[index + 1..end]);
+    let trailing_blanks = body.iter().rev().take_while(|line| line.is_empty()).count();
+    body.truncate(body.len() - trailing_blanks);
+
+    output.push(format!("{}```{}", " ".repeat(indent), language));
+    output.extend(body.into_iter().map(|line| format!("{}{}", " ".repeat(indent), line)));
+    output.push(format!("{}```", " ".repeat(indent)));
+    output.extend((1..trailing_blanks).map(|_| String::new()));
+    end
+}
+
+/// Index of the first line from `start` that isn't blank and isn't indented
+/// past `indent`, ending the indented body of the line before `start`
+fn indented_block_end(lines: &[&str], start: usize, indent: usize) -> usize {
+    let mut end = start;
+    while let Some(line) = lines.get(end) {
+        if !line.trim().is_empty() && indentation(line) <= indent {
+            break;
+        }
+        end += 1;
+    }
+    end
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// `lines` with the indentation they share removed; blank lines come out empty
+fn dedent(lines: &[&str]) -> Vec<String> {
+    let common = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| indentation(line))
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .map(|line| if line.trim().is_empty() { String::new() } else { line[common..].to_string() })
+        .collect()
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Rewrite RST inline markup: ``` ``code`` ```, `` `text <url>`_ `` links,
+/// `` `name`_ `` references, `:role:` text and `` `interpreted` `` text.
+/// Bold and italic are written the same in both.
+fn inline(line: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(start) = rest.find([':', '`']) {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        // Inline literals
+        if let Some(code) = rest.strip_prefix("``")
+            && let Some(end) = code.find("``")
+        {
+            result.push('`');
+            result.push_str(&code[..end]);
+            result.push('`');
+            rest = &code[end + 2..];
+            continue;
+        }
+
+        // Roles like :code:`x` or :ref:`x`, shown as code
+        if let Some(role) = rest.strip_prefix(':')
+            && let Some(name_end) = role.find(":`")
+            && role[..name_end].chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == ':')
+            && let Some(end) = role[name_end + 2..].find('`')
+        {
+            let text = &role[name_end + 2..name_end + 2 + end];
+            result.push('`');
+            result.push_str(text);
+            result.push('`');
+            rest = &role[name_end + 3 + end..];
+            continue;
+        }
+
+        if let Some(quoted) = rest.strip_prefix('`')
+            && let Some(end) = quoted.find('`')
+        {
+            let text = &quoted[..end];
+            let after = &quoted[end + 1..];
+            let (is_link, after) = match after.strip_prefix("__").or_else(|| after.strip_prefix('_')) {
+                Some(after) => (true, after),
+                None => (false, after),
+            };
+            if is_link {
+                // `text <url>`_ links inline, `name`_ refers to a target
+                match text.rsplit_once(" <").filter(|(_, url)| url.ends_with('>')) {
+                    Some((label, url)) => result.push_str(&format!("[{}]({})", label, &url[..url.len() - 1])),
+                    None => result.push_str(&format!("[{}]", text)),
+                }
+            } else {
+                result.push_str(&format!("*{}*", text));
+            }
+            rest = after;
+            continue;
+        }
+
+        // A lone `:` or backtick
+        result.push_str(&rest[..1]);
+        rest = &rest[1..];
+    }
+    result.push_str(rest);
+    result
+}
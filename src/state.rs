@@ -0,0 +1,79 @@
+use crate::{ViewMode, WrapMode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where a file was left off, keyed by its canonical path
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedPosition {
+    pub scroll_offset: usize,
+    pub view_mode: ViewMode,
+    /// Size of the file when the position was saved, to detect large edits
+    pub file_size: u64,
+}
+
+/// How the viewer was set up when last closed, for every file. Unset fields
+/// fall back to the built-in defaults; command-line flags override them all.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preferences {
+    pub view_mode: Option<ViewMode>,
+    pub wrap: Option<WrapMode>,
+    pub wrap_markers: Option<bool>,
+    pub theme: Option<String>,
+}
+
+/// Persisted state from previous sessions, stored in `~/.local/state/mess/positions.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedState {
+    files: HashMap<PathBuf, SavedPosition>,
+    preferences: Preferences,
+}
+
+impl SavedState {
+    /// Load the state file; a missing or unreadable file just means starting fresh
+    pub fn load() -> SavedState {
+        state_path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = state_path() else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn position(&self, path: &Path) -> Option<&SavedPosition> {
+        self.files.get(path)
+    }
+
+    pub fn set_position(&mut self, path: PathBuf, position: SavedPosition) {
+        self.files.insert(path, position);
+    }
+
+    pub fn preferences(&self) -> &Preferences {
+        &self.preferences
+    }
+
+    pub fn set_preferences(&mut self, preferences: Preferences) {
+        self.preferences = preferences;
+    }
+}
+
+/// `$XDG_STATE_HOME/mess/positions.json`, falling back to `~/.local/state/mess/positions.json`
+fn state_path() -> Option<PathBuf> {
+    let state_dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state")))?;
+    Some(state_dir.join("mess").join("positions.json"))
+}
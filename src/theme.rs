@@ -0,0 +1,162 @@
+use color_eyre::Result;
+use ratatui::style::{Color, Modifier, Style};
+use serde::Deserialize;
+use std::fs;
+
+/// Colors used by the renderer and the surrounding UI
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    pub heading: Color,
+    pub code: Color,
+    /// Background of inline code in rendered markdown
+    pub code_background: Color,
+    pub emphasis: Color,
+    pub blockquote: Color,
+    pub header: Color,
+    pub footer: Color,
+    pub border: Color,
+    pub selection: Color,
+    /// Bare URLs in rendered markdown
+    pub link: Color,
+    /// Background of the line the cursor is on
+    pub cursor_line: Color,
+    /// Background of TODO/FIXME-style keywords when they are highlighted
+    pub todo: Color,
+    /// Guide drawn at the `--ruler` column
+    pub ruler: Color,
+    /// Source text past the `--ruler` column
+    pub overflow: Color,
+    /// Background of lines matching the active search
+    pub search_match: Color,
+    /// Background of trailing whitespace when it is shown
+    pub trailing_whitespace: Color,
+    /// Scrollbar ticks at headings
+    pub minimap_heading: Color,
+    /// Scrollbar ticks at search matches
+    pub minimap_match: Color,
+    /// Lines only the new file has in `--diff`
+    pub diff_added: Color,
+    /// Lines only the old file has in `--diff`
+    pub diff_removed: Color,
+    /// Name of the syntect theme used to highlight source files
+    pub syntax_theme: String,
+    /// Set by `--no-color`: backgrounds become reversed text and source files
+    /// aren't highlighted
+    #[serde(skip)]
+    pub monochrome: bool,
+}
+
+impl Theme {
+    pub fn dark() -> Theme {
+        Theme {
+            heading: Color::Reset,
+            code: Color::Yellow,
+            code_background: Color::Indexed(237),
+            emphasis: Color::Reset,
+            blockquote: Color::Gray,
+            header: Color::Yellow,
+            footer: Color::Gray,
+            border: Color::Reset,
+            selection: Color::Yellow,
+            link: Color::Cyan,
+            cursor_line: Color::Indexed(236),
+            todo: Color::Yellow,
+            ruler: Color::DarkGray,
+            overflow: Color::LightRed,
+            search_match: Color::DarkGray,
+            trailing_whitespace: Color::Red,
+            minimap_heading: Color::Cyan,
+            minimap_match: Color::Yellow,
+            diff_added: Color::Green,
+            diff_removed: Color::Red,
+            syntax_theme: "base16-ocean.dark".to_string(),
+            monochrome: false,
+        }
+    }
+
+    pub fn light() -> Theme {
+        Theme {
+            heading: Color::Blue,
+            code: Color::Magenta,
+            code_background: Color::Indexed(253),
+            emphasis: Color::Reset,
+            blockquote: Color::DarkGray,
+            header: Color::Blue,
+            footer: Color::DarkGray,
+            border: Color::Reset,
+            selection: Color::Blue,
+            link: Color::Blue,
+            cursor_line: Color::Indexed(254),
+            todo: Color::LightRed,
+            ruler: Color::Gray,
+            overflow: Color::Red,
+            search_match: Color::LightYellow,
+            trailing_whitespace: Color::LightRed,
+            minimap_heading: Color::Blue,
+            minimap_match: Color::Magenta,
+            diff_added: Color::Green,
+            diff_removed: Color::Red,
+            syntax_theme: "InspiredGitHub".to_string(),
+            monochrome: false,
+        }
+    }
+
+    /// This theme with every color left to the terminal, for `--no-color`
+    pub fn without_colors(self) -> Theme {
+        Theme {
+            heading: Color::Reset,
+            code: Color::Reset,
+            code_background: Color::Reset,
+            emphasis: Color::Reset,
+            blockquote: Color::Reset,
+            header: Color::Reset,
+            footer: Color::Reset,
+            border: Color::Reset,
+            selection: Color::Reset,
+            link: Color::Reset,
+            cursor_line: Color::Reset,
+            todo: Color::Reset,
+            ruler: Color::Reset,
+            overflow: Color::Reset,
+            search_match: Color::Reset,
+            trailing_whitespace: Color::Reset,
+            minimap_heading: Color::Reset,
+            minimap_match: Color::Reset,
+            diff_added: Color::Reset,
+            diff_removed: Color::Reset,
+            monochrome: true,
+            ..self
+        }
+    }
+
+    /// Text on a `color` background, or reversed text when colors are off
+    pub fn background(&self, color: Color) -> Style {
+        if self.monochrome {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default().bg(color)
+        }
+    }
+
+    /// Resolve `--theme`: a built-in theme name or the path to a TOML theme file.
+    /// Colors missing from the file keep their `dark` values.
+    pub fn load(spec: &str) -> Result<Theme> {
+        match spec {
+            "dark" => Ok(Theme::dark()),
+            "light" => Ok(Theme::light()),
+            path => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| color_eyre::eyre::eyre!("Failed to read theme '{}': {}", path, e))?;
+                toml::from_str(&text)
+                    .map_err(|e| color_eyre::eyre::eyre!("Invalid theme file '{}': {}", path, e))
+            }
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::dark()
+    }
+}
@@ -0,0 +1,31 @@
+use std::fs;
+use std::process::Command;
+
+fn mess_plain_source(path: &str) -> Vec<u8> {
+    let output = Command::new(env!("CARGO_BIN_EXE_mess"))
+        .args(["--plain", "--no-resume", "--mode", "source", path])
+        .output()
+        .expect("failed to run mess");
+    assert!(output.status.success(), "mess exited with {}", output.status);
+    output.stdout
+}
+
+#[test]
+fn plain_source_output_matches_the_file() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/simple.md");
+    assert_eq!(mess_plain_source(path), fs::read(path).unwrap());
+}
+
+#[test]
+fn plain_source_output_ends_with_exactly_one_newline() {
+    let dir = std::env::temp_dir().join(format!("mess-cli-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let with_newline = dir.join("with-newline.txt");
+    let without_newline = dir.join("without-newline.txt");
+    fs::write(&with_newline, "a\nb\n").unwrap();
+    fs::write(&without_newline, "a\nb").unwrap();
+
+    assert_eq!(mess_plain_source(with_newline.to_str().unwrap()), b"a\nb\n");
+    assert_eq!(mess_plain_source(without_newline.to_str().unwrap()), b"a\nb\n");
+    fs::remove_dir_all(&dir).unwrap();
+}